@@ -0,0 +1,58 @@
+//! The end-to-end rate of the unrolled and the scalar search on real archives, to catch
+//! regressions that only show up outside the hot loop, like allocations or locking
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use zipcrack::crack::{crack_unrolled_without_display, find_passwords};
+use zipcrack::info::InfoData;
+use zipcrack::opt::{CrackOptions, Opt};
+use zipcrack::password_iter::keyspace_size;
+use zipcrack::zipfile::ZipArchive;
+
+/// Four stored files, encrypted with the password "aZ9"
+const ARCHIVE: &[u8] = include_bytes!("../tests/data/encrypted.zip");
+
+/// 50 small stored files, encrypted with the password "aZ9"
+const FIFTY_FILES_ARCHIVE: &[u8] = include_bytes!("../tests/data/fifty.zip");
+
+fn base64_length_3(unroll: bool) -> Opt {
+    CrackOptions::new("base64".parse().unwrap())
+        .min_length(3)
+        .max_length(3)
+        .unroll(unroll)
+        .logfile("")
+        .into()
+}
+
+fn crack_rate(c: &mut Criterion) {
+    let archive = ZipArchive::parse(ARCHIVE).unwrap();
+    let opt = base64_length_3(true);
+    let mut group = c.benchmark_group("crack");
+    group.throughput(Throughput::Elements(keyspace_size(&opt) as u64));
+    group.bench_function("unrolled_base64_length_3", |b| {
+        b.iter(|| {
+            let info_data = InfoData::new();
+            crack_unrolled_without_display(opt.clone(), &archive, info_data.clone());
+            assert_eq!(info_data.found_passwords.lock().unwrap().len(), 1);
+        })
+    });
+    group.finish();
+}
+
+/// The scalar check with 4 and with 50 files. The rates are about the same, because later files
+/// are only checked for the few passwords that pass the first one.
+fn scalar_rate(c: &mut Criterion) {
+    let opt = base64_length_3(false);
+    let mut group = c.benchmark_group("scalar");
+    group.throughput(Throughput::Elements(keyspace_size(&opt) as u64));
+    for (name, data) in [("4_files", ARCHIVE), ("50_files", FIFTY_FILES_ARCHIVE)] {
+        let archive = ZipArchive::parse(data).unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| assert_eq!(find_passwords(opt.clone(), &archive).len(), 1))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, crack_rate, scalar_rate);
+criterion_main!(benches);
@@ -0,0 +1,186 @@
+//! Options from a TOML file, for --config. The keys are the names of the long options, e.g.
+//! `max-length = 6`, and options on the command line override them.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    alphabet: Option<String>,
+    exclude: Option<String>,
+    min_length: Option<u8>,
+    max_length: Option<u8>,
+    max_length_cap: Option<u8>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+    markov: Option<String>,
+    file_glob: Option<String>,
+    magic: Option<String>,
+    num_threads: Option<u8>,
+    refresh_interval: Option<u64>,
+    log_interval: Option<u64>,
+    logfile: Option<String>,
+    output: Option<String>,
+    format: Option<String>,
+    color: Option<String>,
+    max_false_positive_rate: Option<f64>,
+    try_common: bool,
+    incremental_charset: bool,
+    auto_extend: bool,
+    unroll: bool,
+    pin_threads: bool,
+    headers_only: bool,
+    strict: bool,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {:?}", path))?;
+        Self::from_toml(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// The long option names with their values, None for flags without a value
+    fn options(&self) -> Vec<(&'static str, Option<String>)> {
+        let values = [
+            ("alphabet", self.alphabet.clone()),
+            ("exclude", self.exclude.clone()),
+            ("min-length", self.min_length.map(|n| n.to_string())),
+            ("max-length", self.max_length.map(|n| n.to_string())),
+            ("max-length-cap", self.max_length_cap.map(|n| n.to_string())),
+            ("prefix", self.prefix.clone()),
+            ("suffix", self.suffix.clone()),
+            ("markov", self.markov.clone()),
+            ("file-glob", self.file_glob.clone()),
+            ("magic", self.magic.clone()),
+            ("num-threads", self.num_threads.map(|n| n.to_string())),
+            (
+                "refresh-interval",
+                self.refresh_interval.map(|n| n.to_string()),
+            ),
+            ("log-interval", self.log_interval.map(|n| n.to_string())),
+            ("logfile", self.logfile.clone()),
+            ("output", self.output.clone()),
+            ("format", self.format.clone()),
+            ("color", self.color.clone()),
+            (
+                "max-false-positive-rate",
+                self.max_false_positive_rate.map(|n| n.to_string()),
+            ),
+        ];
+        let flags = [
+            ("try-common", self.try_common),
+            ("incremental-charset", self.incremental_charset),
+            ("auto-extend", self.auto_extend),
+            ("unroll", self.unroll),
+            ("pin-threads", self.pin_threads),
+            ("headers-only", self.headers_only),
+            ("strict", self.strict),
+        ];
+        let values = values
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, Some(value?))));
+        let flags = flags
+            .into_iter()
+            .filter(|&(_, is_set)| is_set)
+            .map(|(name, _)| (name, None));
+        values.chain(flags).collect()
+    }
+
+    /// The arguments for the options that aren't on the command line, to go in front of its
+    /// arguments. The command line arguments don't include the program name.
+    pub fn args(&self, command_line: &[OsString]) -> Vec<OsString> {
+        let mut args = Vec::new();
+        for (name, value) in self.options() {
+            if is_given(command_line, name) {
+                continue;
+            }
+            args.push(OsString::from(format!("--{}", name)));
+            args.extend(value.map(OsString::from));
+        }
+        args
+    }
+}
+
+/// Whether the option is on the command line, also as -a for --alphabet
+fn is_given(command_line: &[OsString], name: &str) -> bool {
+    let long = format!("--{}", name);
+    command_line
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| {
+            arg == long
+                || arg.starts_with(&format!("{}=", long))
+                || (name == "alphabet" && arg.starts_with("-a"))
+        })
+}
+
+/// The file of --config, found before the other options are parsed so that they can be added
+pub fn config_path(command_line: &[OsString]) -> Option<PathBuf> {
+    let mut args = command_line.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn os_strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_config_args() {
+        let config = Config::from_toml(
+            "alphabet = \"lower+digits\"\nmax-length = 6\nlogfile = \"run.json\"\nunroll = true\n\
+             strict = false\n",
+        )
+        .unwrap();
+        let command_line = os_strings(&["a.zip", "--config", "zipcrack.toml", "--max-length=4"]);
+        assert_eq!(
+            config_path(&command_line),
+            Some(PathBuf::from("zipcrack.toml"))
+        );
+        assert_eq!(
+            config.args(&command_line),
+            os_strings(&[
+                "--alphabet",
+                "lower+digits",
+                "--logfile",
+                "run.json",
+                "--unroll"
+            ])
+        );
+        let command_line = os_strings(&["-a", "upper", "a.zip"]);
+        assert_eq!(config_path(&command_line), None);
+        assert!(!config.args(&command_line).contains(&"--alphabet".into()));
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let e = Config::from_toml("max-lenght = 6").unwrap_err();
+        assert!(
+            e.to_string().contains("unknown field `max-lenght`"),
+            "{}",
+            e
+        );
+        assert!(Config::from_toml("max-length = \"6\"").is_err());
+    }
+}
@@ -1,66 +1,405 @@
+use crate::decompress::CompressionMethod;
 use crate::decrypt::{
-    encryption_data_matches, password_matches_unrolled, EncryptionData, PasswordBlock,
-    RESULT_CAPACITY,
+    encryption_data_matches, file_data_starts_with, password_matches, password_matches_scalar,
+    password_matches_unrolled, select_password_matcher, update_keys, EncryptionData, PasswordBlock,
+    PasswordBlockMatcher, RESULT_CAPACITY,
 };
-use crate::info::{run_with_info_thread, InfoData};
-use crate::opt::Opt;
-use crate::password_iter::{test_each_password, test_each_password_unrolled};
-use crate::zipfile::Record;
+use crate::extract::verify_candidate;
+use crate::info::{
+    format_duration, password_rate, run_with_info_thread, warn, CancellationToken, FoundPassword,
+    InfoData,
+};
+use crate::opt::{Alphabet, CrackOptions, Opt};
+use crate::password_iter::{
+    for_each_password, for_each_password_unrolled, keyspace_size, test_each_password,
+    test_each_password_unrolled, test_password_list, test_password_range, test_stdin_passwords,
+    InitializedKeys, Password, LARGE_KEYSPACE, TINY_KEYSPACE,
+};
+use crate::zipfile::{EncryptionMethod, LocalFile, ZipArchive};
 
+use anyhow::bail;
 use arrayvec::ArrayVec;
+use core_affinity::CoreId;
 
+use std::any::Any;
+use std::ffi::OsString;
+use std::ops::Range;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+fn encryption_data(local_file: &LocalFile) -> Option<EncryptionData> {
+    Some(EncryptionData {
+        encryption_header: local_file.encryption_header?.bytes,
+        last_mod_file_time: local_file.local_file_header.last_mod_file_time,
+        crc32: local_file.local_file_header.crc32,
+        has_data_descriptor: local_file.local_file_header.has_data_descriptor(),
+    })
+}
+
+/// Whether the file matches the --file-glob, if any
+fn is_selected(opt: &Opt, local_file: &LocalFile) -> bool {
+    opt.file_glob.as_ref().is_none_or(|pattern| {
+        pattern.matches(&local_file.local_file_header.file_name.to_string_lossy())
+    })
+}
+
+/// The encrypted files to check passwords against, with their encryption data. Only files that
+/// match the --file-glob are used. Files with the same encryption data as an earlier file are
+/// skipped, since they can't eliminate any more passwords. There's no need to limit the number of
+/// files: a password is only checked against a file if it passed the ones before, which is rare.
+fn files_to_check<'a>(opt: &Opt, zipfile: &'a ZipArchive) -> Vec<(&'a LocalFile, EncryptionData)> {
+    let mut files: Vec<(&LocalFile, EncryptionData)> = Vec::new();
+    for local_file in zipfile.encrypted_files().filter(|lf| is_selected(opt, lf)) {
+        if let Some(ed) = encryption_data(local_file) {
+            if files.iter().all(|&(_, other)| other != ed) {
+                files.push((local_file, ed));
+            }
+        }
+    }
+    files
+}
 
-pub fn get_encryption_data(zipfile: &[Record]) -> Vec<EncryptionData> {
+pub fn get_encryption_data(opt: &Opt, zipfile: &ZipArchive) -> Vec<EncryptionData> {
+    files_to_check(opt, zipfile)
+        .into_iter()
+        .map(|(_, ed)| ed)
+        .collect()
+}
+
+/// For --format, the number of encrypted files in the archive whose check the keys pass
+pub fn num_matching_files(zipfile: &ZipArchive, keys: [u32; 3]) -> usize {
     zipfile
+        .encrypted_files()
+        .filter_map(encryption_data)
+        .filter(|&ed| encryption_data_matches(ed, keys))
+        .count()
+}
+
+/// The start of the first file that is checked, to be compared against the --magic bytes
+#[derive(Clone)]
+pub struct MagicCheck {
+    ed: EncryptionData,
+    file_data: Vec<u8>,
+    magic: Vec<u8>,
+}
+
+impl MagicCheck {
+    pub fn matches(&self, keys: [u32; 3]) -> bool {
+        file_data_starts_with(self.ed, keys, &self.file_data, &self.magic)
+    }
+}
+
+pub fn get_magic_check(opt: &Opt, zipfile: &ZipArchive) -> Option<MagicCheck> {
+    let magic = opt.magic.clone()?.0;
+    let (local_file, ed) = files_to_check(opt, zipfile).into_iter().next()?;
+    if local_file.local_file_header.compression_method != 0 {
+        warn(
+            opt.color,
+            format!(
+                "{:?} is compressed, so --magic will likely reject every password",
+                local_file.local_file_header.file_name
+            ),
+        );
+    }
+    let file_data = local_file
+        .file_data
         .iter()
-        .filter_map(|record| {
-            let local_file = record.get_local_file()?;
-            let encryption_header = local_file.encryption_header?.bytes;
-            Some(EncryptionData {
-                encryption_header,
-                last_mod_file_time: local_file.local_file_header.last_mod_file_time,
-            })
+        .take(magic.len())
+        .copied()
+        .collect();
+    Some(MagicCheck {
+        ed,
+        file_data,
+        magic,
+    })
+}
+
+/// With --extract, the files whose CRC-32 a found password has to reproduce before the search
+/// stops at it
+#[derive(Clone)]
+pub struct CrcCheck {
+    local_files: Vec<LocalFile>,
+    /// None with --independent-files, where finding one file's password must not stop the search
+    /// for the others
+    cancel: Option<CancellationToken>,
+}
+
+impl CrcCheck {
+    /// Decrypts the files. If they all pass, stops the search, since this is the password.
+    pub fn matches(&self, keys: [u32; 3]) -> bool {
+        let matches = self
+            .local_files
+            .iter()
+            .all(|local_file| verify_candidate(local_file, keys));
+        if matches {
+            if let Some(cancel) = &self.cancel {
+                cancel.cancel();
+            }
+        }
+        matches
+    }
+}
+
+pub fn get_crc_check(opt: &Opt, zipfile: &ZipArchive) -> Option<CrcCheck> {
+    opt.extract.as_ref()?;
+    let local_files: Vec<LocalFile> = files_to_check(opt, zipfile)
+        .into_iter()
+        .map(|(local_file, _)| local_file)
+        .filter(|local_file| {
+            let method = CompressionMethod::from(local_file.local_file_header.compression_method);
+            method.is_supported() && local_file.crc32().is_some()
         })
+        .cloned()
+        .collect();
+    if local_files.is_empty() {
+        warn(
+            opt.color,
+            "None of the files can be decompressed to check the CRC-32, so --extract uses the \
+             first password that passes the encryption headers",
+        );
+        return None;
+    }
+    Some(CrcCheck {
+        local_files,
+        cancel: (!opt.independent_files).then(|| opt.cancel.clone()),
+    })
+}
+
+/// The names of the files that get_encryption_data() returns data for, in the same order
+pub fn get_encrypted_file_names(opt: &Opt, zipfile: &ZipArchive) -> Vec<OsString> {
+    files_to_check(opt, zipfile)
+        .into_iter()
+        .map(|(local_file, _)| local_file.local_file_header.file_name.clone())
         .collect()
 }
 
-pub fn crack(opt: Opt, zipfile: &[Record]) {
-    let eds = get_encryption_data(zipfile);
-    let callback = move |initialized_keys: [u32; 3]| -> bool {
-        for &ed in &eds {
+/// The cores available for --pin-threads, or None if the flag isn't set or affinity isn't
+/// supported on this platform
+fn pinning_cores(opt: &Opt) -> Option<Vec<CoreId>> {
+    if !opt.pin_threads {
+        return None;
+    }
+    core_affinity::get_core_ids().filter(|core_ids| !core_ids.is_empty())
+}
+
+/// The core each worker thread gets pinned to with --pin-threads, or None for every thread if
+/// the flag isn't set or affinity isn't supported on this platform. This is called for every
+/// stage of the search, so report_pinning() prints the decisions once instead.
+pub fn worker_cores(opt: &Opt) -> Vec<Option<CoreId>> {
+    let num_threads = usize::from(opt.num_threads);
+    match pinning_cores(opt) {
+        Some(core_ids) => (0..num_threads)
+            .map(|idx| Some(core_ids[idx % core_ids.len()]))
+            .collect(),
+        None => vec![None; num_threads],
+    }
+}
+
+/// Prints which core each worker thread gets pinned to with --pin-threads, before the search
+/// starts and takes over the terminal
+pub fn report_pinning(opt: &Opt) {
+    if !opt.pin_threads {
+        return;
+    }
+    let Some(core_ids) = pinning_cores(opt) else {
+        warn(
+            opt.color,
+            "Pinning threads is not supported on this platform, ignoring --pin-threads",
+        );
+        return;
+    };
+    let num_threads = usize::from(opt.num_threads);
+    if num_threads > core_ids.len() {
+        warn(
+            opt.color,
+            format!(
+                "{} threads but only {} cores, some cores get several threads",
+                num_threads,
+                core_ids.len()
+            ),
+        );
+    }
+    for idx in 0..num_threads {
+        println!(
+            "Pinning thread {} to core {}",
+            idx,
+            core_ids[idx % core_ids.len()].id
+        );
+    }
+}
+
+/// Pins the calling thread to the core, if any
+fn pin_current_thread(core_id: Option<CoreId>) {
+    if let Some(core_id) = core_id {
+        if !core_affinity::set_for_current(core_id) {
+            eprintln!("Could not pin a thread to core {}", core_id.id);
+        }
+    }
+}
+
+/// Cancels the search when a worker thread panics, so that the other workers stop instead of the
+/// crash only coming to light once they are done
+struct CancelOnPanic(CancellationToken);
+
+impl Drop for CancelOnPanic {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.cancel();
+        }
+    }
+}
+
+/// Spawns a worker thread pinned to the core, which cancels the search if it panics
+pub(crate) fn spawn_worker(
+    cancel: CancellationToken,
+    core_id: Option<CoreId>,
+    f: impl FnOnce() + Send + 'static,
+) -> JoinHandle<()> {
+    let cancel_on_panic = CancelOnPanic(cancel);
+    std::thread::spawn(move || {
+        let _cancel_on_panic = cancel_on_panic;
+        pin_current_thread(core_id);
+        f()
+    })
+}
+
+/// Waits for all worker threads, then passes on the first panic
+pub(crate) fn join_workers(threads: Vec<JoinHandle<()>>) {
+    let mut first_panic = None;
+    for join_handle in threads {
+        if let Err(e) = join_handle.join() {
+            first_panic.get_or_insert(e);
+        }
+    }
+    if let Some(e) = first_panic {
+        std::panic::resume_unwind(e);
+    }
+}
+
+/// How often --verbose reports rejected passwords, per thread
+const REJECTION_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checks a password against all files and the --magic bytes
+pub(crate) fn password_predicate(
+    opt: &Opt,
+    zipfile: &ZipArchive,
+) -> impl FnMut(&Password, InitializedKeys) -> bool + Clone + Send + 'static {
+    let eds = get_encryption_data(opt, zipfile);
+    let magic_check = get_magic_check(opt, zipfile);
+    let crc_check = get_crc_check(opt, zipfile);
+    let verbose = opt.verbose > 0;
+    let file_names = get_encrypted_file_names(opt, zipfile);
+    let mut last_report: Option<Instant> = None;
+    move |pw: &Password, initialized_keys: [u32; 3]| -> bool {
+        // The files aren't reordered and nothing is shared between their checks: each file
+        // rejects all but about 1 in 65536 passwords with its two check bytes, so almost all the
+        // time is spent on the first file, no matter which one it is. benches/crack.rs measures
+        // this with 4 and with 50 files.
+        for (i, &ed) in eds.iter().enumerate() {
             if !encryption_data_matches(ed, initialized_keys) {
+                // Passing the first file is rare enough that this doesn't slow down the search
+                if verbose
+                    && i > 0
+                    && last_report.is_none_or(|t| t.elapsed() >= REJECTION_REPORT_INTERVAL)
+                {
+                    last_report = Some(Instant::now());
+                    eprintln!(
+                        "{:?} passed {} file(s), but was rejected by {:?}",
+                        String::from_utf8_lossy(pw),
+                        i,
+                        file_names[i]
+                    );
+                }
                 return false;
             }
         }
-        true
-    };
+        if !magic_check
+            .as_ref()
+            .is_none_or(|magic_check| magic_check.matches(initialized_keys))
+        {
+            return false;
+        }
+        let Some(crc_check) = &crc_check else {
+            return true;
+        };
+        let matches = crc_check.matches(initialized_keys);
+        if !matches
+            && verbose
+            && last_report.is_none_or(|t| t.elapsed() >= REJECTION_REPORT_INTERVAL)
+        {
+            last_report = Some(Instant::now());
+            eprintln!(
+                "{:?} passed all files, but the decrypted contents have the wrong CRC-32",
+                String::from_utf8_lossy(pw)
+            );
+        }
+        matches
+    }
+}
 
-    run_with_info_thread(opt, move |opt: Opt, info_data: Arc<InfoData>| {
-        let mut threads = Vec::new();
-        for idx in 0..opt.num_threads {
-            let opt = opt.clone();
-            let info_data = info_data.clone();
-            let callback = callback.clone();
-            let join_handle =
-                std::thread::spawn(move || test_each_password(opt, info_data, idx, callback));
-            threads.push(join_handle);
-        }
-        threads.into_iter().for_each(|join_handle| {
-            if let Err(e) = join_handle.join() {
-                std::panic::resume_unwind(e);
-            }
-        });
+pub fn crack(opt: Opt, zipfile: &ZipArchive, info_data: Arc<InfoData>) {
+    let callback = password_predicate(&opt, zipfile);
+    run_with_info_thread(opt, info_data, move |opt: Opt, info_data: Arc<InfoData>| {
+        if opt.stdin_passwords {
+            test_stdin_passwords(&opt, std::io::stdin().lock(), info_data, callback);
+            return;
+        }
+        run_threads(opt, info_data, callback);
     });
 }
 
-pub fn crack_unrolled(opt: Opt, zipfile: &[Record]) {
-    let eds = get_encryption_data(zipfile);
-    let callback = move |password_block: PasswordBlock| -> Vec<Vec<u8>> {
+/// Tests all passwords with --num-threads workers
+fn run_threads(
+    opt: Opt,
+    info_data: Arc<InfoData>,
+    callback: impl FnMut(&Password, InitializedKeys) -> bool + Clone + Send + 'static,
+) {
+    let mut threads = Vec::new();
+    for (idx, core_id) in (0..opt.num_threads).zip(worker_cores(&opt)) {
+        let opt = opt.clone();
+        let info_data = info_data.clone();
+        let callback = callback.clone();
+        threads.push(spawn_worker(opt.cancel.clone(), core_id, move || {
+            test_each_password(opt, info_data, idx, callback)
+        }));
+    }
+    join_workers(threads);
+}
+
+/// Tests the passwords with indices in the range (see for_each_password_range()), without the
+/// status display
+pub fn crack_range(opt: Opt, zipfile: &ZipArchive, info_data: Arc<InfoData>, range: Range<u128>) {
+    let callback = password_predicate(&opt, zipfile);
+    let mut threads = Vec::new();
+    for (idx, core_id) in (0..opt.num_threads).zip(worker_cores(&opt)) {
+        let opt = opt.clone();
+        let info_data = info_data.clone();
+        let callback = callback.clone();
+        let range = range.clone();
+        threads.push(spawn_worker(opt.cancel.clone(), core_id, move || {
+            test_password_range(opt, info_data, idx, range, callback)
+        }));
+    }
+    join_workers(threads);
+}
+
+/// The password block check for the unrolled algorithm, against all files and the --magic bytes
+fn unrolled_callback(
+    opt: &Opt,
+    zipfile: &ZipArchive,
+) -> impl FnMut(PasswordBlock) -> Vec<Vec<u8>> + Clone + Send + 'static {
+    let eds = get_encryption_data(opt, zipfile);
+    let magic_check = get_magic_check(opt, zipfile);
+    let crc_check = get_crc_check(opt, zipfile);
+    let password_matches = select_password_matcher();
+    move |password_block: PasswordBlock| -> Vec<Vec<u8>> {
         let mut matching_chars = ArrayVec::<u8, RESULT_CAPACITY>::new();
         let mut iter = eds.iter();
         if let Some(ed) = iter.next() {
-            password_matches_unrolled(password_block, *ed, &mut matching_chars);
+            password_matches(password_block, *ed, &mut matching_chars);
         };
         if matching_chars.is_empty() {
             return Vec::new();
@@ -69,7 +408,7 @@ pub fn crack_unrolled(opt: Opt, zipfile: &[Record]) {
         // Ok, the password passed the first file – check against the other files
         let mut matching_chars_other = ArrayVec::<u8, RESULT_CAPACITY>::new();
         for ed in iter {
-            password_matches_unrolled(password_block, *ed, &mut matching_chars_other);
+            password_matches(password_block, *ed, &mut matching_chars_other);
             // Only keep passwords that match all files/eds
             matching_chars.retain(|ch| matching_chars_other.contains(ch));
             matching_chars_other.clear();
@@ -78,6 +417,21 @@ pub fn crack_unrolled(opt: Opt, zipfile: &[Record]) {
             }
         }
 
+        if let Some(magic_check) = &magic_check {
+            matching_chars.retain(|&mut ch| {
+                let mut keys = password_block.initialized_keys;
+                update_keys(&mut keys, ch);
+                magic_check.matches(keys)
+            });
+        }
+        if let Some(crc_check) = &crc_check {
+            matching_chars.retain(|&mut ch| {
+                let mut keys = password_block.initialized_keys;
+                update_keys(&mut keys, ch);
+                crc_check.matches(keys)
+            });
+        }
+
         let mut new_passwords: Vec<_> = matching_chars
             .iter()
             .copied()
@@ -90,23 +444,1184 @@ pub fn crack_unrolled(opt: Opt, zipfile: &[Record]) {
         new_passwords.dedup();
         matching_chars.clear();
         new_passwords
+    }
+}
+
+fn run_unrolled_threads(
+    opt: Opt,
+    info_data: Arc<InfoData>,
+    callback: impl FnMut(PasswordBlock) -> Vec<Vec<u8>> + Clone + Send + 'static,
+) {
+    let mut threads = Vec::new();
+    for (idx, core_id) in (0..opt.num_threads).zip(worker_cores(&opt)) {
+        let opt = opt.clone();
+        let info_data = info_data.clone();
+        let callback = callback.clone();
+        threads.push(spawn_worker(opt.cancel.clone(), core_id, move || {
+            test_each_password_unrolled(opt, info_data, idx, callback)
+        }));
+    }
+    join_workers(threads);
+}
+
+/// The unrolled search appends at least one character to the prefix, so the prefix on its own is
+/// tested with the scalar path here, and the minimum length is raised past it
+fn test_bare_prefix(opt: &mut Opt, zipfile: &ZipArchive, info_data: Arc<InfoData>) {
+    let Some(prefix_length) = opt.prefix.as_ref().and_then(|p| u8::try_from(p.len()).ok()) else {
+        return;
     };
+    if opt.min_length > prefix_length {
+        return;
+    }
+    // Later alphabets of --incremental-charset and start passwords are past the bare prefix
+    let start_password = opt.start_password.as_deref().unwrap_or_default();
+    if opt.previous_alphabet.is_none() && start_password.is_empty() {
+        let mut bare_prefix = opt.clone();
+        bare_prefix.max_length = prefix_length;
+        bare_prefix.num_threads = 1;
+        let callback = password_predicate(&bare_prefix, zipfile);
+        test_each_password(bare_prefix, info_data, 0, callback);
+    }
+    opt.min_length = prefix_length + 1;
+}
 
-    run_with_info_thread(opt, move |opt: Opt, info_data: Arc<InfoData>| {
-        let mut threads = Vec::new();
-        for idx in 0..opt.num_threads {
-            let opt = opt.clone();
-            let info_data = info_data.clone();
-            let callback = callback.clone();
-            let join_handle = std::thread::spawn(move || {
-                test_each_password_unrolled(opt, info_data, idx, callback)
+pub fn crack_unrolled(mut opt: Opt, zipfile: &ZipArchive, info_data: Arc<InfoData>) {
+    test_bare_prefix(&mut opt, zipfile, info_data.clone());
+    let callback = unrolled_callback(&opt, zipfile);
+    run_with_info_thread(opt, info_data, move |opt: Opt, info_data: Arc<InfoData>| {
+        run_unrolled_threads(opt, info_data, callback)
+    });
+}
+
+/// Like crack_unrolled(), but without the status display, e.g. for benchmarks
+pub fn crack_unrolled_without_display(
+    mut opt: Opt,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) {
+    test_bare_prefix(&mut opt, zipfile, info_data.clone());
+    let callback = unrolled_callback(&opt, zipfile);
+    run_unrolled_threads(opt, info_data, callback);
+}
+
+/// Tries all passwords on the calling thread, without the status display. For environments
+/// without threads, like the browser.
+pub fn find_passwords(opt: Opt, zipfile: &ZipArchive) -> Vec<FoundPassword> {
+    let eds = get_encryption_data(&opt, zipfile);
+    let mut found_passwords = Vec::new();
+    if eds.is_empty() {
+        return found_passwords;
+    }
+    for_each_password(opt, 0, |pw: &Password, keys: InitializedKeys| {
+        if eds.iter().all(|&ed| encryption_data_matches(ed, keys)) {
+            found_passwords.push(FoundPassword {
+                password: String::from_utf8_lossy(pw).into_owned(),
+                keys,
             });
-            threads.push(join_handle);
         }
-        threads.into_iter().for_each(|join_handle| {
-            if let Err(e) = join_handle.join() {
-                std::panic::resume_unwind(e);
+    });
+    found_passwords
+}
+
+/// How many passwords passed each file's check on its own, and how many passed all of them
+#[derive(Debug, PartialEq, Eq)]
+pub struct MatchCounts {
+    pub total: u64,
+    /// The file names with their counts, in the order of get_encryption_data()
+    pub per_file: Vec<(OsString, u64)>,
+    pub all_files: u64,
+}
+
+/// Goes through the whole keyspace for --count-only and checks every password against every
+/// file, not only until the first file rejects it
+pub fn count_matches(opt: &Opt, zipfile: &ZipArchive) -> MatchCounts {
+    let eds = get_encryption_data(opt, zipfile);
+    let count_thread = |idx: u8| {
+        let mut total = 0;
+        let mut per_file = vec![0; eds.len()];
+        let mut all_files = 0;
+        let callback = |_: &Password, keys: InitializedKeys| {
+            total += 1;
+            let mut passed_all = true;
+            for (count, &ed) in per_file.iter_mut().zip(&eds) {
+                if encryption_data_matches(ed, keys) {
+                    *count += 1;
+                } else {
+                    passed_all = false;
+                }
+            }
+            if passed_all {
+                all_files += 1;
+            }
+        };
+        match &opt.markov_model {
+            Some(model) => model.for_each_password(opt, idx, callback),
+            None => for_each_password(opt.clone(), idx, callback),
+        }
+        (total, per_file, all_files)
+    };
+    let thread_counts: Vec<(u64, Vec<u64>, u64)> = std::thread::scope(|s| {
+        let threads: Vec<_> = (0..opt.num_threads)
+            .zip(worker_cores(opt))
+            .map(|(idx, core_id)| {
+                s.spawn(move || {
+                    pin_current_thread(core_id);
+                    count_thread(idx)
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    });
+    let mut counts = MatchCounts {
+        total: 0,
+        per_file: get_encrypted_file_names(opt, zipfile)
+            .into_iter()
+            .map(|file_name| (file_name, 0))
+            .collect(),
+        all_files: 0,
+    };
+    for (total, per_file, all_files) in thread_counts {
+        counts.total += total;
+        for ((_, count), thread_count) in counts.per_file.iter_mut().zip(per_file) {
+            *count += thread_count;
+        }
+        counts.all_files += all_files;
+    }
+    counts
+}
+
+/// One in how many wrong passwords passes the check of a file
+const FALSE_POSITIVE_ODDS: f64 = 65536.0;
+
+/// Prints the counts next to the expected ones. The check compares the last two bytes of the
+/// decrypted encryption header, so a wrong password passes a file with probability 1/65536.
+pub fn print_match_counts(counts: &MatchCounts) {
+    let total = counts.total as f64;
+    println!("Checked {} passwords", counts.total);
+    for (file_name, count) in &counts.per_file {
+        println!(
+            "{:?}: {} passed (expected about {:.1e} wrong ones)",
+            file_name,
+            count,
+            total / FALSE_POSITIVE_ODDS
+        );
+    }
+    println!(
+        "All {} files: {} passed (expected about {:.1e} wrong ones)",
+        counts.per_file.len(),
+        counts.all_files,
+        total / FALSE_POSITIVE_ODDS.powi(counts.per_file.len() as i32)
+    );
+}
+
+/// How many wrong passwords of the keyspace are expected to pass the checks of all files and the
+/// --magic bytes
+pub fn expected_false_positives(opt: &Opt, zipfile: &ZipArchive) -> f64 {
+    let num_files = get_encryption_data(opt, zipfile).len();
+    let magic_length = opt.magic.as_ref().map_or(0, |magic| magic.0.len());
+    keyspace_size(opt) as f64
+        / FALSE_POSITIVE_ODDS.powi(num_files as i32)
+        / 256f64.powi(magic_length as i32)
+}
+
+/// Warns before the search if more wrong passwords than --max-false-positive-rate are expected
+/// to pass, or fails with --strict. With --extract, candidates are checked against the CRC-32, so
+/// wrong passwords don't get through.
+pub fn check_false_positives(opt: &Opt, zipfile: &ZipArchive) -> anyhow::Result<()> {
+    if opt.extract.is_some() || opt.stdin_passwords || opt.markov_model.is_some() {
+        return Ok(());
+    }
+    let expected = expected_false_positives(opt, zipfile);
+    if expected <= opt.max_false_positive_rate {
+        return Ok(());
+    }
+    let message = format!(
+        "About {:.1e} wrong passwords are expected to pass the checks of {} file(s), more than \
+         --max-false-positive-rate {}. Use an archive with more encrypted files, --magic, or \
+         --extract, which checks the CRC-32 of the found passwords.",
+        expected,
+        get_encryption_data(opt, zipfile).len(),
+        opt.max_false_positive_rate
+    );
+    if opt.strict {
+        bail!(message);
+    }
+    warn(opt.color, message);
+    Ok(())
+}
+
+/// A SplitMix64 generator, which is plenty for drawing random keys
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Checks random initialized keys against all files and the --magic bytes, for --sample, and
+/// returns how many passed. The keys of wrong passwords are as good as random, so this is the
+/// false positive rate without enumerating any passwords.
+pub fn sample_matches(opt: &Opt, zipfile: &ZipArchive, num_samples: u64, seed: u64) -> u64 {
+    let eds = get_encryption_data(opt, zipfile);
+    let magic_check = get_magic_check(opt, zipfile);
+    let mut rng = SplitMix64(seed);
+    let mut passed = 0;
+    for _ in 0..num_samples {
+        let random = rng.next();
+        let keys = [random as u32, (random >> 32) as u32, rng.next() as u32];
+        if eds.iter().all(|&ed| encryption_data_matches(ed, keys))
+            && magic_check.as_ref().is_none_or(|check| check.matches(keys))
+        {
+            passed += 1;
+        }
+    }
+    passed
+}
+
+/// Prints the result of sample_matches() and what it means for the whole keyspace. The seed is
+/// printed so that the run can be repeated with --seed.
+pub fn print_sample_result(
+    opt: &Opt,
+    zipfile: &ZipArchive,
+    num_samples: u64,
+    seed: u64,
+    passed: u64,
+) {
+    let num_files = get_encryption_data(opt, zipfile).len();
+    println!(
+        "{} of {} random keys (seed {}) passed the checks of {} file(s)",
+        passed, num_samples, seed, num_files
+    );
+    if num_samples == 0 {
+        return;
+    }
+    // With few passes, the sampled rate is mostly noise, so fall back to the theoretical one
+    let rate = if passed > 0 {
+        passed as f64 / num_samples as f64
+    } else {
+        FALSE_POSITIVE_ODDS.powi(num_files as i32).recip()
+    };
+    let expected = keyspace_size(opt) as f64 * rate;
+    println!(
+        "Expect about {:.1e} wrong passwords to pass in the keyspace of {:.1e}",
+        expected,
+        keyspace_size(opt) as f64
+    );
+    if expected >= 1.0 {
+        warn(
+            opt.color,
+            "Wrong passwords will likely be reported. Use an archive with more encrypted files or \
+             --magic to rule them out.",
+        );
+    }
+}
+
+/// Searches for a password whose initialized keys are these, e.g. keys from a known-plaintext
+/// attack. Only feasible for short passwords, since it's another brute force search, which also
+/// tries the empty password.
+pub fn recover_password_from_keys(
+    opt: &Opt,
+    keys: InitializedKeys,
+    info_data: Arc<InfoData>,
+) -> Option<String> {
+    let mut opt = opt.clone();
+    opt.min_length = 0;
+    let keyspace = keyspace_size(&opt);
+    if keyspace > LARGE_KEYSPACE {
+        warn(
+            opt.color,
+            format!(
+                "Searching {:.1e} passwords for the keys, a lower --max-length is much faster",
+                keyspace as f64
+            ),
+        );
+    }
+    let cancel = opt.cancel.clone();
+    let predicate = move |_: &Password, initialized_keys: InitializedKeys| {
+        let matches = initialized_keys == keys;
+        if matches {
+            cancel.cancel();
+        }
+        matches
+    };
+    run_with_info_thread(
+        opt,
+        info_data.clone(),
+        move |opt: Opt, info_data: Arc<InfoData>| run_threads(opt, info_data, predicate),
+    );
+    let found_passwords = info_data.found_passwords.lock().unwrap();
+    found_passwords.first().map(|found| found.password.clone())
+}
+
+/// The encryption data that --self-test checks, same as in test_three_letter_passwords
+const SELF_TEST_ED: EncryptionData = EncryptionData::from_header_and_time(
+    [147, 150, 41, 25, 165, 183, 31, 129, 76, 121, 70, 196],
+    40784,
+);
+/// The 3-character base64 passwords that match SELF_TEST_ED, in enumeration order
+const SELF_TEST_PASSWORDS: [&[u8]; 7] = [b"3bw", b"5Zx", b"IWY", b"M3e", b"P61", b"eVw", b"sHD"];
+
+/// Checks the password search against a known answer, then measures the speed of one thread
+pub fn self_test() -> anyhow::Result<()> {
+    check_known_answer()?;
+    println!("Self-test passed");
+
+    let base64: Alphabet = "base64".parse().unwrap();
+    let block_size = base64.0.len() as u64;
+    let opt: Opt = CrackOptions::new(base64)
+        .min_length(4)
+        .max_length(4)
+        .logfile("")
+        .into();
+    let password_block_matches = select_password_matcher();
+    let start_time = Instant::now();
+    let mut counter = 0;
+    for_each_password_unrolled(opt, 0, |password_block: PasswordBlock| {
+        let mut matching_chars = ArrayVec::new();
+        password_block_matches(password_block, SELF_TEST_ED, &mut matching_chars);
+        counter += block_size;
+    });
+    println!(
+        "Measured {:.2}M passwords/s on one thread",
+        password_rate(counter, start_time.elapsed()) / 1_000_000.0
+    );
+    Ok(())
+}
+
+/// Checks that every implementation of the password check finds exactly the known passwords for
+/// SELF_TEST_ED
+fn check_known_answer() -> anyhow::Result<()> {
+    let base64: Alphabet = "base64".parse().unwrap();
+    let opt: Opt = CrackOptions::new(base64)
+        .min_length(3)
+        .max_length(3)
+        .logfile("")
+        .into();
+    let check = |name: &str, found: Vec<Vec<u8>>| -> anyhow::Result<()> {
+        if found != SELF_TEST_PASSWORDS {
+            bail!(
+                "Self-test failed: {} found {:?}, expected {:?}",
+                name,
+                found
+                    .iter()
+                    .map(|pw| String::from_utf8_lossy(pw))
+                    .collect::<Vec<_>>(),
+                SELF_TEST_PASSWORDS.map(String::from_utf8_lossy)
+            );
+        }
+        Ok(())
+    };
+
+    let found = SELF_TEST_PASSWORDS
+        .iter()
+        .filter(|pw| password_matches(SELF_TEST_ED, pw))
+        .map(|pw| pw.to_vec())
+        .collect();
+    check("password_matches", found)?;
+
+    let mut found = Vec::new();
+    for_each_password(opt.clone(), 0, |pw: &Password, keys: InitializedKeys| {
+        if encryption_data_matches(SELF_TEST_ED, keys) {
+            found.push(pw.to_vec());
+        }
+    });
+    check("for_each_password", found)?;
+
+    let matchers: [(&str, PasswordBlockMatcher); 2] = [
+        ("password_matches_scalar", password_matches_scalar),
+        ("password_matches_unrolled", password_matches_unrolled),
+    ];
+    for (name, password_block_matches) in matchers {
+        let mut found = Vec::new();
+        for_each_password_unrolled(opt.clone(), 0, |password_block: PasswordBlock| {
+            let mut matching_chars = ArrayVec::new();
+            password_block_matches(password_block, SELF_TEST_ED, &mut matching_chars);
+            for ch in matching_chars {
+                let mut password = password_block.password_prefix.to_vec();
+                password.push(ch);
+                found.push(password);
             }
         });
+        check(name, found)?;
+    }
+    Ok(())
+}
+
+/// How many passwords --estimate tries at most
+const ESTIMATE_PASSWORDS: u64 = 20_000_000;
+/// How long --estimate runs at most
+const ESTIMATE_DURATION: Duration = Duration::from_secs(5);
+
+/// Measures the speed of the unrolled algorithm on this machine, in passwords per second, and
+/// returns it with the number of passwords it tried
+fn measure_rate(opt: &Opt, zipfile: &ZipArchive) -> (f64, u64) {
+    let mut opt = opt.clone();
+    opt.cancel = CancellationToken::new();
+    let info_data = InfoData::new();
+    let callback = unrolled_callback(&opt, zipfile);
+    let start_time = Instant::now();
+    std::thread::scope(|s| {
+        let search = s.spawn(|| run_unrolled_threads(opt.clone(), info_data.clone(), callback));
+        while !search.is_finished()
+            && info_data.counter.load(Ordering::Relaxed) < ESTIMATE_PASSWORDS
+            && start_time.elapsed() < ESTIMATE_DURATION
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        opt.cancel.cancel();
     });
+    let elapsed = start_time.elapsed();
+    let counter = info_data.counter.load(Ordering::Relaxed);
+    (password_rate(counter, elapsed), counter)
+}
+
+/// How long searching this many passwords takes at the rate
+fn search_duration(keyspace: f64, rate: f64) -> String {
+    format_duration(Duration::from_secs_f64(
+        (keyspace / rate).min(u64::MAX as f64),
+    ))
+}
+
+/// Measures the speed of the unrolled algorithm on this machine and projects how long searching
+/// the whole keyspace takes
+pub fn estimate(opt: &Opt, zipfile: &ZipArchive) {
+    let (rate, counter) = measure_rate(opt, zipfile);
+    let keyspace = keyspace_size(opt) as f64;
+    println!(
+        "Measured {:.2}M passwords/s over {} passwords",
+        rate / 1_000_000.0,
+        counter
+    );
+    println!(
+        "The keyspace of {:.1e} passwords takes about {}",
+        keyspace,
+        search_duration(keyspace, rate)
+    );
+}
+
+/// Like --estimate, but for each of the built-in alphabets and the configured one, for
+/// --benchmark-alphabets. The speed hardly depends on the alphabet, so it's measured only once.
+pub fn benchmark_alphabets(opt: &Opt, zipfile: &ZipArchive) {
+    let mut alphabets: Vec<(String, Alphabet)> = Alphabet::presets()
+        .map(|(name, alphabet)| (name.to_owned(), alphabet))
+        .collect();
+    if !opt.alphabet.0.is_empty() {
+        alphabets.push((String::from("--alphabet"), opt.alphabet.clone()));
+    }
+    let mut measure_opt = opt.clone();
+    measure_opt.alphabet = alphabets.last().unwrap().1.clone();
+    let (rate, counter) = measure_rate(&measure_opt, zipfile);
+    println!(
+        "Measured {:.2}M passwords/s over {} passwords, lengths {}-{}",
+        rate / 1_000_000.0,
+        counter,
+        opt.min_length,
+        opt.max_length
+    );
+    println!("{:<12} {:>5} {:>9}  Time", "Alphabet", "Chars", "Keyspace");
+    for (name, alphabet) in alphabets {
+        let mut opt = opt.clone();
+        opt.alphabet = alphabet;
+        let keyspace = keyspace_size(&opt) as f64;
+        println!(
+            "{:<12} {:>5} {:>9.1e}  {}",
+            name,
+            opt.alphabet.0.len(),
+            keyspace,
+            search_duration(keyspace, rate)
+        );
+    }
+}
+
+/// Commonly used passwords for --try-common, one per line, most common first. Compiled from
+/// published lists of the most common passwords in breaches.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// Tests the common passwords, and returns the ones that match
+pub fn try_common_passwords(
+    opt: &Opt,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> Vec<FoundPassword> {
+    let found_before = info_data.found_passwords.lock().unwrap().len();
+    println!(
+        "Trying {} common passwords",
+        COMMON_PASSWORDS.lines().count()
+    );
+    test_password_list(
+        COMMON_PASSWORDS.lines(),
+        info_data.clone(),
+        password_predicate(opt, zipfile),
+    );
+    let found_passwords = info_data.found_passwords.lock().unwrap();
+    let found_common = found_passwords[found_before..].to_vec();
+    for found in &found_common {
+        println!("Common password matched: {:?}", found.password);
+    }
+    found_common
+}
+
+/// Fails if there are no files to check passwords against, since every password would match
+pub fn check_files_to_check(opt: &Opt, zipfile: &ZipArchive) -> anyhow::Result<()> {
+    // These would be skipped, which points to a parsing problem rather than an archive without
+    // encrypted files
+    let missing_headers: Vec<&OsString> = zipfile
+        .encrypted_files()
+        .filter(|local_file| {
+            is_selected(opt, local_file)
+                && local_file.local_file_header.encryption_method()
+                    == Some(EncryptionMethod::ZipCrypto)
+                && local_file.encryption_header.is_none()
+        })
+        .map(|local_file| &local_file.local_file_header.file_name)
+        .collect();
+    if !files_to_check(opt, zipfile).is_empty() {
+        Ok(())
+    } else if !missing_headers.is_empty() {
+        bail!(
+            "The encryption headers of {:?} couldn't be parsed, so there is nothing to check \
+             passwords against",
+            missing_headers
+        )
+    } else if let Some(pattern) = &opt.file_glob {
+        bail!(
+            "--file-glob {:?} matches no encrypted files that can be cracked",
+            pattern.as_str()
+        )
+    } else if zipfile.is_any_encrypted() {
+        bail!("None of the encrypted files can be cracked")
+    } else {
+        bail!("No encrypted files found, nothing to crack")
+    }
+}
+
+/// Runs the search with the configured algorithm. With --incremental-charset, there is one search
+/// per alphabet in the sequence of growing alphabets. If the search is cancelled, returns the
+/// passwords found until then.
+pub fn run(
+    opt: Opt,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> anyhow::Result<Vec<FoundPassword>> {
+    check_files_to_check(&opt, zipfile)?;
+    opt.check_start_password()?;
+    // A crashed worker must not look like a search that found nothing
+    std::panic::catch_unwind(AssertUnwindSafe(|| search(opt, zipfile, info_data)))
+        .unwrap_or_else(|e| bail!("The search crashed: {}", panic_message(e.as_ref())))
+}
+
+/// The message that a panic was raised with
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown error", String::as_str),
+    }
+}
+
+/// The search of run(), after the files were checked
+fn search(
+    opt: Opt,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> anyhow::Result<Vec<FoundPassword>> {
+    if opt.try_common {
+        let found_common = try_common_passwords(&opt, zipfile, info_data.clone());
+        if !found_common.is_empty() {
+            return Ok(found_common);
+        }
+        println!("No common password matched, starting the search");
+    }
+    if opt.stdin_passwords {
+        crack(opt, zipfile, info_data.clone());
+        let found_passwords = info_data.found_passwords.lock().unwrap();
+        return Ok(found_passwords.clone());
+    }
+    let mut opt = opt;
+    loop {
+        search_alphabets(&opt, zipfile, info_data.clone());
+        let found_any = !info_data.found_passwords.lock().unwrap().is_empty();
+        if found_any
+            || opt.cancel.is_cancelled()
+            || !opt.auto_extend
+            || opt.max_length >= opt.max_length_cap
+        {
+            break;
+        }
+        // The shorter passwords were all tried, so continue with only the next length
+        println!(
+            "No password found up to length {}, continuing with length {}",
+            opt.max_length,
+            opt.max_length + 1
+        );
+        opt.max_length += 1;
+        opt.min_length = opt.max_length;
+        opt.start_password = None;
+    }
+    let found_passwords = info_data.found_passwords.lock().unwrap();
+    Ok(found_passwords.clone())
+}
+
+/// One search per alphabet, see run()
+fn search_alphabets(opt: &Opt, zipfile: &ZipArchive, info_data: Arc<InfoData>) {
+    let alphabets = if opt.incremental_charset {
+        opt.alphabet.incremental()
+    } else {
+        vec![opt.alphabet.clone()]
+    };
+    let mut previous_alphabet = None;
+    for alphabet in alphabets {
+        if opt.cancel.is_cancelled() {
+            break;
+        }
+        let mut opt = opt.clone();
+        opt.alphabet = alphabet.clone();
+        opt.previous_alphabet = previous_alphabet.replace(alphabet);
+        let keyspace = keyspace_size(&opt);
+        if opt.markov_model.is_some() {
+            // The model only tries the likely passwords, so the keyspace would overstate the search
+            println!(
+                "Alphabet: {} chars [{}], lengths {}-{}, Markov threshold {} bits",
+                opt.alphabet.0.len(),
+                opt.alphabet,
+                opt.min_length,
+                opt.max_length,
+                opt.markov_threshold
+            );
+        } else {
+            println!(
+                "Alphabet: {} chars [{}], lengths {}-{}, keyspace ≈ {:.1e}",
+                opt.alphabet.0.len(),
+                opt.alphabet,
+                opt.min_length,
+                opt.max_length,
+                keyspace as f64
+            );
+        }
+        if opt.markov_model.is_none() && keyspace <= TINY_KEYSPACE {
+            search_tiny_keyspace(opt, zipfile, info_data.clone(), keyspace);
+        } else if opt.unroll {
+            crack_unrolled(opt, zipfile, info_data.clone());
+        } else {
+            crack(opt, zipfile, info_data.clone());
+        }
+    }
+}
+
+/// Tries all passwords on the calling thread and prints how many right away. Such a search is
+/// over before the info thread would notice, so it would end without any output.
+fn search_tiny_keyspace(
+    mut opt: Opt,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+    keyspace: u128,
+) {
+    let cancel = opt.cancel.clone();
+    let start_count = info_data.counter.load(Ordering::Relaxed);
+    // E.g. with a minimum length above the maximum length, there is nothing to enumerate
+    if keyspace > 0 {
+        opt.num_threads = 1;
+        let callback = password_predicate(&opt, zipfile);
+        test_each_password(opt, info_data.clone(), 0, callback);
+    }
+    // The caller reports the found passwords
+    if cancel.is_cancelled() {
+        let tried = info_data.counter.load(Ordering::Relaxed) - start_count;
+        println!("Tried {} of {} candidates", tried, keyspace);
+    } else {
+        println!("Tried all {} candidates", keyspace);
+    }
+}
+
+/// Searches for the password of each checked file on its own, for archives whose files were
+/// encrypted with different passwords. Returns the file names with the passwords found for them.
+pub fn run_independent(
+    opt: Opt,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> anyhow::Result<Vec<(OsString, Vec<FoundPassword>)>> {
+    check_files_to_check(&opt, zipfile)?;
+    let mut results = Vec::new();
+    for (local_file, _) in files_to_check(&opt, zipfile) {
+        if opt.cancel.is_cancelled() {
+            break;
+        }
+        let file_name = local_file.local_file_header.file_name.clone();
+        println!("Searching for the password of {:?}", file_name);
+        let single_file = ZipArchive::from_local_file(local_file.clone());
+        let found_before = info_data.found_passwords.lock().unwrap().len();
+        run(opt.clone(), &single_file, info_data.clone())?;
+        let found_passwords = info_data.found_passwords.lock().unwrap();
+        results.push((file_name, found_passwords[found_before..].to_vec()));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::{
+        crc32_checksum, decrypt_byte, keys_for_password, update_keys, INITIAL_KEYS,
+    };
+    use crate::zipfile::test::LocalFileBytes;
+    use std::sync::atomic::AtomicBool;
+
+    /// An encrypted local file with the encryption header and no data. The high word of its
+    /// CRC-32 is the time, so the header checks the same as SELF_TEST_ED.
+    fn encrypted_file_bytes(file_name: &str, encryption_header: [u8; 12]) -> Vec<u8> {
+        LocalFileBytes {
+            flags: 1,
+            last_mod_file_time: 0x9f50,
+            crc32: 0x9f50_0000,
+            uncompressed_size: Some(0),
+            file_name,
+            data: &encryption_header,
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    /// Like encrypted_file_bytes(), but with bit 3 set, so the CRC-32 is in a data descriptor
+    /// and the header is checked against the time
+    fn encrypted_file_bytes_with_data_descriptor(
+        file_name: &str,
+        encryption_header: [u8; 12],
+    ) -> Vec<u8> {
+        LocalFileBytes {
+            flags: 9,
+            last_mod_file_time: 0x9f50,
+            uncompressed_size: Some(0),
+            file_name,
+            data: &encryption_header,
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_duplicate_encryption_data() {
+        let mut input =
+            encrypted_file_bytes_with_data_descriptor("a.txt", SELF_TEST_ED.encryption_header);
+        input.extend(encrypted_file_bytes_with_data_descriptor(
+            "b.txt",
+            SELF_TEST_ED.encryption_header,
+        ));
+        input.extend(encrypted_file_bytes_with_data_descriptor("c.txt", [0; 12]));
+        let archive = ZipArchive::parse(&input).unwrap();
+        assert_eq!(archive.encrypted_files().count(), 3);
+        let opt = CrackOptions::new("base64".parse().unwrap()).into();
+        assert_eq!(
+            get_encryption_data(&opt, &archive),
+            vec![
+                SELF_TEST_ED,
+                EncryptionData {
+                    encryption_header: [0; 12],
+                    ..SELF_TEST_ED
+                }
+            ]
+        );
+        assert_eq!(
+            get_encrypted_file_names(&opt, &archive),
+            vec!["a.txt", "c.txt"]
+        );
+    }
+
+    #[test]
+    fn test_count_matches() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let mut opt: Opt = CrackOptions::new("custom:abcdefghijklmnopqrstuvwxyz".parse().unwrap())
+            .min_length(3)
+            .max_length(3)
+            .threads(2)
+            .logfile("")
+            .into();
+        let counts = count_matches(&opt, &archive);
+        assert_eq!(counts.total, 26 * 26 * 26);
+        assert_eq!(counts.per_file.len(), 3);
+        assert!(counts.per_file.iter().all(|&(_, count)| count >= 1));
+        assert_eq!(counts.all_files, 1);
+
+        opt.num_threads = 1;
+        assert_eq!(count_matches(&opt, &archive), counts);
+    }
+
+    #[test]
+    fn test_sample_matches() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .logfile("")
+            .into();
+        // Three files, so no random key is expected to pass all of them
+        assert_eq!(sample_matches(&opt, &archive, 100_000, 1), 0);
+        let single_file =
+            ZipArchive::from_local_file(archive.local_files().next().unwrap().clone());
+        let passed = sample_matches(&opt, &single_file, 1_000_000, 1);
+        // About 15 are expected
+        assert!((3..50).contains(&passed), "{} passed", passed);
+        // The same seed draws the same keys
+        assert_eq!(sample_matches(&opt, &single_file, 1_000_000, 1), passed);
+    }
+
+    #[test]
+    fn test_check_false_positives() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let single_file =
+            ZipArchive::from_local_file(archive.local_files().next().unwrap().clone());
+        let mut opt: Opt = CrackOptions::new("base64".parse().unwrap())
+            .max_length(4)
+            .logfile("")
+            .into();
+        // 64^4 + 64^3 + 64^2 + 64 passwords, one in 65536 passes
+        assert_eq!(
+            expected_false_positives(&opt, &single_file),
+            17_043_520.0 / 65536.0
+        );
+        assert!(expected_false_positives(&opt, &archive) < 1e-6);
+
+        opt.strict = true;
+        assert!(check_false_positives(&opt, &archive).is_ok());
+        assert!(check_false_positives(&opt, &single_file).is_err());
+        opt.max_false_positive_rate = 300.0;
+        assert!(check_false_positives(&opt, &single_file).is_ok());
+    }
+
+    #[test]
+    fn test_auto_extend() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let mut opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(1)
+            .logfile("")
+            .into();
+        opt.auto_extend = true;
+        opt.max_length_cap = 2;
+        assert!(run(opt.clone(), &archive, InfoData::new())
+            .unwrap()
+            .is_empty());
+
+        opt.max_length_cap = 4;
+        let info_data = InfoData::new();
+        let found = run(opt, &archive, info_data.clone()).unwrap();
+        assert_eq!(found[0].password, "abc");
+        // 3 + 9 + 27 passwords, but only up to the found one in the last length
+        assert!(info_data.counter.load(Ordering::Relaxed) <= 3 + 9 + 27);
+    }
+
+    #[test]
+    fn test_tiny_keyspace() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let mut opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(3)
+            .logfile("")
+            .into();
+        let info_data = InfoData::new();
+        let found = run(opt.clone(), &archive, info_data.clone()).unwrap();
+        assert_eq!(found[0].password, "abc");
+        assert!(info_data.counter.load(Ordering::Relaxed) <= 3 + 9 + 27);
+
+        // Used to hang
+        opt.min_length = 4;
+        let info_data = InfoData::new();
+        assert!(run(opt, &archive, info_data.clone()).unwrap().is_empty());
+        assert_eq!(info_data.counter.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_unrolled_bare_prefix() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .prefix("abc")
+            .min_length(3)
+            .max_length(6)
+            .unroll(true)
+            .logfile("")
+            .into();
+        let info_data = InfoData::new();
+        crack_unrolled_without_display(opt, &archive, info_data.clone());
+        let found_passwords = info_data.found_passwords.lock().unwrap();
+        assert_eq!(found_passwords.len(), 1);
+        assert_eq!(found_passwords[0].password, "abc");
+    }
+
+    #[test]
+    fn test_without_data_descriptor() {
+        // Without bit 3, the encryption header ends with the high word of the CRC-32 instead of
+        // the time
+        let text = b"Hello, world!\n";
+        let crc32 = crc32_checksum(text);
+        let mut keys = keys_for_password(b"abc");
+        let mut plaintext = vec![7; 10];
+        plaintext.extend(((crc32 >> 16) as u16).to_le_bytes());
+        plaintext.extend(text);
+        let ciphertext: Vec<u8> = plaintext
+            .into_iter()
+            .map(|p| {
+                let c = p ^ decrypt_byte(keys[2]);
+                update_keys(&mut keys, p);
+                c
+            })
+            .collect();
+        let input = LocalFileBytes {
+            flags: 1,
+            last_mod_file_time: 0x1234,
+            crc32,
+            uncompressed_size: Some(text.len() as u32),
+            data: &ciphertext,
+            ..Default::default()
+        }
+        .to_bytes();
+        let archive = ZipArchive::parse(&input).unwrap();
+        let local_file = archive.encrypted_files().next().unwrap();
+        assert!(local_file.data_descriptor.is_none());
+        for unroll in [false, true] {
+            let opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+                .max_length(3)
+                .unroll(unroll)
+                .logfile("")
+                .into();
+            let found = run(opt, &archive, InfoData::new()).unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].password, "abc");
+        }
+    }
+
+    #[test]
+    fn test_control_byte_alphabet() {
+        let password = b"\0\x1f\0";
+        let mut input = Vec::new();
+        for (i, file_name) in ["a.txt", "b.txt", "c.txt"].into_iter().enumerate() {
+            let mut keys = keys_for_password(password);
+            let mut plaintext = [i as u8; 12];
+            plaintext[10..].copy_from_slice(&[0x50, 0x9f]);
+            let encryption_header = plaintext.map(|p| {
+                let c = p ^ decrypt_byte(keys[2]);
+                update_keys(&mut keys, p);
+                c
+            });
+            input.extend(encrypted_file_bytes(file_name, encryption_header));
+        }
+        let archive = ZipArchive::parse(&input).unwrap();
+        for unroll in [false, true] {
+            let opt: Opt = CrackOptions::new("hex:001f+digits".parse().unwrap())
+                .max_length(3)
+                .unroll(unroll)
+                .logfile("")
+                .into();
+            let found = run(opt, &archive, InfoData::new()).unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].password.as_bytes(), password);
+        }
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let opt: Opt = CrackOptions::new("custom:ab".parse().unwrap())
+            .max_length(40)
+            .unroll(true)
+            .threads(2)
+            .logfile("")
+            .into();
+        let cancel = opt.cancel.clone();
+        // Only the first worker that gets here panics, the other one would search for ages
+        let panicked = Arc::new(AtomicBool::new(false));
+        let callback = move |password_block: PasswordBlock| -> Vec<Vec<u8>> {
+            if password_block.password_prefix.len() == 4 && !panicked.swap(true, Ordering::Relaxed)
+            {
+                panic!("Injected failure");
+            }
+            Vec::new()
+        };
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            run_with_info_thread(opt, InfoData::new(), move |opt, info_data| {
+                run_unrolled_threads(opt, info_data, callback)
+            })
+        }));
+        let e = result.unwrap_err();
+        assert_eq!(panic_message(e.as_ref()), "Injected failure");
+        // The other worker was stopped
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn test_no_encrypted_files() {
+        let mut input = encrypted_file_bytes("a.txt", [0; 12]);
+        // Clear the encryption flag
+        input[6] = 0;
+        let archive = ZipArchive::parse(&input).unwrap();
+        assert_eq!(archive.local_files().count(), 1);
+        let opt: Opt = CrackOptions::new("base64".parse().unwrap())
+            .logfile("")
+            .into();
+        let e = run(opt, &archive, InfoData::new()).unwrap_err();
+        assert_eq!(e.to_string(), "No encrypted files found, nothing to crack");
+        assert!(find_passwords(
+            CrackOptions::new("base64".parse().unwrap()).into(),
+            &archive
+        )
+        .is_empty());
+
+        // Encrypted, but only 4 bytes of data, too short for the encryption header
+        let mut input = encrypted_file_bytes("a.txt", [0; 12]);
+        input[18..22].copy_from_slice(&4u32.to_le_bytes());
+        input.drain(39..47);
+        let archive = ZipArchive::parse(&input).unwrap();
+        assert!(archive
+            .local_files()
+            .next()
+            .unwrap()
+            .encryption_header
+            .is_none());
+        let opt: Opt = CrackOptions::new("base64".parse().unwrap())
+            .logfile("")
+            .into();
+        let e = run(opt, &archive, InfoData::new()).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("The encryption headers of [\"a.txt\"]"));
+    }
+
+    #[test]
+    fn test_file_glob() {
+        let mut input = encrypted_file_bytes("docs/a.pdf", [1; 12]);
+        input.extend(encrypted_file_bytes("b.txt", [2; 12]));
+        input.extend(encrypted_file_bytes("c.pdf", [3; 12]));
+        let archive = ZipArchive::parse(&input).unwrap();
+        let mut opt: Opt = CrackOptions::new("base64".parse().unwrap()).into();
+        opt.file_glob = Some("*.pdf".parse().unwrap());
+        assert_eq!(
+            get_encrypted_file_names(&opt, &archive),
+            vec!["docs/a.pdf", "c.pdf"]
+        );
+        opt.file_glob = Some("*.doc".parse().unwrap());
+        assert!(get_encryption_data(&opt, &archive).is_empty());
+    }
+
+    #[test]
+    fn test_independent_files() {
+        let mut input = encrypted_file_bytes("a.txt", SELF_TEST_ED.encryption_header);
+        input.extend(encrypted_file_bytes("b.txt", [0; 12]));
+        let archive = ZipArchive::parse(&input).unwrap();
+        let opt: Opt = CrackOptions::new("base64".parse().unwrap())
+            .min_length(3)
+            .max_length(3)
+            .logfile("")
+            .into();
+        // Together, no password matches both files
+        assert!(find_passwords(opt.clone(), &archive).is_empty());
+        let results = run_independent(opt, &archive, InfoData::new()).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "a.txt");
+        let found: Vec<String> = results[0].1.iter().map(|f| f.password.clone()).collect();
+        assert_eq!(found, SELF_TEST_PASSWORDS.map(String::from_utf8_lossy));
+        assert_eq!(results[1].0, "b.txt");
+    }
+
+    #[test]
+    fn test_archive_from_json() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let imported =
+            crate::zipfile::archive_from_json(&crate::zipfile::records_to_json(&archive)).unwrap();
+        let opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(3)
+            .logfile("")
+            .into();
+        assert_eq!(
+            get_encryption_data(&opt, &imported),
+            get_encryption_data(&opt, &archive)
+        );
+        let found = find_passwords(opt, &imported);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].password, "abc");
+    }
+
+    #[test]
+    fn test_raw_header() {
+        let archive = ZipArchive::from_encryption_header(
+            SELF_TEST_ED.encryption_header,
+            SELF_TEST_ED.last_mod_file_time,
+        );
+        let opt = CrackOptions::new("base64".parse().unwrap())
+            .min_length(3)
+            .max_length(3)
+            .logfile("")
+            .into();
+        let encryption_data = get_encryption_data(&opt, &archive);
+        assert_eq!(encryption_data.len(), 1);
+        assert_eq!(encryption_data[0].check_bytes(), SELF_TEST_ED.check_bytes());
+        let found: Vec<String> = find_passwords(opt, &archive)
+            .into_iter()
+            .map(|found| found.password)
+            .collect();
+        assert_eq!(found, SELF_TEST_PASSWORDS.map(String::from_utf8_lossy));
+    }
+
+    #[test]
+    fn test_common_passwords() {
+        assert!(COMMON_PASSWORDS.lines().all(|line| !line.is_empty()));
+        // "dragon" with the encryption header from Info-ZIP's zip
+        let archive = ZipArchive::from_encryption_header(
+            [174, 224, 3, 29, 14, 47, 166, 59, 135, 45, 179, 92],
+            0x138b,
+        );
+        let opt: Opt = CrackOptions::new("base64".parse().unwrap())
+            .logfile("")
+            .into();
+        let found = try_common_passwords(&opt, &archive, InfoData::new());
+        assert!(found.iter().any(|found| found.password == "dragon"));
+    }
+
+    #[test]
+    fn test_extract_checks_crc() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let mut opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(4)
+            .logfile("")
+            .into();
+        assert!(get_crc_check(&opt, &archive).is_none());
+        // The directory is only used after the search
+        opt.extract = Some(std::path::PathBuf::from("out"));
+        let crc_check = get_crc_check(&opt, &archive).unwrap();
+        assert!(!crc_check.matches(keys_for_password(b"abd")));
+        assert!(!opt.cancel.is_cancelled());
+
+        let found = run(opt.clone(), &archive, InfoData::new()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].password, "abc");
+        assert!(opt.cancel.is_cancelled());
+    }
+
+    #[test]
+    fn test_known_answer() {
+        check_known_answer().unwrap();
+    }
+
+    #[test]
+    fn test_recover_password_from_keys() {
+        let options = |max_length| -> Opt {
+            CrackOptions::new("base64".parse().unwrap())
+                .max_length(max_length)
+                .threads(2)
+                .logfile("")
+                .into()
+        };
+        let recover = |max_length, keys| {
+            recover_password_from_keys(&options(max_length), keys, InfoData::new())
+        };
+        assert_eq!(recover(3, INITIAL_KEYS), Some(String::new()));
+        let keys = keys_for_password(b"aZ9");
+        assert_eq!(recover(3, keys), Some(String::from("aZ9")));
+        assert_eq!(recover(2, keys), None);
+    }
 }
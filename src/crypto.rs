@@ -0,0 +1,162 @@
+//! The ZipCrypto primitives from APPNOTE.TXT 6.1. This module only uses core, so that it also
+//! builds without the "std" feature, e.g. for embedded targets.
+
+use core::num::Wrapping;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncryptionData {
+    pub encryption_header: [u8; 12],
+    pub last_mod_file_time: u16,
+    pub crc32: u32,
+    /// Whether bit 3 of the general purpose flag is set. Then the CRC-32 isn't known yet when
+    /// the encryption header is written, so the header ends with the time instead.
+    pub has_data_descriptor: bool,
+}
+
+impl EncryptionData {
+    /// The encryption data of a header whose last two bytes check the time, like with a data
+    /// descriptor, e.g. for headers captured by other tools
+    pub const fn from_header_and_time(header: [u8; 12], last_mod_file_time: u16) -> Self {
+        Self {
+            encryption_header: header,
+            last_mod_file_time,
+            crc32: 0,
+            has_data_descriptor: true,
+        }
+    }
+
+    /// The last two bytes of the decrypted encryption header (APPNOTE.TXT 6.1.6): the time with
+    /// a data descriptor, otherwise the high word of the CRC-32
+    #[inline(always)]
+    pub fn check_bytes(&self) -> [u8; 2] {
+        if self.has_data_descriptor {
+            self.last_mod_file_time.to_le_bytes()
+        } else {
+            ((self.crc32 >> 16) as u16).to_le_bytes()
+        }
+    }
+}
+
+const fn crc32_byte(mut byte: u8) -> u32 {
+    byte = byte.reverse_bits();
+    let mut value = (byte as u32) << 24;
+    let mut i = 0;
+    while i < 8 {
+        value = (value << 1) ^ ((value >> 31) * 0x04c11db7);
+        i += 1;
+    }
+    value = value.reverse_bits();
+    value
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < table.len() {
+        table[i] = crc32_byte(i as u8);
+        i += 1;
+    }
+    table
+}
+
+pub(crate) const CRC_32_TAB: &[u32; 256] = &crc32_table();
+
+pub const fn crc32(crc: u32, byte: u8) -> u32 {
+    let index = crc as u8 ^ byte;
+    CRC_32_TAB[index as usize] ^ (crc >> 8)
+}
+
+/// The CRC-32 of the data, as stored in the ZIP headers
+pub fn crc32_checksum(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| crc32(crc, byte))
+}
+
+/// 6.1.5 Initializing the encryption keys: the keys before the first password byte
+pub const INITIAL_KEYS: [u32; 3] = [0x12345678, 0x23456789, 0x34567890];
+
+/// The keys after initialization with the password, which is what decrypts the files
+pub fn keys_for_password(password: &[u8]) -> [u32; 3] {
+    let mut keys = INITIAL_KEYS;
+    for &c in password {
+        update_keys(&mut keys, c);
+    }
+    keys
+}
+
+#[inline(always)]
+pub fn update_keys(keys: &mut [u32; 3], c: u8) {
+    keys[0] = crc32(keys[0], c);
+    keys[1] = (Wrapping(keys[1]) + Wrapping(keys[0] & 0x000000ff)).0;
+    keys[1] = (Wrapping(keys[1]) * Wrapping(134775813) + Wrapping(1)).0;
+    keys[2] = crc32(keys[2], (keys[1] >> 24) as u8);
+}
+
+#[inline(always)]
+pub fn decrypt_byte(key2: u32) -> u8 {
+    let temp = key2 as u16 | 2;
+    let res = (Wrapping(temp) * Wrapping(temp ^ 1)).0 >> 8;
+    res as u8
+}
+
+/// The "plain" version of the password check as described in APPNOTE.TXT
+#[allow(unused)]
+pub fn password_matches(ed: EncryptionData, password: &[u8]) -> bool {
+    check_header(ed, keys_for_password(password))
+}
+
+pub fn encryption_data_matches(ed: EncryptionData, keys: [u32; 3]) -> bool {
+    check_header(ed, keys)
+}
+
+/// The check of the encryption header that all password checks share, given the keys after the
+/// password
+#[inline(always)]
+fn check_header(ed: EncryptionData, mut keys: [u32; 3]) -> bool {
+    // 6.1.6 Decrypting the encryption header. Only the last two bytes are compared, so the
+    // others only need to update the keys.
+    for &buf in &ed.encryption_header[..10] {
+        let c: u8 = buf ^ decrypt_byte(keys[2]);
+        update_keys(&mut keys, c);
+    }
+
+    // The last bytes in buffer should be the timestamp or CRC. Almost all passwords already fail
+    // on the low byte, so the high byte is only decrypted for the rest.
+    let [low_byte, high_byte] = ed.check_bytes();
+    let c = ed.encryption_header[10] ^ decrypt_byte(keys[2]);
+    if c != low_byte {
+        return false;
+    }
+    update_keys(&mut keys, c);
+    ed.encryption_header[11] ^ decrypt_byte(keys[2]) == high_byte
+}
+
+/// Checks whether the file data, decrypted with keys that passed the encryption header check,
+/// starts with the expected plaintext
+pub fn file_data_starts_with(
+    ed: EncryptionData,
+    mut keys: [u32; 3],
+    file_data: &[u8],
+    plaintext: &[u8],
+) -> bool {
+    // 6.1.6 Decrypting the encryption header
+    decrypt_stream(&mut keys, &ed.encryption_header, &mut [0; 12]);
+
+    // The file data is decrypted in the same way, continuing with the keys after the header
+    file_data.len() >= plaintext.len()
+        && file_data.iter().zip(plaintext).all(|(&buf, &expected)| {
+            let c: u8 = buf ^ decrypt_byte(keys[2]);
+            update_keys(&mut keys, c);
+            c == expected
+        })
+}
+
+/// Decrypts an arbitrary amount of data, advancing the keys as it goes. The ciphertext of an
+/// entry consists of the encryption header, followed by the file data.
+#[inline(always)]
+pub fn decrypt_stream(keys: &mut [u32; 3], ciphertext: &[u8], out: &mut [u8]) {
+    for (&buf, plain) in ciphertext.iter().zip(out) {
+        let c: u8 = buf ^ decrypt_byte(keys[2]);
+        update_keys(keys, c);
+        *plain = c;
+    }
+}
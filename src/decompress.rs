@@ -0,0 +1,174 @@
+use std::fmt;
+use std::io::Read;
+
+use anyhow::{bail, Result};
+use flate2::read::DeflateDecoder;
+
+/// The compression methods from APPNOTE.TXT 4.4.5 that we know about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionMethod {
+    Stored,
+    Deflate,
+    Deflate64,
+    Bzip2,
+    Lzma,
+    Other(u16),
+}
+
+impl From<u16> for CompressionMethod {
+    fn from(method: u16) -> Self {
+        match method {
+            0 => CompressionMethod::Stored,
+            8 => CompressionMethod::Deflate,
+            9 => CompressionMethod::Deflate64,
+            12 => CompressionMethod::Bzip2,
+            14 => CompressionMethod::Lzma,
+            other => CompressionMethod::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for CompressionMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionMethod::Stored => write!(f, "Stored"),
+            CompressionMethod::Deflate => write!(f, "Deflate"),
+            CompressionMethod::Deflate64 => write!(f, "Deflate64"),
+            CompressionMethod::Bzip2 => write!(f, "Bzip2"),
+            CompressionMethod::Lzma => write!(f, "LZMA"),
+            CompressionMethod::Other(method) => write!(f, "method {}", method),
+        }
+    }
+}
+
+impl CompressionMethod {
+    /// Whether decompress() can handle this method in this build
+    pub fn is_supported(self) -> bool {
+        match self {
+            CompressionMethod::Stored | CompressionMethod::Deflate => true,
+            CompressionMethod::Bzip2 => cfg!(feature = "bzip2"),
+            CompressionMethod::Lzma => cfg!(feature = "lzma"),
+            CompressionMethod::Deflate64 | CompressionMethod::Other(_) => false,
+        }
+    }
+}
+
+/// Turns the (already decrypted) file data into the original file contents
+pub fn decompress(
+    method: CompressionMethod,
+    data: &[u8],
+    uncompressed_size: Option<usize>,
+) -> Result<Vec<u8>> {
+    match method {
+        CompressionMethod::Stored => Ok(data.to_vec()),
+        CompressionMethod::Deflate => {
+            let mut decompressed = Vec::with_capacity(initial_capacity(data, uncompressed_size));
+            DeflateDecoder::new(data).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        #[cfg(feature = "bzip2")]
+        CompressionMethod::Bzip2 => {
+            let mut decompressed = Vec::with_capacity(initial_capacity(data, uncompressed_size));
+            bzip2::read::BzDecoder::new(data).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        #[cfg(not(feature = "bzip2"))]
+        CompressionMethod::Bzip2 => {
+            bail!("Extracting Bzip2 requires building with --features bzip2")
+        }
+        #[cfg(feature = "lzma")]
+        CompressionMethod::Lzma => decompress_lzma(data, uncompressed_size),
+        #[cfg(not(feature = "lzma"))]
+        CompressionMethod::Lzma => bail!("Extracting LZMA requires building with --features lzma"),
+        method => bail!("Unsupported compression method: {}", method),
+    }
+}
+
+/// The size of the decompressed data to allocate up front. The uncompressed size comes from the
+/// archive, so a crafted one could allocate gigabytes for every candidate password. The Vec grows
+/// if the data is larger.
+fn initial_capacity(data: &[u8], uncompressed_size: Option<usize>) -> usize {
+    uncompressed_size
+        .unwrap_or_default()
+        .min(data.len().saturating_mul(4))
+}
+
+/// LZMA in ZIP files has its own header (APPNOTE.TXT 5.8.8): The LZMA SDK version and the size of
+/// the properties, followed by the properties and the raw stream. Unlike in .lzma files, there's
+/// no uncompressed size. Without one from the archive, the stream has to end with a marker.
+#[cfg(feature = "lzma")]
+fn decompress_lzma(data: &[u8], uncompressed_size: Option<usize>) -> Result<Vec<u8>> {
+    use anyhow::Context;
+    use lzma_rs::decompress::{Options, UnpackedSize};
+
+    let (header, stream) = data.split_at_checked(4).context("LZMA data is too short")?;
+    let properties_size = u16::from_le_bytes([header[2], header[3]]);
+    if properties_size != 5 {
+        bail!("Unexpected LZMA properties size {}", properties_size);
+    }
+    let options = Options {
+        unpacked_size: UnpackedSize::UseProvided(uncompressed_size.map(|size| size as u64)),
+        ..Default::default()
+    };
+    let mut decompressed = Vec::with_capacity(initial_capacity(data, uncompressed_size));
+    lzma_rs::lzma_decompress_with_options(&mut &stream[..], &mut decompressed, &options)
+        .map_err(|e| anyhow::anyhow!("Invalid LZMA data: {}", e))?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use flate2::read::DeflateEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn test_decompress() {
+        let text = b"To be, or not to be, that is the question. To be, or not to be.".to_vec();
+        let mut compressed = Vec::new();
+        DeflateEncoder::new(text.as_slice(), Compression::best())
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert_eq!(
+            decompress(CompressionMethod::Deflate, &compressed, Some(text.len())).unwrap(),
+            text
+        );
+        assert_eq!(
+            decompress(CompressionMethod::Stored, &text, Some(text.len())).unwrap(),
+            text
+        );
+        assert!(decompress(CompressionMethod::Deflate64, &text, Some(text.len())).is_err());
+        // A size field from a crafted archive doesn't allocate that much
+        assert_eq!(
+            decompress(
+                CompressionMethod::Deflate,
+                &compressed,
+                Some(u32::MAX as usize)
+            )
+            .unwrap(),
+            text
+        );
+        assert_eq!(
+            initial_capacity(&compressed, Some(u32::MAX as usize)),
+            compressed.len() * 4
+        );
+    }
+
+    #[cfg(feature = "lzma")]
+    #[test]
+    fn test_decompress_lzma() {
+        let text = b"To be, or not to be, that is the question. To be, or not to be.".to_vec();
+        let mut lzma_file = Vec::new();
+        lzma_rs::lzma_compress(&mut text.as_slice(), &mut lzma_file).unwrap();
+        // A .lzma file has the 5 bytes of properties, then the uncompressed size as a u64
+        let mut compressed = vec![16, 2, 5, 0];
+        compressed.extend(&lzma_file[..5]);
+        compressed.extend(&lzma_file[13..]);
+        assert_eq!(
+            decompress(CompressionMethod::Lzma, &compressed, Some(text.len())).unwrap(),
+            text
+        );
+        assert!(decompress(CompressionMethod::Lzma, &compressed[..3], Some(text.len())).is_err());
+    }
+}
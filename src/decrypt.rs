@@ -1,89 +1,57 @@
 use std::num::Wrapping;
+use std::sync::OnceLock;
 
 use arrayvec::ArrayVec;
 
-pub const RESULT_CAPACITY: usize = 256;
-
-#[derive(Clone, Copy)]
-pub struct EncryptionData {
-    pub encryption_header: [u8; 12],
-    pub last_mod_file_time: u16,
-}
-
-const fn crc32_byte(mut byte: u8) -> u32 {
-    byte = byte.reverse_bits();
-    let mut value = (byte as u32) << 24;
-    let mut i = 0;
-    while i < 8 {
-        value = (value << 1) ^ ((value >> 31) * 0x04c11db7);
-        i += 1;
-    }
-    value = value.reverse_bits();
-    value
-}
-
-const fn crc32_table() -> [u32; 256] {
-    let mut table = [0u32; 256];
-    let mut i = 0;
-    while i < table.len() {
-        table[i] = crc32_byte(i as u8);
-        i += 1;
-    }
-    table
-}
+use crate::crypto::CRC_32_TAB;
+pub use crate::crypto::{
+    crc32, decrypt_byte, decrypt_stream, encryption_data_matches, file_data_starts_with,
+    keys_for_password, password_matches, update_keys, EncryptionData, INITIAL_KEYS,
+};
 
-const CRC_32_TAB: &[u32; 256] = &crc32_table();
-
-pub const fn crc32(crc: u32, byte: u8) -> u32 {
-    let index = crc as u8 ^ byte;
-    CRC_32_TAB[index as usize] ^ (crc >> 8)
-}
+pub const RESULT_CAPACITY: usize = 256;
 
-#[inline(always)]
-pub fn update_keys(keys: &mut [u32; 3], c: u8) {
-    keys[0] = crc32(keys[0], c);
-    keys[1] = (Wrapping(keys[1]) + Wrapping(keys[0] & 0x000000ff)).0;
-    keys[1] = (Wrapping(keys[1]) * Wrapping(134775813) + Wrapping(1)).0;
-    keys[2] = crc32(keys[2], (keys[1] >> 24) as u8);
+/// Decrypts the file data of an entry, given the keys after initialization with the password
+pub fn decrypt_with_keys(
+    mut keys: [u32; 3],
+    encryption_header: [u8; 12],
+    file_data: &[u8],
+) -> Vec<u8> {
+    // 6.1.6 Decrypting the encryption header
+    decrypt_stream(&mut keys, &encryption_header, &mut [0; 12]);
+    // 6.1.7 Decrypting the compressed data stream
+    let mut plaintext = vec![0; file_data.len()];
+    decrypt_stream(&mut keys, file_data, &mut plaintext);
+    plaintext
 }
 
-#[inline(always)]
-pub fn decrypt_byte(key2: u32) -> u8 {
-    let temp = key2 as u16 | 2;
-    let res = (Wrapping(temp) * Wrapping(temp ^ 1)).0 >> 8;
-    res as u8
+/// Decrypts the file data of an entry as it is read, e.g. to decompress it without decrypting
+/// all of it first
+pub struct DecryptingReader<'a> {
+    keys: [u32; 3],
+    ciphertext: &'a [u8],
 }
 
-/// The "plain" version of the password check as described in APPNOTE.TXT
-#[allow(unused)]
-pub fn password_matches(mut ed: EncryptionData, password: &[u8]) -> bool {
-    // 6.1.5 Initializing the encryption keys
-    let mut keys = [305419896u32, 591751049u32, 878082192u32];
-    for &c in password {
-        update_keys(&mut keys, c);
-    }
-
-    // 6.1.6 Decrypting the encryption header
-    for buf in &mut ed.encryption_header {
-        let c: u8 = *buf ^ decrypt_byte(keys[2]);
-        update_keys(&mut keys, c);
-        *buf = c;
+impl<'a> DecryptingReader<'a> {
+    /// Given the keys after initialization with the password
+    pub fn new(mut keys: [u32; 3], encryption_header: [u8; 12], file_data: &'a [u8]) -> Self {
+        // 6.1.6 Decrypting the encryption header
+        decrypt_stream(&mut keys, &encryption_header, &mut [0; 12]);
+        Self {
+            keys,
+            ciphertext: file_data,
+        }
     }
-
-    // The last bytes in buffer should be the timestamp
-    ed.encryption_header[10..] == ed.last_mod_file_time.to_le_bytes()
 }
 
-pub fn encryption_data_matches(mut ed: EncryptionData, mut keys: [u32; 3]) -> bool {
-    // 6.1.6 Decrypting the encryption header
-    for buf in &mut ed.encryption_header {
-        let c: u8 = *buf ^ decrypt_byte(keys[2]);
-        update_keys(&mut keys, c);
-        *buf = c;
+impl std::io::Read for DecryptingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.ciphertext.len());
+        let (chunk, rest) = self.ciphertext.split_at(len);
+        decrypt_stream(&mut self.keys, chunk, &mut buf[..len]);
+        self.ciphertext = rest;
+        Ok(len)
     }
-
-    // The last bytes in buffer should be the timestamp
-    ed.encryption_header[10..] == ed.last_mod_file_time.to_le_bytes()
 }
 
 /// Represents a "block" of passwords where only the last letter is variable
@@ -130,6 +98,95 @@ pub fn decrypt_byte_chunked(key2: [u32; 8]) -> [u8; 8] {
     res
 }
 
+/// Checks all passwords of a password block against the encryption data, and pushes the last
+/// letters of the matching passwords
+pub type PasswordBlockMatcher =
+    fn(PasswordBlock, EncryptionData, &mut ArrayVec<u8, RESULT_CAPACITY>);
+
+/// The SIMD instructions of this CPU that the unrolled matcher can be vectorized with
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuFeatures {
+    pub sse2: bool,
+    pub avx2: bool,
+    pub neon: bool,
+    pub simd128: bool,
+}
+
+impl CpuFeatures {
+    /// Detects the features at runtime. WebAssembly has no runtime detection, there SIMD is a
+    /// compile-time choice.
+    pub fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let (sse2, avx2) = (
+            is_x86_feature_detected!("sse2"),
+            is_x86_feature_detected!("avx2"),
+        );
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        let (sse2, avx2) = (false, false);
+        #[cfg(target_arch = "aarch64")]
+        let neon = std::arch::is_aarch64_feature_detected!("neon");
+        #[cfg(not(target_arch = "aarch64"))]
+        let neon = false;
+        Self {
+            sse2,
+            avx2,
+            neon,
+            simd128: cfg!(target_feature = "simd128"),
+        }
+    }
+}
+
+/// The password block matchers that select_password_matcher() chooses from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatcherKind {
+    Scalar,
+    Unrolled,
+}
+
+impl MatcherKind {
+    /// The unrolled version relies on the compiler vectorizing it, which needs SIMD instructions.
+    /// Compiling it with AVX2 enabled was measured to be slower than the SSE2 baseline, because
+    /// the CRC table lookups turn into gather instructions, so AVX2 only implies SSE2 here.
+    pub fn for_features(features: CpuFeatures) -> Self {
+        if features.sse2 || features.avx2 || features.neon || features.simd128 {
+            MatcherKind::Unrolled
+        } else {
+            MatcherKind::Scalar
+        }
+    }
+
+    pub fn matcher(self) -> PasswordBlockMatcher {
+        match self {
+            MatcherKind::Scalar => password_matches_scalar,
+            MatcherKind::Unrolled => password_matches_unrolled,
+        }
+    }
+}
+
+/// Picks the fastest password block matcher for this CPU. The features are only detected on the
+/// first call.
+pub fn select_password_matcher() -> PasswordBlockMatcher {
+    static KIND: OnceLock<MatcherKind> = OnceLock::new();
+    KIND.get_or_init(|| MatcherKind::for_features(CpuFeatures::detect()))
+        .matcher()
+}
+
+/// Checks the passwords of the block one at a time
+#[inline(never)]
+pub fn password_matches_scalar(
+    password_block: PasswordBlock,
+    ed: EncryptionData,
+    matching_chars: &mut ArrayVec<u8, RESULT_CAPACITY>,
+) {
+    for &last_char in password_block.alphabet.iter().flatten() {
+        let mut keys = password_block.initialized_keys;
+        update_keys(&mut keys, last_char);
+        if encryption_data_matches(ed, keys) {
+            matching_chars.push(last_char);
+        }
+    }
+}
+
 #[inline(never)]
 pub fn password_matches_unrolled(
     password_block: PasswordBlock,
@@ -302,9 +359,8 @@ pub fn password_matches_unrolled(
             *buf = c.to_le_bytes();
         }
 
-        // The last bytes in buffer should be the timestamp
-        let low_byte = ed.last_mod_file_time.to_le_bytes()[0];
-        let high_byte = ed.last_mod_file_time.to_le_bytes()[1];
+        // The last bytes in buffer should be the timestamp or CRC
+        let [low_byte, high_byte] = ed.check_bytes();
         for i in 0..8 {
             if encryption_header_chunk[10][i] == low_byte
                 && encryption_header_chunk[11][i] == high_byte
@@ -324,18 +380,18 @@ mod test {
 
     #[test]
     fn test_three_letter_passwords() {
-        let ed = EncryptionData {
-            encryption_header: [147, 150, 41, 25, 165, 183, 31, 129, 76, 121, 70, 196],
-            last_mod_file_time: 40784,
-        };
+        let ed = EncryptionData::from_header_and_time(
+            [147, 150, 41, 25, 165, 183, 31, 129, 76, 121, 70, 196],
+            40784,
+        );
         let mut password = b"---".to_vec();
         let mut found_passwords = vec![];
-        for i in 0..64 {
-            password[0] = BASE64_ALPHABET[i];
-            for j in 0..64 {
-                password[1] = BASE64_ALPHABET[j];
-                for k in 0..64 {
-                    password[2] = BASE64_ALPHABET[k];
+        for &c0 in BASE64_ALPHABET {
+            password[0] = c0;
+            for &c1 in BASE64_ALPHABET {
+                password[1] = c1;
+                for &c2 in BASE64_ALPHABET {
+                    password[2] = c2;
                     if password_matches(ed, &password) {
                         found_passwords.push(password.clone());
                     }
@@ -353,4 +409,107 @@ mod test {
         ];
         assert_eq!(found_passwords, expected_passwords);
     }
+
+    #[test]
+    fn test_crc_check_bytes() {
+        // Decrypted with "3bw", the header ends with the bytes 0x50, 0x9f
+        let time_ed = EncryptionData::from_header_and_time(
+            [147, 150, 41, 25, 165, 183, 31, 129, 76, 121, 70, 196],
+            0x9f50,
+        );
+        let crc_ed = EncryptionData {
+            last_mod_file_time: 0,
+            crc32: 0x9f50_1234,
+            has_data_descriptor: false,
+            ..time_ed
+        };
+        assert!(password_matches(time_ed, b"3bw"));
+        assert!(password_matches(crc_ed, b"3bw"));
+        assert!(!password_matches(
+            EncryptionData {
+                has_data_descriptor: true,
+                ..crc_ed
+            },
+            b"3bw"
+        ));
+    }
+
+    #[test]
+    fn test_password_matchers() {
+        let ed = EncryptionData::from_header_and_time(
+            [147, 150, 41, 25, 165, 183, 31, 129, 76, 121, 70, 196],
+            40784,
+        );
+        let alphabet: Vec<[u8; 8]> = BASE64_ALPHABET
+            .chunks(8)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect();
+        let matchers: [PasswordBlockMatcher; 3] = [
+            password_matches_scalar,
+            password_matches_unrolled,
+            select_password_matcher(),
+        ];
+        let no_simd = CpuFeatures::default();
+        assert_eq!(MatcherKind::for_features(no_simd), MatcherKind::Scalar);
+        for features in [
+            CpuFeatures {
+                sse2: true,
+                ..no_simd
+            },
+            CpuFeatures {
+                neon: true,
+                ..no_simd
+            },
+            CpuFeatures {
+                simd128: true,
+                ..no_simd
+            },
+        ] {
+            assert_eq!(MatcherKind::for_features(features), MatcherKind::Unrolled);
+        }
+        for matcher in matchers {
+            let mut found_passwords = vec![];
+            let mut matching_chars = ArrayVec::new();
+            for &c0 in BASE64_ALPHABET {
+                for &c1 in BASE64_ALPHABET {
+                    let keys = keys_for_password(&[c0, c1]);
+                    let password_block = PasswordBlock {
+                        password_prefix: &[c0, c1],
+                        alphabet: &alphabet,
+                        initialized_keys: keys,
+                    };
+                    matcher(password_block, ed, &mut matching_chars);
+                    for c2 in matching_chars.drain(..) {
+                        found_passwords.push(vec![c0, c1, c2]);
+                    }
+                }
+            }
+            // Same as in test_three_letter_passwords
+            let expected_passwords = vec![
+                vec![51, 98, 119],
+                vec![53, 90, 120],
+                vec![73, 87, 89],
+                vec![77, 51, 101],
+                vec![80, 54, 49],
+                vec![101, 86, 119],
+                vec![115, 72, 68],
+            ];
+            assert_eq!(found_passwords, expected_passwords);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_stream() {
+        // A stored file with the password "aZ9", created with Info-ZIP's zip
+        let ciphertext = [
+            153, 212, 109, 2, 81, 95, 244, 33, 121, 249, 68, 127, 227, 6, 238, 11, 210, 47, 253, 0,
+            176, 11, 125, 10, 37,
+        ];
+        let mut keys = keys_for_password(b"aZ9");
+        let mut plaintext = [0; 25];
+        decrypt_stream(&mut keys, &ciphertext, &mut plaintext);
+        // The last byte of the encryption header is the high byte of the time
+        assert_eq!(plaintext[11], (2990u16 >> 8) as u8);
+        assert_eq!(&plaintext[12..], b"secret stuff\n");
+    }
 }
@@ -0,0 +1,386 @@
+//! The status display of the command line tool
+
+use std::io::{stdout, IsTerminal, Stdout};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::{
+    cursor::{Hide, MoveUp, RestorePosition, SavePosition, Show},
+    execute,
+    style::{Color, Stylize},
+    terminal::{self, Clear, ClearType, ScrollUp},
+    ExecutableCommand,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::info::{escape_control, log_to_file, password_rate, FoundPassword, InfoData};
+use crate::opt::{is_dumb_terminal, ColorChoice, Keys, Opt};
+use crate::password_iter::keyspace_size;
+
+macro_rules! handle_err {
+    ($result:expr) => {
+        if let Err(_) = $result {
+            println!("Could not control terminal, no output will be provided.");
+            return;
+        }
+    };
+}
+
+/// Prints the passwords found in the complete search, pointing out likely false positives
+pub fn report_found_passwords(found_passwords: &[FoundPassword], color: ColorChoice) {
+    let color = color.use_color(&stdout());
+    let highlight = |password: &str| {
+        let password = format!("{:?}", password);
+        if color {
+            password.green().to_string()
+        } else {
+            password
+        }
+    };
+    match found_passwords {
+        [] => println!("No password found"),
+        [found] => println!("Found password: {}", highlight(&found.password)),
+        _ => {
+            println!(
+                "Found {} candidate passwords, most of them are likely false positives:",
+                found_passwords.len()
+            );
+            for found in found_passwords {
+                println!("  {}", highlight(&found.password));
+            }
+            println!("Use --magic or an archive with more encrypted files to eliminate them.");
+        }
+    }
+}
+
+/// Prints the keys of each found password for --dump-keys, in the same format as bkcrack
+pub fn dump_keys(found_passwords: &[FoundPassword]) {
+    for found in found_passwords {
+        println!("Keys: {}", Keys(found.keys));
+    }
+}
+
+/// Whether the status lines can move the cursor around. Not when stdout is piped, e.g. into a
+/// logfile or in CI, or on a terminal that doesn't understand the escape codes.
+pub fn should_use_terminal_control() -> bool {
+    stdout().is_terminal() && !is_dumb_terminal()
+}
+
+pub fn restore_terminal(stdout: &mut Stdout) {
+    if should_use_terminal_control() {
+        handle_err!(execute!(stdout, Show, ScrollUp(NUM_STATUS_LINES)));
+    }
+}
+
+pub(crate) fn final_stats(data: Arc<InfoData>) {
+    println!(
+        "Total passwords tried: {}",
+        data.counter.load(Ordering::Relaxed)
+    );
+}
+
+/// How many of the found passwords the status display shows
+const MAX_DISPLAYED_PASSWORDS: usize = 5;
+
+/// The most recently found passwords for the status display. The logfile has all of them.
+fn format_found_passwords(found_passwords: &[FoundPassword]) -> String {
+    let num_hidden = found_passwords
+        .len()
+        .saturating_sub(MAX_DISPLAYED_PASSWORDS);
+    let passwords: Vec<_> = found_passwords[num_hidden..]
+        .iter()
+        .map(|found| &found.password)
+        .collect();
+    if num_hidden == 0 {
+        format!("{:?}", passwords)
+    } else {
+        format!("{:?} (+{} more)", passwords, num_hidden)
+    }
+}
+
+/// Shortens the line to the terminal width. Lines that wrap would take more space than
+/// NUM_STATUS_LINES reserves and leave garbage on the screen.
+fn fit_to_width(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        return line.to_owned();
+    }
+    let mut shortened: String = line.chars().take(width.saturating_sub(1)).collect();
+    shortened.push('…');
+    shortened
+}
+
+fn thread_counts(data: &InfoData, num_threads: u8) -> Vec<u64> {
+    data.thread_counters[..usize::from(num_threads)]
+        .iter()
+        .map(|counter| counter.0.load(Ordering::Relaxed))
+        .collect()
+}
+
+/// The speed of each thread since the last sample, for --per-thread-stats
+fn format_thread_rates(counts: &[u64], last_counts: &[u64], elapsed: Duration) -> String {
+    let rates: Vec<String> = counts
+        .iter()
+        .zip(last_counts)
+        .map(|(&count, &last_count)| {
+            format!(
+                "{:.2}",
+                password_rate(count - last_count, elapsed) / 1_000_000.0
+            )
+        })
+        .collect();
+    format!(" (threads: {}M/s)", rates.join(" "))
+}
+
+/// The number of lines the status display uses below the current one
+const NUM_STATUS_LINES: u16 = 2;
+/// How much weight a new sample gets in the smoothed rate used by --min-rate
+const RATE_SMOOTHING: f64 = 0.1;
+/// How many consecutive samples must be below --min-rate before aborting
+const MIN_RATE_SAMPLES: u32 = 50;
+
+/// A progress bar for --progress-bar, if the keyspace is known and stdout is a terminal
+fn progress_bar(opt: &Opt) -> Option<ProgressBar> {
+    if !opt.progress_bar
+        || opt.stdin_passwords
+        || opt.markov_model.is_some()
+        || !should_use_terminal_control()
+    {
+        return None;
+    }
+    let keyspace = u64::try_from(keyspace_size(opt)).unwrap_or(u64::MAX);
+    let style = ProgressStyle::with_template(
+        "[{elapsed_precise}] {wide_bar} {percent}% {per_sec} ETA {eta}\n{msg}",
+    )
+    .unwrap();
+    Some(ProgressBar::new(keyspace).with_style(style))
+}
+
+/// Ends the status display when the info thread stops early
+fn end_status(progress_bar: &Option<ProgressBar>, stdout: &mut Stdout) {
+    match progress_bar {
+        Some(progress_bar) => progress_bar.abandon(),
+        None => restore_terminal(stdout),
+    }
+}
+
+pub fn spawn_info_thread(opt: Opt, data: Arc<InfoData>) -> thread::JoinHandle<()> {
+    #[cfg(feature = "tui")]
+    if opt.tui {
+        return crate::tui::spawn_tui_thread(opt, data);
+    }
+    // We might have a duration of more than a second between loops, so it's best to measure
+    // the elapsed time to calculate the number of passwords per second.
+    let start_time = Instant::now();
+    let mut log_timer = Instant::now();
+    let mut log_error: Option<anyhow::Error> = None;
+    let start_counter = data.counter.load(Ordering::Relaxed);
+    let mut last_counter = start_counter;
+    let mut last_sample_time = Instant::now();
+    let mut smoothed_rate: Option<f64> = None;
+    let mut num_slow_samples = 0;
+    let mut last_thread_counts = thread_counts(&data, opt.num_threads);
+    let mut stdout = stdout();
+    let progress_bar = progress_bar(&opt);
+    let color = opt.color.use_color(&stdout);
+    // Without terminal control, there are no status lines, only the logfile
+    let status_lines = progress_bar.is_none() && should_use_terminal_control();
+    thread::spawn(move || {
+        if status_lines {
+            handle_err!(execute!(
+                stdout,
+                ScrollUp(NUM_STATUS_LINES),
+                MoveUp(NUM_STATUS_LINES),
+                Hide
+            ));
+        }
+        loop {
+            if status_lines {
+                handle_err!(stdout.execute(SavePosition));
+            }
+            thread::sleep(Duration::from_millis(opt.refresh_interval));
+            let cur_counter = data.counter.load(Ordering::Relaxed);
+            if cur_counter == last_counter {
+                break;
+            }
+            let sample_elapsed = last_sample_time.elapsed();
+            let sample_rate = password_rate(cur_counter - last_counter, sample_elapsed);
+            last_counter = cur_counter;
+            last_sample_time = Instant::now();
+            let thread_rates = if opt.per_thread_stats {
+                let counts = thread_counts(&data, opt.num_threads);
+                let thread_rates =
+                    format_thread_rates(&counts, &last_thread_counts, sample_elapsed);
+                last_thread_counts = counts;
+                thread_rates
+            } else {
+                String::new()
+            };
+
+            let rate =
+                smoothed_rate.map_or(sample_rate, |r| r + RATE_SMOOTHING * (sample_rate - r));
+            smoothed_rate = Some(rate);
+            if let Some(min_rate) = opt.min_rate {
+                if rate < min_rate {
+                    num_slow_samples += 1;
+                } else {
+                    num_slow_samples = 0;
+                }
+                if num_slow_samples >= MIN_RATE_SAMPLES {
+                    end_status(&progress_bar, &mut stdout);
+                    eprintln!(
+                        "Aborting: speed dropped to {:.0} passwords/s, below --min-rate {}",
+                        rate, min_rate
+                    );
+                    // The caller reports the result once the workers have stopped
+                    data.aborted.store(true, Ordering::Relaxed);
+                    opt.cancel.cancel();
+                    break;
+                }
+            }
+
+            let found_passwords = data.found_passwords.lock().unwrap();
+            let recent_password = data.recent_password.lock().unwrap();
+            let recent_password_escaped = escape_control(&recent_password);
+            let passwords = format_found_passwords(&found_passwords);
+            let log_status = match &log_error {
+                Some(e) => format!(" (not logging: {})", e),
+                None => String::new(),
+            };
+            let found_color = (color && !found_passwords.is_empty()).then_some(Color::Green);
+            match &progress_bar {
+                Some(progress_bar) => {
+                    progress_bar.set_position(cur_counter - start_counter);
+                    let passwords = match found_color {
+                        Some(found_color) => passwords.with(found_color).to_string(),
+                        None => passwords,
+                    };
+                    progress_bar.set_message(format!(
+                        "Latest password: {}, found passwords: {}{}{}",
+                        recent_password_escaped, passwords, thread_rates, log_status
+                    ));
+                }
+                None if status_lines => {
+                    let lines = [
+                        format!(
+                            "Speed: {:>5.2}M passwords/s, total: {}M{}{}",
+                            password_rate(cur_counter - start_counter, start_time.elapsed())
+                                / 1_000_000.0,
+                            cur_counter / 1_000_000,
+                            thread_rates,
+                            log_status
+                        ),
+                        format!("Latest password: {}", recent_password_escaped),
+                        format!("Found passwords: {}", passwords),
+                    ];
+                    let line_colors = [None, None, found_color];
+                    let width = terminal::size().map_or(80, |(width, _)| usize::from(width));
+                    for (i, (line, line_color)) in lines.iter().zip(line_colors).enumerate() {
+                        if i > 0 {
+                            println!();
+                        }
+                        let line = fit_to_width(line, width);
+                        match line_color {
+                            Some(line_color) => print!("{}", line.with(line_color)),
+                            None => print!("{}", line),
+                        }
+                        handle_err!(stdout.execute(Clear(ClearType::UntilNewLine)));
+                    }
+                }
+                None => (),
+            }
+
+            // After an error, e.g. a full disk, stop logging but keep the display running
+            if log_error.is_none() && log_timer.elapsed() > Duration::from_secs(opt.log_interval) {
+                log_timer = Instant::now();
+                if let Err(e) = log_to_file(
+                    &opt.logfile,
+                    cur_counter,
+                    &found_passwords,
+                    &recent_password,
+                ) {
+                    log_error = Some(e);
+                }
+            }
+            if status_lines {
+                handle_err!(stdout.execute(RestorePosition));
+            }
+        }
+        if !data.aborted.load(Ordering::Relaxed) {
+            match &progress_bar {
+                Some(progress_bar) => progress_bar.finish(),
+                None => restore_terminal(&mut stdout),
+            }
+        }
+        if let Some(e) = log_error {
+            eprintln!("Error writing logfile, stopped logging: {}", e);
+        }
+        final_stats(data);
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_found_passwords() {
+        let found = |password: &str| FoundPassword {
+            password: password.to_owned(),
+            keys: [0; 3],
+        };
+        assert_eq!(format_found_passwords(&[]), "[]");
+        assert_eq!(
+            format_found_passwords(&[found("a"), found("b")]),
+            r#"["a", "b"]"#
+        );
+        let many: Vec<_> = ["a", "b", "c", "d", "e", "f", "g"]
+            .into_iter()
+            .map(found)
+            .collect();
+        assert_eq!(
+            format_found_passwords(&many),
+            r#"["c", "d", "e", "f", "g"] (+2 more)"#
+        );
+    }
+
+    #[test]
+    fn test_fit_to_width() {
+        assert_eq!(
+            fit_to_width("Found passwords: []", 80),
+            "Found passwords: []"
+        );
+        assert_eq!(fit_to_width("Found passwords: [\"a\"]", 10), "Found pas…");
+        assert_eq!(fit_to_width("äöü", 3), "äöü");
+    }
+
+    #[test]
+    fn test_format_thread_rates() {
+        assert_eq!(
+            format_thread_rates(
+                &[3_000_000, 2_500_000],
+                &[1_000_000, 1_500_000],
+                Duration::from_secs(2)
+            ),
+            " (threads: 1.00 0.50M/s)"
+        );
+    }
+
+    #[test]
+    fn test_min_rate_cancels() {
+        let mut opt = crate::password_iter::test::test_opt();
+        opt.min_rate = Some(1e12);
+        opt.refresh_interval = 1;
+        let data = InfoData::new();
+        let info_thread = spawn_info_thread(opt.clone(), data.clone());
+        // A search that is slower than --min-rate, but keeps counting until it's cancelled
+        while !opt.cancel.is_cancelled() && !info_thread.is_finished() {
+            data.counter.fetch_add(1, Ordering::Relaxed);
+            std::hint::spin_loop();
+        }
+        info_thread.join().unwrap();
+        assert!(data.aborted.load(Ordering::Relaxed));
+        assert!(opt.cancel.is_cancelled());
+    }
+}
@@ -0,0 +1,498 @@
+//! Splits the keyspace across machines. The coordinator (--serve) hands out ranges of password
+//! indices (see password_iter::index_to_password()) to workers over TCP and collects the found
+//! passwords. Messages are JSON objects, one per line:
+//!
+//! - Worker: `{"type": "request_range"}`, answered with `{"type": "range", "start": "<index>",
+//!   "end": "<index>", ...}` (plus the search options), `{"type": "wait"}` if all remaining ranges
+//!   are being worked on, or `{"type": "done"}`
+//! - Worker: `{"type": "report_result", "found": [<passwords>]}` when its range is done
+//! - Worker: `{"type": "heartbeat"}` every HEARTBEAT_INTERVAL, so the coordinator notices within
+//!   HEARTBEAT_TIMEOUT if it's gone
+//!
+//! Indices are strings since they can exceed the range of JSON numbers.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde_json as json;
+
+use crate::crack::{crack_range, password_predicate};
+use crate::decrypt::keys_for_password;
+use crate::info::{password_rate, FoundPassword, InfoData};
+use crate::opt::{Alphabet, Opt};
+use crate::password_iter::{keyspace_size, start_index, InitializedKeys, Password};
+use crate::zipfile::ZipArchive;
+
+/// How long the coordinator waits for a message from a worker before reassigning its range
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a worker sends a heartbeat
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a worker waits before asking again if all remaining ranges are being worked on
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The ranges of password indices that are not done yet
+pub struct RangeQueue {
+    next_start: u128,
+    end: u128,
+    chunk_size: u128,
+    /// Ranges of workers that dropped out
+    abandoned: Vec<Range<u128>>,
+    in_progress: usize,
+}
+
+impl RangeQueue {
+    pub fn new(indices: Range<u128>, chunk_size: u128) -> Self {
+        Self {
+            next_start: indices.start,
+            end: indices.end,
+            chunk_size: chunk_size.max(1),
+            abandoned: Vec::new(),
+            in_progress: 0,
+        }
+    }
+
+    /// The next range to hand out, if all ranges aren't handed out yet
+    pub fn next_range(&mut self) -> Option<Range<u128>> {
+        let range = match self.abandoned.pop() {
+            Some(range) => range,
+            None if self.next_start < self.end => {
+                let start = self.next_start;
+                self.next_start = start.saturating_add(self.chunk_size).min(self.end);
+                start..self.next_start
+            }
+            None => return None,
+        };
+        self.in_progress += 1;
+        Some(range)
+    }
+
+    /// Marks a range from next_range() as done
+    pub fn complete(&mut self) {
+        self.in_progress -= 1;
+    }
+
+    /// Returns a range from next_range() to be handed out again
+    pub fn abandon(&mut self, range: Range<u128>) {
+        self.in_progress -= 1;
+        self.abandoned.push(range);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next_start >= self.end && self.abandoned.is_empty() && self.in_progress == 0
+    }
+}
+
+type PasswordPredicate = Box<dyn FnMut(&Password, InitializedKeys) -> bool + Send>;
+
+struct Coordinator {
+    queue: RangeQueue,
+    /// The options that workers need to enumerate the same passwords
+    search: json::Value,
+    info_data: Arc<InfoData>,
+    /// Checks the passwords that workers report, which could be wrong if a worker is broken
+    password_predicate: PasswordPredicate,
+}
+
+impl Coordinator {
+    fn new(opt: &Opt, zipfile: &ZipArchive, info_data: Arc<InfoData>) -> Self {
+        let keyspace = keyspace_size(opt);
+        let start = start_index(opt);
+        Self {
+            queue: RangeQueue::new(start..start.saturating_add(keyspace), opt.chunk_size),
+            search: json::json!({
+                "alphabet": String::from_utf8_lossy(&opt.alphabet.0),
+                "min_length": opt.min_length,
+                "max_length": opt.max_length,
+                "prefix": opt.prefix,
+                "suffix": opt.suffix,
+            }),
+            info_data,
+            password_predicate: Box::new(password_predicate(opt, zipfile)),
+        }
+    }
+}
+
+/// Runs the coordinator until all ranges are done, and returns the passwords that the workers
+/// found
+pub fn serve(
+    opt: Opt,
+    addr: &str,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> anyhow::Result<Vec<FoundPassword>> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Could not listen on {}", addr))?;
+    serve_on(opt, listener, zipfile, info_data)
+}
+
+/// Like serve(), on a socket that is already bound
+fn serve_on(
+    opt: Opt,
+    listener: TcpListener,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> anyhow::Result<Vec<FoundPassword>> {
+    let keyspace = keyspace_size(&opt);
+    println!(
+        "Serving {:.1e} passwords in ranges of {:.1e} on {}",
+        keyspace as f64,
+        opt.chunk_size as f64,
+        listener.local_addr()?
+    );
+    let coordinator = Arc::new((
+        Mutex::new(Coordinator::new(&opt, zipfile, info_data.clone())),
+        Condvar::new(),
+    ));
+
+    let coordinator_for_listener = coordinator.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let coordinator = coordinator_for_listener.clone();
+            match stream {
+                Ok(stream) => {
+                    std::thread::spawn(move || handle_worker(stream, &coordinator));
+                }
+                Err(e) => eprintln!("Error accepting a worker: {}", e),
+            }
+        }
+    });
+
+    let (lock, done) = &*coordinator;
+    let mut state = lock.lock().unwrap();
+    while !state.queue.is_done() && !opt.cancel.is_cancelled() {
+        state = done.wait_timeout(state, Duration::from_secs(1)).unwrap().0;
+    }
+    let found_passwords = info_data.found_passwords.lock().unwrap();
+    Ok(found_passwords.clone())
+}
+
+/// Talks to one worker until it disconnects. If it was working on a range, the range is handed
+/// out again.
+fn handle_worker(stream: TcpStream, coordinator: &(Mutex<Coordinator>, Condvar)) {
+    let peer = stream
+        .peer_addr()
+        .map_or_else(|_| String::from("?"), |addr| addr.to_string());
+    println!("Worker {} connected", peer);
+    let mut assigned = None;
+    if let Err(e) = talk_to_worker(stream, coordinator, &peer, &mut assigned) {
+        eprintln!("Worker {}: {}", peer, e);
+    }
+    let (lock, done) = coordinator;
+    if let Some(range) = assigned {
+        println!(
+            "Worker {} dropped out, handing out its range {}..{} again",
+            peer, range.start, range.end
+        );
+        lock.lock().unwrap().queue.abandon(range);
+        done.notify_all();
+    }
+}
+
+fn talk_to_worker(
+    stream: TcpStream,
+    coordinator: &(Mutex<Coordinator>, Condvar),
+    peer: &str,
+    assigned: &mut Option<Range<u128>>,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let (lock, done) = coordinator;
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("No heartbeat")?;
+        let message: json::Value =
+            json::from_str(&line).with_context(|| format!("Invalid message {:?}", line))?;
+        match message["type"].as_str() {
+            Some("request_range") => {
+                if assigned.is_some() {
+                    bail!("Requested a range before finishing the last one");
+                }
+                let mut state = lock.lock().unwrap();
+                let reply = match state.queue.next_range() {
+                    Some(range) => {
+                        let mut reply = state.search.clone();
+                        reply["type"] = "range".into();
+                        reply["start"] = range.start.to_string().into();
+                        reply["end"] = range.end.to_string().into();
+                        *assigned = Some(range);
+                        reply
+                    }
+                    None if state.queue.is_done() => json::json!({ "type": "done" }),
+                    None => json::json!({ "type": "wait" }),
+                };
+                drop(state);
+                writeln!(writer, "{}", reply)?;
+            }
+            Some("report_result") => {
+                let Some(range) = assigned.take() else {
+                    bail!("Reported a result without having a range");
+                };
+                let found: Vec<String> = json::from_value(message["found"].clone())
+                    .context("Invalid list of found passwords")?;
+                let mut state = lock.lock().unwrap();
+                let len = u64::try_from(range.end - range.start).unwrap_or(u64::MAX);
+                state.info_data.counter.fetch_add(len, Ordering::Relaxed);
+                for password in found {
+                    let keys = keys_for_password(password.as_bytes());
+                    if !(state.password_predicate)(password.as_bytes(), keys) {
+                        eprintln!(
+                            "Worker {} reported password {:?}, which doesn't match the archive",
+                            peer, password
+                        );
+                        continue;
+                    }
+                    println!("Worker {} found password {:?}", peer, password);
+                    let mut found_passwords = state.info_data.found_passwords.lock().unwrap();
+                    found_passwords.push(FoundPassword { password, keys });
+                }
+                state.queue.complete();
+                done.notify_all();
+            }
+            Some("heartbeat") => {}
+            _ => bail!("Unknown message {:?}", line),
+        }
+    }
+    Ok(())
+}
+
+/// Sends one message
+fn send(writer: &Mutex<TcpStream>, message: json::Value) -> std::io::Result<()> {
+    writeln!(writer.lock().unwrap(), "{}", message)
+}
+
+/// The range in a "range" message, and the options with the coordinator's search options
+fn parse_range(opt: &Opt, message: &json::Value) -> anyhow::Result<(Range<u128>, Opt)> {
+    let index = |key: &str| -> anyhow::Result<u128> {
+        message[key]
+            .as_str()
+            .and_then(|index| index.parse().ok())
+            .with_context(|| format!("Invalid range {}", message))
+    };
+    let length = |key: &str| -> anyhow::Result<u8> {
+        message[key]
+            .as_u64()
+            .and_then(|length| u8::try_from(length).ok())
+            .with_context(|| format!("Invalid {} in {}", key, message))
+    };
+    // Null or a string
+    let fixed_part = |key: &str| -> anyhow::Result<Option<String>> {
+        match &message[key] {
+            json::Value::Null => Ok(None),
+            json::Value::String(part) => Ok(Some(part.clone())),
+            _ => bail!("Invalid {} in {}", key, message),
+        }
+    };
+    let mut opt = opt.clone();
+    // The passwords are built from the bytes of the alphabet, like with the alphabets of the
+    // command line
+    let alphabet = message["alphabet"]
+        .as_str()
+        .filter(|alphabet| !alphabet.is_empty() && alphabet.is_ascii())
+        .map(|alphabet| alphabet.as_bytes().to_vec())
+        .filter(|alphabet| {
+            let mut sorted = alphabet.clone();
+            sorted.sort_unstable();
+            sorted.dedup();
+            sorted.len() == alphabet.len()
+        })
+        .with_context(|| format!("Invalid alphabet in {}", message))?;
+    opt.alphabet = Alphabet(alphabet);
+    opt.min_length = length("min_length")?;
+    opt.max_length = length("max_length")?;
+    if opt.min_length > opt.max_length {
+        bail!("Invalid lengths in {}", message);
+    }
+    opt.prefix = fixed_part("prefix")?;
+    opt.suffix = fixed_part("suffix")?;
+    let range = index("start")?..index("end")?;
+    if range.start > range.end {
+        bail!("Invalid range {}", message);
+    }
+    Ok((range, opt))
+}
+
+/// Works on ranges from the coordinator until there are none left, and returns the passwords
+/// that this worker found
+pub fn connect(
+    opt: Opt,
+    addr: &str,
+    zipfile: &ZipArchive,
+    info_data: Arc<InfoData>,
+) -> anyhow::Result<Vec<FoundPassword>> {
+    let stream =
+        TcpStream::connect(addr).with_context(|| format!("Could not connect to {}", addr))?;
+    println!("Connected to {}", addr);
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = BufReader::new(stream);
+
+    // Stops when the connection is shut down below
+    let heartbeat_writer = writer.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(HEARTBEAT_INTERVAL);
+        if send(&heartbeat_writer, json::json!({ "type": "heartbeat" })).is_err() {
+            break;
+        }
+    });
+
+    while !opt.cancel.is_cancelled() {
+        // The coordinator exits once all ranges are done
+        let mut line = String::new();
+        let request = send(&writer, json::json!({ "type": "request_range" }));
+        if request.is_err() || reader.read_line(&mut line)? == 0 {
+            println!("The coordinator closed the connection");
+            break;
+        }
+        let message: json::Value =
+            json::from_str(&line).with_context(|| format!("Invalid message {:?}", line))?;
+        match message["type"].as_str() {
+            Some("range") => {
+                let (range, range_opt) = parse_range(&opt, &message)?;
+                let start_time = Instant::now();
+                let counter_before = info_data.counter.load(Ordering::Relaxed);
+                let found_before = info_data.found_passwords.lock().unwrap().len();
+                crack_range(range_opt, zipfile, info_data.clone(), range.clone());
+                if opt.cancel.is_cancelled() {
+                    // The range is unfinished, the coordinator hands it out again
+                    break;
+                }
+                let counter = info_data.counter.load(Ordering::Relaxed) - counter_before;
+                println!(
+                    "Searched range {}..{} at {:.2}M passwords/s",
+                    range.start,
+                    range.end,
+                    password_rate(counter, start_time.elapsed()) / 1_000_000.0
+                );
+                let found: Vec<String> = info_data.found_passwords.lock().unwrap()[found_before..]
+                    .iter()
+                    .map(|found| found.password.clone())
+                    .collect();
+                send(
+                    &writer,
+                    json::json!({ "type": "report_result", "found": found }),
+                )?;
+            }
+            Some("wait") => std::thread::sleep(WAIT_INTERVAL),
+            Some("done") => break,
+            _ => bail!("Unexpected message {:?}", line),
+        }
+    }
+    // Fails if the coordinator closed the connection already
+    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    let found_passwords = info_data.found_passwords.lock().unwrap();
+    Ok(found_passwords.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_range_queue() {
+        let mut queue = RangeQueue::new(0..25, 10);
+        assert_eq!(queue.next_range(), Some(0..10));
+        assert_eq!(queue.next_range(), Some(10..20));
+        assert_eq!(queue.next_range(), Some(20..25));
+        assert_eq!(queue.next_range(), None);
+        assert!(!queue.is_done());
+        queue.complete();
+        queue.abandon(10..20);
+        assert_eq!(queue.next_range(), Some(10..20));
+        queue.complete();
+        assert!(!queue.is_done());
+        queue.complete();
+        assert!(queue.is_done());
+        assert_eq!(queue.next_range(), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let opt = crate::password_iter::test::test_opt();
+        let message = json::json!({
+            "type": "range",
+            "start": "0",
+            "end": "10",
+            "alphabet": "abc",
+            "min_length": 1,
+            "max_length": 3,
+            "prefix": "x",
+            "suffix": null,
+        });
+        let (range, range_opt) = parse_range(&opt, &message).unwrap();
+        assert_eq!(range, 0..10);
+        assert_eq!(range_opt.alphabet.0, b"abc");
+        assert_eq!(range_opt.prefix.as_deref(), Some("x"));
+        assert_eq!(range_opt.suffix, None);
+        for (key, value) in [
+            ("alphabet", json::json!("\u{e9}")),
+            ("alphabet", json::json!("aba")),
+            ("min_length", json::json!(4)),
+            ("prefix", json::json!(5)),
+            ("end", json::json!("x")),
+        ] {
+            let mut invalid = message.clone();
+            invalid[key] = value;
+            assert!(parse_range(&opt, &invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_serve_and_connect() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let mut opt: Opt = crate::opt::CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(3)
+            .logfile("")
+            .into();
+        opt.chunk_size = 10;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let coordinator_data = InfoData::new();
+        let worker_data = InfoData::new();
+        let (served, connected) = std::thread::scope(|s| {
+            let serve =
+                s.spawn(|| serve_on(opt.clone(), listener, &archive, coordinator_data.clone()));
+            let connected = connect(opt.clone(), &addr, &archive, worker_data.clone()).unwrap();
+            (serve.join().unwrap().unwrap(), connected)
+        });
+        for found in [served, connected] {
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].password, "abc");
+        }
+        // All 3 + 9 + 27 passwords in 4 ranges were reported back
+        assert_eq!(coordinator_data.counter.load(Ordering::Relaxed), 39);
+    }
+
+    #[test]
+    fn test_reported_passwords_are_checked() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let opt: Opt = crate::opt::CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(3)
+            .logfile("")
+            .into();
+        let info_data = InfoData::new();
+        let coordinator = (
+            Mutex::new(Coordinator::new(&opt, &archive, info_data.clone())),
+            Condvar::new(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut worker = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| handle_worker(stream, &coordinator));
+            writeln!(worker, "{}", json::json!({ "type": "request_range" })).unwrap();
+            let mut reply = String::new();
+            BufReader::new(&worker).read_line(&mut reply).unwrap();
+            assert!(reply.contains("\"range\""), "{}", reply);
+            // A broken worker reports a password that doesn't decrypt the archive
+            let found = json::json!({ "type": "report_result", "found": ["abc", "cab"] });
+            writeln!(worker, "{}", found).unwrap();
+            worker.shutdown(Shutdown::Both).unwrap();
+        });
+        let found_passwords = info_data.found_passwords.lock().unwrap();
+        assert_eq!(found_passwords.len(), 1);
+        assert_eq!(found_passwords[0].password, "abc");
+    }
+}
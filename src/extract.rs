@@ -0,0 +1,235 @@
+use std::io::{ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::DeflateDecoder;
+
+use crate::crypto::{crc32, crc32_checksum};
+use crate::decompress::{decompress, CompressionMethod};
+use crate::decrypt::{decrypt_with_keys, DecryptingReader};
+use crate::zipfile::{LocalFile, Record};
+
+/// Turns the stored file data into the original file contents, decrypting it if necessary
+fn file_contents(local_file: &LocalFile, keys: Option<[u32; 3]>) -> Result<Vec<u8>> {
+    let header = &local_file.local_file_header;
+    let data = match (local_file.encryption_header, keys) {
+        (Some(encryption_header), Some(keys)) => {
+            decrypt_with_keys(keys, encryption_header.bytes, &local_file.file_data)
+        }
+        (Some(_), None) => bail!("Cannot extract {:?}: no password", header.file_name),
+        (None, _) => local_file.file_data.clone(),
+    };
+    decompress(
+        header.compression_method.into(),
+        &data,
+        local_file.uncompressed_size().map(|size| size as usize),
+    )
+    .with_context(|| format!("Cannot extract {:?}", header.file_name))
+}
+
+/// How much verify_candidate() decompresses at a time
+const VERIFY_CHUNK_SIZE: usize = 4096;
+
+/// Whether the keys decrypt the file to contents with the CRC-32 from the archive. Unlike the
+/// check of the encryption header, this practically never passes with wrong keys.
+///
+/// Stored and Deflate data is decrypted and decompressed in chunks, and the check stops at the
+/// first invalid Deflate block or once there are more bytes than the file should have. Wrong keys
+/// almost always fail in the first chunk, so they are cheap to reject even for large files.
+pub fn verify_candidate(local_file: &LocalFile, keys: [u32; 3]) -> bool {
+    let (Some(expected_crc), Some(encryption_header)) =
+        (local_file.crc32(), local_file.encryption_header)
+    else {
+        return false;
+    };
+    let expected_size = local_file.uncompressed_size();
+    let reader = DecryptingReader::new(keys, encryption_header.bytes, &local_file.file_data);
+    match local_file.local_file_header.compression_method.into() {
+        CompressionMethod::Stored => stream_matches(reader, expected_size, expected_crc),
+        CompressionMethod::Deflate => {
+            stream_matches(DeflateDecoder::new(reader), expected_size, expected_crc)
+        }
+        _ => file_contents(local_file, Some(keys))
+            .is_ok_and(|contents| crc32_checksum(&contents) == expected_crc),
+    }
+}
+
+/// Whether the reader produces contents of the size and with the CRC-32, see verify_candidate()
+fn stream_matches(mut reader: impl Read, expected_size: Option<u32>, expected_crc: u32) -> bool {
+    let mut chunk = [0; VERIFY_CHUNK_SIZE];
+    let mut crc = !0;
+    let mut size: u64 = 0;
+    loop {
+        let len = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        };
+        size += len as u64;
+        if expected_size.is_some_and(|expected_size| size > u64::from(expected_size)) {
+            return false;
+        }
+        crc = chunk[..len].iter().fold(crc, |crc, &byte| crc32(crc, byte));
+    }
+    !crc == expected_crc
+}
+
+/// Builds the output path, refusing names that would escape the output directory
+fn output_path(dir: &Path, local_file: &LocalFile) -> Result<PathBuf> {
+    let file_name = Path::new(&local_file.local_file_header.file_name);
+    let mut path = dir.to_path_buf();
+    for component in file_name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            _ => bail!("Refusing to extract {:?}: unsafe path", file_name),
+        }
+    }
+    Ok(path)
+}
+
+fn extract_file(local_file: &LocalFile, dir: &Path, keys: Option<[u32; 3]>) -> Result<()> {
+    let path = output_path(dir, local_file)?;
+    // Directories are stored as empty entries whose name ends with a slash
+    if local_file
+        .local_file_header
+        .file_name
+        .as_encoded_bytes()
+        .ends_with(b"/")
+    {
+        std::fs::create_dir_all(&path)?;
+        return Ok(());
+    }
+    let data = file_contents(local_file, keys)?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid output path {:?}", path))?;
+    std::fs::create_dir_all(parent)?;
+    std::fs::write(&path, data)?;
+    println!("Extracted {:?}", path);
+    Ok(())
+}
+
+/// Writes every file that is not encrypted into the given directory
+pub fn extract_clear(records: &[Record], dir: &Path) -> Result<()> {
+    for local_file in records.iter().filter_map(Record::get_local_file) {
+        if !local_file.local_file_header.is_encrypted() {
+            extract_file(local_file, dir, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes every file into the given directory, decrypting them with the recovered keys. Skips
+/// the encrypted files without an encryption header (see validate::check_encryption_headers()).
+pub fn extract_all(records: &[Record], dir: &Path, keys: [u32; 3]) -> Result<()> {
+    for local_file in records.iter().filter_map(Record::get_local_file) {
+        if local_file.local_file_header.is_encrypted() && local_file.encryption_header.is_none() {
+            continue;
+        }
+        extract_file(local_file, dir, Some(keys))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::decrypt::{decrypt_byte, keys_for_password, update_keys};
+    use crate::zipfile::parse_local_file;
+    use crate::zipfile::test::LocalFileBytes;
+
+    /// Encrypts the data like ZipCrypto, i.e. with the keys updated with the plaintext
+    fn encrypt(keys: &mut [u32; 3], plaintext: &[u8]) -> Vec<u8> {
+        plaintext
+            .iter()
+            .map(|&p| {
+                let c = p ^ decrypt_byte(keys[2]);
+                update_keys(keys, p);
+                c
+            })
+            .collect()
+    }
+
+    /// An encrypted local file with a data descriptor and the given compressed data
+    fn encrypted_file(
+        password_keys: [u32; 3],
+        compression_method: u16,
+        compressed: &[u8],
+        text: &[u8],
+    ) -> LocalFile {
+        let mut keys = password_keys;
+        let last_mod_file_time: u16 = 0x1234;
+        let mut encryption_header = vec![7; 10];
+        encryption_header.extend(last_mod_file_time.to_le_bytes());
+        let mut ciphertext = encrypt(&mut keys, &encryption_header);
+        ciphertext.extend(encrypt(&mut keys, compressed));
+
+        let input = LocalFileBytes {
+            flags: 9,
+            compression_method,
+            last_mod_file_time,
+            crc32: crc32_checksum(text),
+            uncompressed_size: Some(text.len() as u32),
+            data: &ciphertext,
+            ..Default::default()
+        }
+        .to_bytes();
+        let (_, local_file) = parse_local_file(&input).unwrap();
+        local_file
+    }
+
+    #[test]
+    fn test_verify_candidate() {
+        let text = b"To be, or not to be, that is the question.".repeat(1000);
+        let mut compressed = Vec::new();
+        flate2::read::DeflateEncoder::new(text.as_slice(), flate2::Compression::best())
+            .read_to_end(&mut compressed)
+            .unwrap();
+        let keys = keys_for_password(b"secret");
+        for (compression_method, data) in [(0, &text), (8, &compressed)] {
+            let local_file = encrypted_file(keys, compression_method, data, &text);
+            assert!(verify_candidate(&local_file, keys));
+            assert!(!verify_candidate(&local_file, keys_for_password(b"secreT")));
+        }
+        // Decompresses correctly, but to other contents
+        let mut local_file = encrypted_file(keys, 8, &compressed, &text);
+        local_file.data_descriptor.as_mut().unwrap().crc32 ^= 1;
+        assert!(!verify_candidate(&local_file, keys));
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn test_extract_encrypted_bzip2() {
+        let text = b"To be, or not to be, that is the question.".to_vec();
+        let mut compressed = Vec::new();
+        bzip2::read::BzEncoder::new(text.as_slice(), bzip2::Compression::best())
+            .read_to_end(&mut compressed)
+            .unwrap();
+        let keys = keys_for_password(b"secret");
+        let local_file = encrypted_file(keys, 12, &compressed, &text);
+        assert_eq!(file_contents(&local_file, Some(keys)).unwrap(), text);
+        assert!(file_contents(&local_file, None).is_err());
+        assert!(verify_candidate(&local_file, keys));
+    }
+
+    #[cfg(feature = "lzma")]
+    #[test]
+    fn test_extract_encrypted_lzma() {
+        let text = b"To be, or not to be, that is the question.".to_vec();
+        let mut lzma_file = Vec::new();
+        lzma_rs::lzma_compress(&mut text.as_slice(), &mut lzma_file).unwrap();
+        // The LZMA header of ZIP files instead of the one of .lzma files, see decompress_lzma()
+        let mut compressed = vec![16, 2, 5, 0];
+        compressed.extend(&lzma_file[..5]);
+        compressed.extend(&lzma_file[13..]);
+        let keys = keys_for_password(b"secret");
+        let mut local_file = encrypted_file(keys, 14, &compressed, &text);
+        // With a data descriptor, archivers write 0 for the size in the header
+        local_file.local_file_header.uncompressed_size = 0;
+        assert_eq!(file_contents(&local_file, Some(keys)).unwrap(), text);
+        assert!(verify_candidate(&local_file, keys));
+    }
+}
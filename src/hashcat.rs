@@ -0,0 +1,134 @@
+//! Exports files with WinZip's AES encryption as hashes for hashcat (mode 13600) and John the
+//! Ripper, for --hashcat. Brute-forcing AES takes a key derivation per password, so zipcrack
+//! leaves that to those tools and only cracks ZipCrypto itself.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::zipfile::{LocalFile, ZipArchive};
+
+/// The length of the password verification value after the salt
+const PASSWORD_VERIFICATION_LENGTH: usize = 2;
+/// The length of the HMAC-SHA1 authentication code after the encrypted data
+const AUTHENTICATION_CODE_LENGTH: usize = 10;
+
+/// The parts of the stored data of an AES-encrypted file
+#[derive(Debug, PartialEq, Eq)]
+pub struct AesFileData<'a> {
+    /// 1, 2 or 3 for 128-, 192- or 256-bit keys
+    pub strength: u8,
+    pub salt: &'a [u8],
+    pub password_verification_value: &'a [u8],
+    pub encrypted_data: &'a [u8],
+    pub authentication_code: &'a [u8],
+}
+
+impl<'a> AesFileData<'a> {
+    pub fn from_local_file(local_file: &'a LocalFile) -> Result<Self> {
+        let header = &local_file.local_file_header;
+        let aes = header
+            .aes
+            .ok_or_else(|| anyhow!("{:?} has no AES extra field", header.file_name))?;
+        let salt_length = aes.salt_length().ok_or_else(|| {
+            anyhow!(
+                "{:?} has an unknown AES strength {}",
+                header.file_name,
+                aes.strength
+            )
+        })?;
+        let data = &local_file.file_data;
+        if data.len() < salt_length + PASSWORD_VERIFICATION_LENGTH + AUTHENTICATION_CODE_LENGTH {
+            bail!(
+                "{:?} is too short for AES-encrypted data ({} bytes)",
+                header.file_name,
+                data.len()
+            );
+        }
+        let (salt, rest) = data.split_at(salt_length);
+        let (password_verification_value, rest) = rest.split_at(PASSWORD_VERIFICATION_LENGTH);
+        let (encrypted_data, authentication_code) =
+            rest.split_at(rest.len() - AUTHENTICATION_CODE_LENGTH);
+        Ok(Self {
+            strength: aes.strength,
+            salt,
+            password_verification_value,
+            encrypted_data,
+            authentication_code,
+        })
+    }
+
+    /// The hash in the "$zip2$" format of zip2john, which hashcat also reads
+    pub fn hash(&self) -> String {
+        format!(
+            "$zip2$*0*{}*0*{}*{}*{:x}*{}*{}*$/zip2$",
+            self.strength,
+            hex(self.salt),
+            hex(self.password_verification_value),
+            self.encrypted_data.len(),
+            hex(self.encrypted_data),
+            hex(self.authentication_code)
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The hashes of all AES-encrypted files in the archive, one per file
+pub fn hashes(archive: &ZipArchive) -> Result<Vec<String>> {
+    let hashes = archive
+        .encrypted_files()
+        .filter(|local_file| local_file.local_file_header.aes.is_some())
+        .map(|local_file| Ok(AesFileData::from_local_file(local_file)?.hash()))
+        .collect::<Result<Vec<_>>>()?;
+    if hashes.is_empty() {
+        bail!("The archive contains no AES-encrypted files");
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zipfile::test::LocalFileBytes;
+
+    /// A local file with the AES extra field for 128-bit keys and the given stored data
+    fn aes_file_bytes(file_name: &str, data: &[u8]) -> Vec<u8> {
+        LocalFileBytes {
+            version: 51,
+            flags: 1,
+            compression_method: 99,
+            uncompressed_size: Some(0),
+            file_name,
+            // AE-2, strength 1, stored
+            extra_field: &[0x01, 0x99, 7, 0, 2, 0, b'A', b'E', 1, 0, 0],
+            data,
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_aes_hash() {
+        let data: Vec<u8> = (0..25).collect();
+        let mut input = aes_file_bytes("a.txt", &data);
+        input.extend(aes_file_bytes("b.txt", &[0; 20]));
+        let archive = ZipArchive::parse(&input).unwrap();
+        let local_file = archive.file_by_name("a.txt").unwrap();
+        assert_eq!(local_file.file_data, data);
+        let aes_data = AesFileData::from_local_file(local_file).unwrap();
+        assert_eq!(aes_data.salt, &data[..8]);
+        assert_eq!(aes_data.password_verification_value, &data[8..10]);
+        assert_eq!(aes_data.encrypted_data, &data[10..15]);
+        assert_eq!(aes_data.authentication_code, &data[15..]);
+        assert_eq!(
+            aes_data.hash(),
+            "$zip2$*0*1*0*0001020304050607*0809*5*0a0b0c0d0e*0f101112131415161718*$/zip2$"
+        );
+        assert_eq!(hashes(&archive).unwrap().len(), 2);
+
+        let input = aes_file_bytes("c.txt", &[0; 19]);
+        let archive = ZipArchive::parse(&input).unwrap();
+        assert!(hashes(&archive).is_err());
+    }
+}
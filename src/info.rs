@@ -1,137 +1,471 @@
-use std::io::{stdout, Stdout};
-use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use crossterm::{
-    cursor::{Hide, MoveUp, RestorePosition, SavePosition, Show},
-    execute,
-    terminal::ScrollUp,
-    ExecutableCommand,
-};
 use serde_json as json;
 
-use crate::opt::Opt;
+use crate::opt::{ColorChoice, Opt, OutputFormat};
 
-macro_rules! handle_err {
-    ($result:expr) => {
-        if let Err(_) = $result {
-            println!("Could not control terminal, no output will be provided.");
-            return;
-        }
-    };
+#[derive(Clone, Debug)]
+pub struct FoundPassword {
+    pub password: String,
+    /// The keys after initialization with the password, these are what decrypts the files
+    pub keys: [u32; 3],
+}
+
+/// Stops a running search when cancelled. The search returns the passwords found so far.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
+/// A counter on its own cache line, so that the threads counting don't slow each other down
+#[derive(Debug, Default)]
+#[repr(align(64))]
+pub struct ThreadCounter(pub AtomicU64);
+
 #[derive(Debug)]
 pub struct InfoData {
     pub counter: AtomicU64,
-    pub found_passwords: Mutex<Vec<String>>,
+    /// The number of passwords each thread tried, only counted with --per-thread-stats
+    pub thread_counters: Vec<ThreadCounter>,
+    pub found_passwords: Mutex<Vec<FoundPassword>>,
     pub recent_password: Mutex<String>,
+    pub start_time: Instant,
+    /// Set when the status display stopped the search because of --min-rate
+    pub aborted: AtomicBool,
 }
 
 impl InfoData {
     pub fn new() -> Arc<Self> {
         Arc::new(Self {
             counter: AtomicU64::new(0),
+            thread_counters: (0..=u8::MAX).map(|_| ThreadCounter::default()).collect(),
             found_passwords: Mutex::new(vec![]),
             recent_password: Mutex::new(String::from("-")),
+            start_time: Instant::now(),
+            aborted: AtomicBool::new(false),
         })
     }
 }
 
-pub fn restore_terminal(stdout: &mut Stdout) {
-    handle_err!(execute!(stdout, Show, ScrollUp(NUM_STATUS_LINES)));
+/// Prints a warning to stderr, in yellow if colors are enabled
+pub fn warn(color: ColorChoice, message: impl Display) {
+    let message = format!("Warning: {}", message);
+    #[cfg(feature = "cli")]
+    if color.use_color(&std::io::stderr()) {
+        use crossterm::style::Stylize;
+        eprintln!("{}", message.yellow());
+        return;
+    }
+    #[cfg(not(feature = "cli"))]
+    let _ = color;
+    eprintln!("{}", message);
 }
 
-fn final_stats(data: Arc<InfoData>) {
-    println!(
-        "Total passwords tried: {}",
-        data.counter.load(Ordering::Relaxed)
-    );
+/// The exit code of the command line tool after an error
+pub const EXIT_ERROR: u8 = 2;
+
+/// Why the run ended, for the --json-summary and the exit code
+#[derive(Clone, Copy, Debug)]
+pub enum Termination {
+    /// The whole keyspace was searched without finding a password
+    Completed,
+    /// The whole keyspace was searched and at least one password was found
+    Found,
+    /// The user pressed Ctrl-C
+    Interrupted,
+    /// The speed dropped below --min-rate
+    Aborted,
 }
 
-fn log(
-    filename: &Path,
+impl Termination {
+    fn as_str(self) -> &'static str {
+        match self {
+            Termination::Completed => "completed",
+            Termination::Found => "found",
+            Termination::Interrupted => "interrupted",
+            Termination::Aborted => "aborted",
+        }
+    }
+
+    /// The exit code of the command line tool, for scripts
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Termination::Found => 0,
+            Termination::Completed => 1,
+            // Not 1, since the keyspace wasn't searched to the end
+            Termination::Aborted => 3,
+            // Like a shell reports a process killed by SIGINT
+            Termination::Interrupted => 130,
+        }
+    }
+}
+
+/// Prints a machine-readable summary of the whole run to stderr
+pub fn print_json_summary(data: &InfoData, termination: Termination) {
+    let counter = data.counter.load(Ordering::Relaxed);
+    let elapsed = data.start_time.elapsed();
+    let found_passwords = data.found_passwords.lock().unwrap();
+    let passwords: Vec<_> = found_passwords
+        .iter()
+        .map(|found| &found.password)
+        .collect();
+    let value = json::json!({
+        "passwords_tried": counter,
+        "elapsed_seconds": elapsed.as_secs_f64(),
+        "average_rate": password_rate(counter, elapsed),
+        "found_passwords": passwords,
+        "termination": termination.as_str(),
+    });
+    eprintln!("{}", value);
+}
+
+/// Writes control characters as \xNN, so that passwords from alphabets with control bytes can't
+/// mess up the terminal or line-based output. Backslashes are doubled then, to keep the escaped
+/// form unambiguous. Text without control characters is returned unchanged.
+pub fn escape_control(text: &str) -> Cow<'_, str> {
+    if !text.contains(|c: char| c.is_ascii_control()) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() * 2);
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Quotes a CSV field if needed
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes the found passwords for --output or stdout, see --format. matched_files is the number
+/// of files that a password passes the check for.
+pub fn write_found_passwords(
+    mut writer: impl Write,
+    format: OutputFormat,
+    found_passwords: &[FoundPassword],
+    matched_files: impl Fn(&FoundPassword) -> usize,
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Plain => {
+            for found in found_passwords {
+                writeln!(writer, "{}", escape_control(&found.password))?;
+            }
+        }
+        OutputFormat::Json => {
+            let passwords: Vec<_> = found_passwords
+                .iter()
+                .map(|found| {
+                    json::json!({
+                        "password": found.password,
+                        "matched_files": matched_files(found),
+                    })
+                })
+                .collect();
+            writeln!(writer, "{}", json::Value::from(passwords))?;
+        }
+        OutputFormat::Csv => {
+            writeln!(writer, "password,length,matched_files")?;
+            for found in found_passwords {
+                writeln!(
+                    writer,
+                    "{},{},{}",
+                    csv_field(&escape_control(&found.password)),
+                    found.password.len(),
+                    matched_files(found)
+                )?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+pub(crate) fn log(
+    writer: impl Write,
     counter: u64,
-    found_passwords: &[String],
+    found_passwords: &[FoundPassword],
     recent_password: &str,
 ) -> Result<()> {
-    let file = std::fs::File::create(filename)?;
-    let writer = std::io::BufWriter::new(file);
+    let mut writer = std::io::BufWriter::new(writer);
+    let passwords: Vec<_> = found_passwords
+        .iter()
+        .map(|found| &found.password)
+        .collect();
+    let keys: Vec<_> = found_passwords.iter().map(|found| found.keys).collect();
     let value = json::json!({
         "counter": counter,
-        "found_passwords": found_passwords,
+        "found_passwords": passwords,
+        "found_keys": keys,
         "recent_password": recent_password
     });
-    json::to_writer_pretty(writer, &value)?;
+    json::to_writer_pretty(&mut writer, &value)?;
+    // Dropping the BufWriter would ignore errors
+    writer.flush()?;
     Ok(())
 }
 
-const NUM_STATUS_LINES: u16 = 2;
-pub fn spawn_info_thread(opt: Opt, data: Arc<InfoData>) -> thread::JoinHandle<()> {
-    // We might have a duration of more than a second between loops, so it's best to measure
-    // the elapsed time to calculate the number of passwords per second.
-    let start_time = Instant::now();
-    let mut log_timer = Instant::now();
-    let mut last_counter = data.counter.load(Ordering::Relaxed);
-    let mut stdout = stdout();
-    thread::spawn(move || {
-        handle_err!(execute!(
-            stdout,
-            ScrollUp(NUM_STATUS_LINES),
-            MoveUp(NUM_STATUS_LINES),
-            Hide
-        ));
-        loop {
-            handle_err!(stdout.execute(SavePosition));
-            thread::sleep(Duration::from_millis(100));
-            let time_elapsed = start_time.elapsed().as_secs_f64();
-            let cur_counter = data.counter.load(Ordering::Relaxed);
-            if cur_counter == last_counter {
-                break;
-            }
-            last_counter = cur_counter;
-
-            let password_rate = (cur_counter / 1_000_000) as f64 / time_elapsed;
-            let found_passwords = data.found_passwords.lock().unwrap();
-            let recent_password = data.recent_password.lock().unwrap();
-            println!(
-                "Speed: {:>5.2}M passwords/s, total: {}M",
-                password_rate,
-                cur_counter / 1_000_000
+pub(crate) fn log_to_file(
+    filename: &Path,
+    counter: u64,
+    found_passwords: &[FoundPassword],
+    recent_password: &str,
+) -> Result<()> {
+    let file = std::fs::File::create(filename)?;
+    log(file, counter, found_passwords, recent_password)
+}
+
+/// Writes the logfile whenever the counter reaches a multiple of --checkpoint-every. Each thread
+/// has its own copy, which remembers the next multiple it knows of, so that most passwords only
+/// cost a comparison.
+#[derive(Clone)]
+pub struct Checkpoints {
+    every: u64,
+    next: u64,
+    logfile: PathBuf,
+    color: ColorChoice,
+}
+
+impl Checkpoints {
+    pub fn new(opt: &Opt) -> Option<Self> {
+        let every = opt.checkpoint_every.filter(|&every| every > 0)?;
+        Some(Self {
+            every,
+            next: every,
+            logfile: opt.logfile.clone(),
+            color: opt.color,
+        })
+    }
+
+    /// Call after adding to the counter, with its value before. Only the thread whose addition
+    /// reached the multiple writes the checkpoint.
+    pub fn update(&mut self, data: &InfoData, previous: u64, added: u64) {
+        let current = previous.saturating_add(added);
+        if current < self.next {
+            return;
+        }
+        let checkpoint = current / self.every * self.every;
+        self.next = checkpoint.saturating_add(self.every);
+        if previous >= checkpoint {
+            return;
+        }
+        let found_passwords = data.found_passwords.lock().unwrap();
+        let recent_password = data.recent_password.lock().unwrap().clone();
+        if let Err(e) = log_to_file(
+            &self.logfile,
+            checkpoint,
+            &found_passwords,
+            &recent_password,
+        ) {
+            warn(
+                self.color,
+                format!(
+                    "Could not write the checkpoint to {:?}: {}",
+                    self.logfile, e
+                ),
             );
-            println!("Latest password: {}", recent_password);
-            print!("Found passwords: {:?}", found_passwords);
-
-            if log_timer.elapsed() > Duration::from_secs(60) {
-                log_timer = Instant::now();
-                if let Err(e) = log(
-                    &opt.logfile,
-                    cur_counter,
-                    &found_passwords,
-                    &recent_password,
-                ) {
-                    restore_terminal(&mut stdout);
-                    eprintln!("Error writing logfile: {}", e);
-                    return;
-                }
-            }
-            handle_err!(stdout.execute(RestorePosition));
         }
-        restore_terminal(&mut stdout);
-        final_stats(data);
-    })
+    }
 }
 
-pub fn run_with_info_thread(opt: Opt, f: impl FnOnce(Opt, Arc<InfoData>)) {
-    let info_data = InfoData::new();
-    let join_handle = spawn_info_thread(opt.clone(), info_data.clone());
-    f(opt, info_data);
+/// Writes the current progress to the logfile, e.g. before exiting
+pub fn write_log(filename: &Path, data: &InfoData) -> Result<()> {
+    let found_passwords = data.found_passwords.lock().unwrap();
+    let recent_password = data.recent_password.lock().unwrap();
+    log_to_file(
+        filename,
+        data.counter.load(Ordering::Relaxed),
+        &found_passwords,
+        &recent_password,
+    )
+}
+
+/// Passwords per second, given a number of passwords tried in a time span
+pub fn password_rate(num_passwords: u64, duration: Duration) -> f64 {
+    num_passwords as f64 / duration.as_secs_f64()
+}
+
+/// Formats a duration with its two largest units, e.g. "3d 4h"
+pub fn format_duration(duration: Duration) -> String {
+    const UNITS: [(&str, u64); 5] = [
+        ("y", 365 * 24 * 3600),
+        ("d", 24 * 3600),
+        ("h", 3600),
+        ("m", 60),
+        ("s", 1),
+    ];
+    let mut secs = duration.as_secs();
+    let mut parts = Vec::new();
+    for (name, unit_secs) in UNITS {
+        if secs >= unit_secs || (parts.is_empty() && unit_secs == 1) {
+            parts.push(format!("{}{}", secs / unit_secs, name));
+            secs %= unit_secs;
+        } else if !parts.is_empty() {
+            // Keep the units adjacent, "1h 0m" instead of "1h 5s"
+            parts.push(format!("0{}", name));
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    parts.join(" ")
+}
+
+/// Runs f while the status display shows the progress
+#[cfg(feature = "cli")]
+pub fn run_with_info_thread(
+    opt: Opt,
+    info_data: Arc<InfoData>,
+    f: impl FnOnce(Opt, Arc<InfoData>),
+) {
+    let join_handle = crate::display::spawn_info_thread(opt.clone(), info_data.clone());
+    // If a worker panicked, the others were cancelled, so the display still ends
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(opt, info_data)));
     // The thread should terminate when it notices that the counter doesn't increment any more.
-    if let Err(e) = join_handle.join() {
+    let info_result = join_handle.join();
+    // The workers' panic is the cause if both panicked
+    if let Err(e) = result.and(info_result) {
         std::panic::resume_unwind(e);
     }
 }
+
+/// Without the "cli" feature, there is no status display
+#[cfg(not(feature = "cli"))]
+pub fn run_with_info_thread(
+    opt: Opt,
+    info_data: Arc<InfoData>,
+    f: impl FnOnce(Opt, Arc<InfoData>),
+) {
+    f(opt, info_data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A writer that fails like a full disk
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("No space left on device"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::other("No space left on device"))
+        }
+    }
+
+    #[test]
+    fn test_write_found_passwords() {
+        let found = |password: &str| FoundPassword {
+            password: password.to_owned(),
+            keys: [0; 3],
+        };
+        let found_passwords = [found("abc"), found("a,\"b")];
+        let write = |format| {
+            let mut written = Vec::new();
+            write_found_passwords(&mut written, format, &found_passwords, |found| {
+                found.password.len()
+            })
+            .unwrap();
+            String::from_utf8(written).unwrap()
+        };
+        assert_eq!(write(OutputFormat::Plain), "abc\na,\"b\n");
+        assert_eq!(
+            write(OutputFormat::Csv),
+            "password,length,matched_files\nabc,3,3\n\"a,\"\"b\",4,4\n"
+        );
+        let value: json::Value = json::from_str(&write(OutputFormat::Json)).unwrap();
+        assert_eq!(value[1]["password"], "a,\"b");
+        assert_eq!(value[1]["matched_files"], 4);
+
+        let found_passwords = [found("a\0\\\u{1f}")];
+        let mut written = Vec::new();
+        write_found_passwords(&mut written, OutputFormat::Plain, &found_passwords, |_| 1).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), "a\\x00\\\\\\x1f\n");
+        assert_eq!(escape_control("a\\b"), "a\\b");
+    }
+
+    #[test]
+    fn test_log_write_failure() {
+        let mut written = Vec::new();
+        log(&mut written, 42, &[], "abc").unwrap();
+        let value: json::Value = json::from_slice(&written).unwrap();
+        assert_eq!(value["counter"], 42);
+        assert_eq!(value["recent_password"], "abc");
+        // The small log fits into the BufWriter's buffer, so this fails only on the final flush
+        let e = log(FailingWriter, 42, &[], "abc").unwrap_err();
+        assert!(e.to_string().contains("No space left on device"));
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let logfile =
+            std::env::temp_dir().join(format!("zipcrack_checkpoint_{}.json", std::process::id()));
+        let mut opt: Opt = crate::opt::CrackOptions::new("digits".parse().unwrap())
+            .logfile(&logfile)
+            .into();
+        let data = InfoData::new();
+        let counter_in_log = || {
+            let value: json::Value =
+                json::from_slice(&std::fs::read(&logfile).unwrap_or_default()).unwrap_or_default();
+            value["counter"].as_u64()
+        };
+        assert!(Checkpoints::new(&opt).is_none());
+        opt.checkpoint_every = Some(10);
+        let mut checkpoints = Checkpoints::new(&opt).unwrap();
+        let mut other_thread = checkpoints.clone();
+        checkpoints.update(&data, 0, 9);
+        assert_eq!(counter_in_log(), None);
+        checkpoints.update(&data, 9, 1);
+        assert_eq!(counter_in_log(), Some(10));
+        other_thread.update(&data, 10, 25);
+        assert_eq!(counter_in_log(), Some(30));
+        // The other thread already wrote the checkpoint at 30
+        std::fs::remove_file(&logfile).unwrap();
+        checkpoints.update(&data, 35, 1);
+        assert_eq!(counter_in_log(), None);
+        checkpoints.update(&data, 36, 4);
+        assert_eq!(counter_in_log(), Some(40));
+        std::fs::remove_file(&logfile).unwrap();
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(3605)), "1h 0m");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 86400 + 7200)),
+            "2d 2h"
+        );
+        assert_eq!(format_duration(Duration::from_secs(400 * 86400)), "1y 35d");
+    }
+}
@@ -0,0 +1,43 @@
+//! Attempts to find the encryption password for a ZIP file with brute force.
+//!
+//! Without the "std" feature, only the crypto module is available. The "cli" feature adds the
+//! status display of the command line tool, and "tui" the --tui dashboard.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod crypto;
+
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod crack;
+#[cfg(feature = "std")]
+pub mod decompress;
+#[cfg(feature = "std")]
+pub mod decrypt;
+#[cfg(feature = "cli")]
+pub mod display;
+#[cfg(feature = "std")]
+pub mod distributed;
+#[cfg(feature = "std")]
+pub mod extract;
+#[cfg(feature = "std")]
+pub mod hashcat;
+#[cfg(feature = "std")]
+pub mod info;
+#[cfg(feature = "std")]
+pub mod markov;
+#[cfg(feature = "std")]
+pub mod opt;
+#[cfg(feature = "std")]
+pub mod password_iter;
+#[cfg(feature = "std")]
+pub mod scan;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "std")]
+pub mod validate;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "std")]
+pub mod zipfile;
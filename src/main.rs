@@ -1,41 +1,327 @@
-use anyhow::{anyhow, Result};
-use structopt::StructOpt;
-
-mod crack;
-mod decrypt;
-mod info;
-mod opt;
-mod password_iter;
-mod zipfile;
-
-fn result_main() -> Result<()> {
-    let opt = opt::Opt::from_args();
-    let input = std::fs::read(&opt.input)?;
-    let (_, records) = zipfile::parse(&input).map_err(|e| anyhow!("{}", e))?;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+
+use anyhow::{Context, Result};
+
+use zipcrack::zipfile::ZipArchive;
+use zipcrack::{
+    crack, display, distributed, extract, hashcat, info, opt, password_iter, scan, validate,
+    zipfile,
+};
+
+#[cfg(feature = "mmap")]
+fn read_input(path: &Path) -> Result<memmap2::Mmap> {
+    let file = std::fs::File::open(path)?;
+    // Safety: The archive must not be modified by another process while we're running.
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(mmap)
+}
+
+#[cfg(not(feature = "mmap"))]
+fn read_input(path: &Path) -> Result<Vec<u8>> {
+    Ok(std::fs::read(path)?)
+}
+
+/// Writes the found passwords to the --output file and, unless the --format is plain, to stdout.
+/// Returns whether they were written to stdout.
+fn write_found_passwords(
+    format: opt::OutputFormat,
+    output: Option<&Path>,
+    archive: &ZipArchive,
+    found_passwords: &[info::FoundPassword],
+) -> Result<bool> {
+    let matched_files =
+        |found: &info::FoundPassword| crack::num_matching_files(archive, found.keys);
+    if let Some(path) = output {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Could not create the output file {:?}", path))?;
+        info::write_found_passwords(
+            std::io::BufWriter::new(file),
+            format,
+            found_passwords,
+            matched_files,
+        )
+        .with_context(|| format!("Could not write the output file {:?}", path))?;
+    }
+    if format == opt::OutputFormat::Plain {
+        return Ok(false);
+    }
+    info::write_found_passwords(
+        std::io::stdout().lock(),
+        format,
+        found_passwords,
+        matched_files,
+    )?;
+    Ok(true)
+}
+
+/// Returns the exit code: 0 if a password was found or nothing was searched, 1 if none was found
+/// Writes the logfile for a search that was stopped without finding a password, like the Ctrl-C
+/// handler does, and tells why it stopped
+fn stopped_early(logfile: &Path, info_data: &info::InfoData) -> info::Termination {
+    if let Err(e) = info::write_log(logfile, info_data) {
+        eprintln!("Error writing logfile: {}", e);
+    }
+    if info_data.aborted.load(Ordering::Relaxed) {
+        info::Termination::Aborted
+    } else {
+        info::Termination::Interrupted
+    }
+}
+
+fn result_main() -> Result<ExitCode> {
+    let opt = opt::Opt::parse()?;
+    if opt.self_test {
+        crack::self_test()?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(dir) = &opt.scan {
+        let results = scan::scan(dir)?;
+        if opt.json {
+            println!("{}", scan::to_json(&results));
+        } else {
+            scan::print_table(&results);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(path) = &opt.generate {
+        let keyspace = password_iter::keyspace_size(&opt);
+        if keyspace > password_iter::LARGE_KEYSPACE {
+            info::warn(
+                opt.color,
+                format!("Writing {:.1e} passwords to {:?}", keyspace as f64, path),
+            );
+        }
+        let file =
+            std::fs::File::create(path).with_context(|| format!("Could not create {:?}", path))?;
+        let count = password_iter::write_passwords(&opt, file)
+            .with_context(|| format!("Could not write to {:?}", path))?;
+        println!("Wrote {} passwords to {:?}", count, path);
+        return Ok(ExitCode::SUCCESS);
+    }
+    let archive = match (opt.raw_header, opt.raw_time, &opt.from_json) {
+        (Some(raw_header), Some(raw_time), _) => {
+            ZipArchive::from_encryption_header(raw_header.0, raw_time)
+        }
+        (_, _, Some(path)) => {
+            let input =
+                std::fs::read(path).with_context(|| format!("Could not read {:?}", path))?;
+            let value: serde_json::Value = serde_json::from_slice(&input)
+                .with_context(|| format!("{:?} is not valid JSON", path))?;
+            zipfile::archive_from_json(&value)
+                .with_context(|| format!("Could not read the records of {:?}", path))?
+        }
+        // The file data takes as much memory as the archive, and the search doesn't need it
+        _ if opt.headers_only || !opt.needs_file_data() => {
+            ZipArchive::parse_headers_only(&read_input(&opt.input)?)?
+        }
+        _ => ZipArchive::parse(&read_input(&opt.input)?)?,
+    };
+    if opt.hashcat {
+        for hash in hashcat::hashes(&archive)? {
+            println!("{}", hash);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
     if opt.show_zipfile_records {
-        zipfile::show_file(&records);
+        if opt.json {
+            println!("{}", zipfile::records_to_json(&archive));
+            return Ok(ExitCode::SUCCESS);
+        }
+        zipfile::show_file(&archive);
     }
+    if let Some(comment) = archive.comment_text() {
+        println!("Archive comment: {:?}", comment);
+    }
+    // First, since a truncated archive explains the missing entries that validate() finds
+    validate::report(&validate::check_structure(&archive), opt.strict, opt.color)?;
+    if opt.validate || opt.strict {
+        validate::report(&validate::validate(archive.records()), true, opt.color)?;
+    }
+    if let Some(dir) = &opt.extract_clear {
+        extract::extract_clear(archive.records(), dir)?;
+    }
+    for local_file in archive.encrypted_files() {
+        match local_file.local_file_header.encryption_method() {
+            Some(zipfile::EncryptionMethod::ZipCrypto) | None => (),
+            Some(method) => info::warn(
+                opt.color,
+                format!(
+                    "{:?} uses {} encryption, which zipcrack can't crack, skipping it",
+                    local_file.local_file_header.file_name, method
+                ),
+            ),
+        }
+    }
+    crack::check_files_to_check(&opt, &archive)?;
+    if opt.count_only {
+        let keyspace = password_iter::keyspace_size(&opt);
+        if keyspace > password_iter::LARGE_KEYSPACE {
+            info::warn(
+                opt.color,
+                format!("Counting over {:.1e} passwords", keyspace as f64),
+            );
+        }
+        crack::print_match_counts(&crack::count_matches(&opt, &archive));
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(num_samples) = opt.sample {
+        let seed = opt.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_nanos() as u64)
+        });
+        let passed = crack::sample_matches(&opt, &archive, num_samples, seed);
+        crack::print_sample_result(&opt, &archive, num_samples, seed, passed);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if opt.estimate {
+        crack::estimate(&opt, &archive);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if opt.benchmark_alphabets {
+        crack::benchmark_alphabets(&opt, &archive);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if !opt.independent_files && opt.serve.is_none() && opt.connect.is_none() && opt.keys.is_none()
+    {
+        crack::check_false_positives(&opt, &archive)?;
+    }
+
+    let info_data = info::InfoData::new();
+    let json_summary = opt.json_summary;
 
-    // Don't want the cursor to stay hidden
+    // Don't want the cursor to stay hidden. With the "termination" feature, this also handles
+    // SIGTERM and SIGHUP.
+    let info_data_for_handler = info_data.clone();
+    let logfile = opt.logfile.clone();
+    let logfile_for_handler = logfile.clone();
+    let tui = opt.tui;
     ctrlc::set_handler(move || {
-        info::restore_terminal(&mut std::io::stdout());
-        std::process::exit(0); // kthxbai
+        if tui {
+            #[cfg(feature = "tui")]
+            zipcrack::tui::restore_terminal();
+        } else {
+            display::restore_terminal(&mut std::io::stdout());
+        }
+        if let Err(e) = info::write_log(&logfile_for_handler, &info_data_for_handler) {
+            eprintln!("Error writing logfile: {}", e);
+        }
+        if json_summary {
+            info::print_json_summary(&info_data_for_handler, info::Termination::Interrupted);
+        }
+        std::process::exit(info::Termination::Interrupted.exit_code().into()); // kthxbai
     })
-    .expect("Error setting Ctrl-C handler");
+    .expect("Error setting signal handler");
 
-    if opt.unroll {
-        crack::crack_unrolled(opt, &records);
+    crack::report_pinning(&opt);
+    if let Some(keys) = opt.keys {
+        let mut termination = info::Termination::Found;
+        match crack::recover_password_from_keys(&opt, keys.0, info_data.clone()) {
+            Some(password) => {
+                let found = [info::FoundPassword {
+                    password,
+                    keys: keys.0,
+                }];
+                let output = opt.output.as_deref();
+                if !write_found_passwords(opt.format, output, &archive, &found)? {
+                    display::report_found_passwords(&found, opt.color);
+                }
+                if opt.dump_keys {
+                    println!("Keys: {}", keys);
+                }
+            }
+            None if opt.cancel.is_cancelled() => {
+                termination = stopped_early(&opt.logfile, &info_data)
+            }
+            None => {
+                println!(
+                    "No password of up to {} characters has these keys",
+                    opt.max_length
+                );
+                termination = info::Termination::Completed;
+            }
+        }
+        if let Some(dir) = &opt.extract {
+            extract::extract_all(archive.records(), dir, keys.0)?;
+        }
+        return Ok(termination.exit_code().into());
+    }
+
+    let extract_dir = opt.extract.clone();
+    let color = opt.color;
+    let independent_files = opt.independent_files;
+    let dump_keys = opt.dump_keys;
+    let format = opt.format;
+    let output = opt.output.clone();
+    let cancel = opt.cancel.clone();
+    let found_passwords = if independent_files {
+        let results = crack::run_independent(opt, &archive, info_data.clone())?;
+        for (file_name, found_passwords) in &results {
+            if format == opt::OutputFormat::Plain {
+                print!("{:?}: ", file_name);
+                display::report_found_passwords(found_passwords, color);
+            }
+            if dump_keys {
+                display::dump_keys(found_passwords);
+            }
+        }
+        results.into_iter().flat_map(|(_, found)| found).collect()
+    } else if let Some(addr) = opt.serve.clone() {
+        distributed::serve(opt, &addr, &archive, info_data.clone())?
+    } else if let Some(addr) = opt.connect.clone() {
+        distributed::connect(opt, &addr, &archive, info_data.clone())?
     } else {
-        crack::crack(opt, &records);
+        crack::run(opt, &archive, info_data.clone())?
+    };
+    let written = write_found_passwords(format, output.as_deref(), &archive, &found_passwords)?;
+    if !independent_files {
+        if !written {
+            display::report_found_passwords(&found_passwords, color);
+        }
+        if dump_keys {
+            display::dump_keys(&found_passwords);
+        }
+    }
+    let termination = if !found_passwords.is_empty() {
+        info::Termination::Found
+    } else if cancel.is_cancelled() {
+        // Quitting the --tui dashboard or --min-rate stop the search without a signal
+        stopped_early(&logfile, &info_data)
+    } else {
+        info::Termination::Completed
+    };
+    if json_summary {
+        info::print_json_summary(&info_data, termination);
+    }
+    if let Some(dir) = extract_dir {
+        match found_passwords.first() {
+            Some(found) => {
+                if found_passwords.len() > 1 {
+                    info::warn(
+                        color,
+                        format!(
+                            "Found {} passwords, extracting with {:?}",
+                            found_passwords.len(),
+                            found.password
+                        ),
+                    );
+                }
+                extract::extract_all(archive.records(), &dir, found.keys)?;
+            }
+            None => eprintln!("No password found, nothing to extract"),
+        }
     }
-    Ok(())
+    Ok(termination.exit_code().into())
 }
 
-fn main() {
+fn main() -> ExitCode {
     match result_main() {
-        Ok(_) => (),
+        Ok(exit_code) => exit_code,
         Err(e) => {
-            eprintln!("{}", e);
+            eprintln!("{:#}", e);
+            ExitCode::from(info::EXIT_ERROR)
         }
     }
 }
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::decrypt::{update_keys, INITIAL_KEYS};
+use crate::info::CancellationToken;
+use crate::opt::Opt;
+use crate::password_iter::{InitializedKeys, Password};
+
+/// Costs are -log2 of the probability, in units of 1/COST_SCALE bits
+const COST_SCALE: f64 = 8.0;
+
+/// The two preceding characters, None at the start of the password
+type Context = [Option<u8>; 2];
+
+fn context(password: &[u8]) -> Context {
+    match password {
+        [] => [None, None],
+        [c] => [None, Some(*c)],
+        [.., c1, c2] => [Some(*c1), Some(*c2)],
+    }
+}
+
+/// An order-2 Markov model of which character follows the two preceding ones
+pub struct MarkovModel {
+    /// For each context seen in the corpus, all characters with their costs, cheapest first
+    transitions: HashMap<Context, Vec<(u8, u32)>>,
+    /// The transitions for contexts that were never seen, all equally likely
+    uniform_transitions: Vec<(u8, u32)>,
+}
+
+impl MarkovModel {
+    /// Builds the model from a corpus with one password per line. Lines with characters outside
+    /// of the alphabet are ignored.
+    pub fn from_corpus(corpus: impl BufRead, alphabet: &[u8]) -> Result<Self> {
+        let mut counts: HashMap<Context, Vec<u32>> = HashMap::new();
+        for line in corpus.split(b'\n') {
+            let mut line = line?;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let Some(indices) = line
+                .iter()
+                .map(|c| alphabet.iter().position(|a| a == c))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            for (i, idx) in indices.into_iter().enumerate() {
+                let counts = counts
+                    .entry(context(&line[..i]))
+                    .or_insert_with(|| vec![0; alphabet.len()]);
+                counts[idx] += 1;
+            }
+        }
+
+        // With add-one smoothing, every password stays possible, just expensive
+        let cost = |count: u32, total: u32| {
+            let probability = f64::from(count + 1) / (f64::from(total) + alphabet.len() as f64);
+            (-probability.log2() * COST_SCALE).round() as u32
+        };
+        let transitions = counts
+            .into_iter()
+            .map(|(context, counts)| {
+                let total = counts.iter().sum();
+                let mut transitions: Vec<_> = alphabet
+                    .iter()
+                    .zip(counts)
+                    .map(|(&c, count)| (c, cost(count, total)))
+                    .collect();
+                transitions.sort_by_key(|&(_, cost)| cost);
+                (context, transitions)
+            })
+            .collect();
+        let uniform_transitions = alphabet.iter().map(|&c| (c, cost(0, 0))).collect();
+        Ok(Self {
+            transitions,
+            uniform_transitions,
+        })
+    }
+
+    pub fn from_file(path: &Path, alphabet: &[u8]) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Self::from_corpus(std::io::BufReader::new(file), alphabet)
+    }
+
+    fn transitions(&self, password: &[u8]) -> &[(u8, u32)] {
+        self.transitions
+            .get(&context(password))
+            .unwrap_or(&self.uniform_transitions)
+    }
+
+    /// Depth-first search for the passwords whose cost is in the given band
+    #[allow(clippy::too_many_arguments)]
+    fn visit(
+        &self,
+        password: &mut Vec<u8>,
+        keys: InitializedKeys,
+        cost: u32,
+        band: &Range<u32>,
+        lengths: &Range<usize>,
+        cancel: &CancellationToken,
+        callback: &mut impl FnMut(&Password, InitializedKeys),
+    ) {
+        if cancel.is_cancelled() {
+            return;
+        }
+        if lengths.contains(&password.len()) && band.contains(&cost) {
+            callback(password, keys);
+        }
+        if password.len() + 1 >= lengths.end {
+            return;
+        }
+        for &(c, char_cost) in self.transitions(password) {
+            let cost = cost + char_cost;
+            // The transitions are sorted by cost, so all remaining ones are too expensive as well
+            if cost >= band.end {
+                break;
+            }
+            let mut keys = keys;
+            update_keys(&mut keys, c);
+            password.push(c);
+            self.visit(password, keys, cost, band, lengths, cancel, callback);
+            password.pop();
+        }
+    }
+
+    /// Enumerates the passwords that are at most --markov-threshold bits unlikely, most likely
+    /// ones first. The passwords are handed out to the threads round-robin.
+    pub fn for_each_password(
+        &self,
+        opt: &Opt,
+        idx: u8,
+        mut callback: impl FnMut(&Password, InitializedKeys),
+    ) {
+        let lengths = usize::from(opt.min_length)..usize::from(opt.max_length) + 1;
+        let max_cost = (f64::from(opt.markov_threshold) * COST_SCALE) as u32;
+        let mut count: u64 = 0;
+        let mut callback_for_thread = |pw: &Password, keys: InitializedKeys| {
+            if count % u64::from(opt.num_threads) == u64::from(idx) {
+                callback(pw, keys);
+            }
+            count += 1;
+        };
+        // Passes over bands of one bit each. Within a band, the order is depth-first.
+        let band_width = COST_SCALE as u32;
+        for band_start in (0..=max_cost).step_by(band_width as usize) {
+            let band = band_start..(band_start + band_width).min(max_cost + 1);
+            self.visit(
+                &mut Vec::new(),
+                INITIAL_KEYS,
+                0,
+                &band,
+                &lengths,
+                &opt.cancel,
+                &mut callback_for_thread,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decrypt::keys_for_password;
+    use crate::opt::Alphabet;
+
+    #[test]
+    fn test_markov_order() {
+        let corpus = b"abc\nabc\nabc\nabd\nbcd\nxyz!\n".as_slice();
+        let model = MarkovModel::from_corpus(corpus, b"abcd").unwrap();
+        let mut opt = crate::password_iter::test::test_opt();
+        opt.alphabet = Alphabet(b"abcd".to_vec());
+        opt.min_length = 3;
+        opt.max_length = 3;
+        opt.markov_threshold = 20;
+        let mut passwords = vec![];
+        model.for_each_password(&opt, 0, |pw: &[u8], keys: InitializedKeys| {
+            assert_eq!(keys, keys_for_password(pw));
+            passwords.push(pw.to_vec());
+        });
+        assert_eq!(passwords[0], b"abc");
+        assert_eq!(passwords[1], b"abd");
+        // No duplicates
+        let mut deduped = passwords.clone();
+        deduped.sort();
+        deduped.dedup();
+        assert_eq!(deduped.len(), passwords.len());
+    }
+}
@@ -1,20 +1,160 @@
+use std::ffi::OsString;
+use std::io::IsTerminal;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
 use structopt::StructOpt;
 
+use crate::config::Config;
+use crate::info::{escape_control, CancellationToken};
+use crate::markov::MarkovModel;
+use crate::password_iter::{index_to_password, keyspace_size};
+
 #[derive(Clone, StructOpt)]
 #[structopt(
     name = "zipcrack",
     about = "Attempts to crack a ZIP archive's password with brute force."
 )]
 pub struct Opt {
-    /// The alphabet to build passwords from. Can be "base64" or "custom:<letters>".
-    #[structopt(short, long)]
+    /// The alphabet to build passwords from. Can be "base64", "lower", "upper", "digits",
+    /// "hex:<bytes>" or "custom:<letters>", or several of them joined with "+", e.g.
+    /// "lower+digits+custom:!@#". "hex:" takes ASCII bytes as pairs of hex digits, e.g. "hex:0009"
+    /// for NUL and tab. "custom:" has to come last, since its letters may contain "+". Can be
+    /// given more than once, the alphabets are combined.
+    #[structopt(
+        short = "a",
+        long = "alphabet",
+        value_name = "alphabet",
+        number_of_values = 1,
+        required_unless_one = &["stdin-passwords", "connect", "self-test", "scan", "hashcat", "benchmark-alphabets", "json"]
+    )]
+    alphabet_arg: Vec<Alphabet>,
+
+    /// The union of the --alphabet arguments, without the --exclude characters
+    #[structopt(skip)]
     pub alphabet: Alphabet,
 
+    /// Tests the passwords from stdin (one per line) instead of generating them
+    #[structopt(
+        long,
+        conflicts_with_all = &["alphabet-arg", "unroll", "markov", "incremental-charset", "prefix", "suffix", "start-password"]
+    )]
+    pub stdin_passwords: bool,
+
+    /// Tries a built-in list of common passwords before the search, and stops if one matches
+    #[structopt(long)]
+    pub try_common: bool,
+
+    /// Characters to remove from the alphabet
+    #[structopt(long)]
+    pub exclude: Option<String>,
+
+    /// Tries lowercase letters first, then lowercase letters and digits, then the whole alphabet
+    #[structopt(long, conflicts_with_all = &["markov", "start-password"])]
+    pub incremental_charset: bool,
+
+    /// Builds a Markov model from this corpus (one password per line) and tries the most likely
+    /// passwords first, instead of brute force
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["start-password", "resume-from-index", "incremental-charset"]
+    )]
+    pub markov: Option<PathBuf>,
+
+    /// How unlikely passwords from the Markov model may be, in bits (-log2 of the probability)
+    #[structopt(long, default_value = "30")]
+    pub markov_threshold: u16,
+
+    #[structopt(skip)]
+    pub markov_model: Option<Arc<MarkovModel>>,
+
+    /// A file with the characters of the alphabet from the most to the least frequent, line
+    /// breaks are ignored. Each position tries the more frequent characters first, which finds
+    /// common passwords sooner without changing the keyspace.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        value_name = "file",
+        conflicts_with = "markov"
+    )]
+    pub freq: Option<PathBuf>,
+
+    /// Passwords consisting only of these characters were already tried
+    #[structopt(skip)]
+    pub previous_alphabet: Option<Alphabet>,
+
+    #[structopt(skip)]
+    pub cancel: CancellationToken,
+
     /// Input ZIP file. Should contain several files to eliminate false positives.
-    #[structopt(parse(from_os_str))]
+    #[structopt(
+        name = "input",
+        parse(from_os_str),
+        required_unless_one = &["self-test", "raw-header", "from-json", "generate", "scan"]
+    )]
+    input_arg: Option<PathBuf>,
+
+    /// The input from the command line, empty with --self-test
+    #[structopt(skip)]
     pub input: PathBuf,
 
+    /// Searches for the password of this encryption header (24 hex digits) from another tool,
+    /// instead of an archive
+    #[structopt(
+        long,
+        value_name = "hex",
+        requires = "raw-time",
+        conflicts_with_all = &["magic", "extract", "extract-clear"]
+    )]
+    pub raw_header: Option<RawHeader>,
+
+    /// The last modification time (in DOS format) of the file with the --raw-header, whose
+    /// bytes the decrypted header ends with
+    #[structopt(long, value_name = "time", requires = "raw-header")]
+    pub raw_time: Option<u16>,
+
+    /// Searches for the password of the encryption headers in this JSON file from
+    /// --show-zipfile-records --json, instead of an archive, e.g. when the archive is huge or on
+    /// another machine
+    #[structopt(
+        long,
+        parse(from_os_str),
+        value_name = "file",
+        conflicts_with_all = &["raw-header", "headers-only", "hashcat", "magic", "extract", "extract-clear"]
+    )]
+    pub from_json: Option<PathBuf>,
+
+    /// Writes the passwords that the search would try to this file, one per line, instead of
+    /// cracking an archive
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["stdin-passwords", "incremental-charset", "serve", "connect", "estimate"]
+    )]
+    pub generate: Option<PathBuf>,
+
+    /// Checks the password search against a built-in known answer and measures the speed, without
+    /// an archive
+    #[structopt(long)]
+    pub self_test: bool,
+
+    /// Lists the ZIP files in this directory and its subdirectories with how they are encrypted,
+    /// without cracking anything
+    #[structopt(long, parse(from_os_str), value_name = "dir")]
+    pub scan: Option<PathBuf>,
+
+    /// Prints the --scan results or the --show-zipfile-records as JSON, e.g. for scripts that
+    /// extract the encryption headers
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Reads options from a TOML file whose keys are the long option names, e.g.
+    /// `max-length = 6`. Options on the command line override the ones in the file.
+    #[structopt(long, parse(from_os_str), value_name = "file.toml")]
+    pub config: Option<PathBuf>,
+
     /// Logfile where progress is saved
     #[structopt(long, parse(from_os_str), default_value = "zipcrack_log.json")]
     pub logfile: PathBuf,
@@ -27,26 +167,642 @@ pub struct Opt {
     #[structopt(long, default_value = "1")]
     pub min_length: u8,
 
+    /// If no password is found, continues with passwords one character longer than
+    /// --max-length, and so on up to --max-length-cap
+    #[structopt(
+        long,
+        conflicts_with_all = &["stdin-passwords", "markov", "pattern", "independent-files", "serve", "connect"]
+    )]
+    pub auto_extend: bool,
+
+    /// The length at which --auto-extend stops
+    #[structopt(long, default_value = "16")]
+    pub max_length_cap: u8,
+
+    /// The password length as "<min>-<max>" or a single exact length. With an exact length, the
+    /// search starts right at that length
+    #[structopt(
+        long,
+        visible_alias = "only-length",
+        conflicts_with_all = &["min-length", "max-length"]
+    )]
+    pub length: Option<LengthRange>,
+
     /// Starts the search from this string, not the alphabetically lowest password
     #[structopt(long)]
     pub start_password: Option<String>,
 
+    /// Starts the search from the password at this position in the enumeration order, counting
+    /// from 0, e.g. to continue where an external scheduler stopped. Unlike --start-password,
+    /// this also works for passwords that can't be typed.
+    #[structopt(
+        long,
+        value_name = "index",
+        conflicts_with_all = &["start-password", "pattern", "markov", "incremental-charset", "stdin-passwords"]
+    )]
+    pub resume_from_index: Option<u128>,
+
+    /// A fixed start of the password. The lengths include it, --start-password does not
+    #[structopt(long)]
+    pub prefix: Option<String>,
+
+    /// A fixed end of the password. The lengths include it, --start-password does not
+    #[structopt(long)]
+    pub suffix: Option<String>,
+
+    /// A remembered password with "?" for each unknown character, e.g. "pass?ord2?". Only the
+    /// "?" go through the alphabet, the other characters stay as they are. "\?" is a literal "?"
+    #[structopt(
+        long,
+        conflicts_with_all = &["stdin-passwords", "markov", "incremental-charset", "prefix", "suffix", "start-password", "length", "unroll", "serve", "connect"]
+    )]
+    pub pattern: Option<PasswordPattern>,
+
+    /// Instead of cracking, counts how many passwords of the keyspace pass each file's check on
+    /// its own and how many pass all of them, to see how many false positives the archive lets
+    /// through
+    #[structopt(
+        long,
+        conflicts_with_all = &["stdin-passwords", "estimate", "independent-files", "extract", "serve", "connect"]
+    )]
+    pub count_only: bool,
+
+    /// Instead of cracking, checks this many random keys against the files and extrapolates how
+    /// many wrong passwords of the keyspace would pass, to see whether false positives are likely
+    #[structopt(
+        long,
+        value_name = "n",
+        conflicts_with_all = &["stdin-passwords", "estimate", "count-only", "independent-files", "extract", "serve", "connect"]
+    )]
+    pub sample: Option<u64>,
+
+    /// The seed for the random keys of --sample, to get the same result as an earlier run.
+    /// Without it, the seed depends on the time
+    #[structopt(long, requires = "sample")]
+    pub seed: Option<u64>,
+
+    /// Measures the speed on this machine and prints how long the search would take, without
+    /// doing it
+    #[structopt(long, conflicts_with_all = &["stdin-passwords", "markov", "suffix"])]
+    pub estimate: bool,
+
+    /// Like --estimate, but for each of the built-in alphabets and --alphabet, if given, to
+    /// compare how long the search would take with each of them
+    #[structopt(
+        long,
+        conflicts_with_all = &["stdin-passwords", "markov", "suffix", "pattern", "estimate"]
+    )]
+    pub benchmark_alphabets: bool,
+
+    /// Coordinates a search across machines: listens on this address (e.g. "0.0.0.0:7878") and
+    /// hands out ranges of the keyspace to workers
+    #[structopt(
+        long,
+        value_name = "addr",
+        conflicts_with_all = &["stdin-passwords", "markov", "incremental-charset", "start-password", "estimate"]
+    )]
+    pub serve: Option<String>,
+
+    /// Works on ranges of the keyspace from the coordinator at this address (see --serve). The
+    /// coordinator decides the alphabet, lengths, prefix and suffix
+    #[structopt(
+        long,
+        value_name = "addr",
+        conflicts_with_all = &["serve", "stdin-passwords", "markov", "incremental-charset", "start-password", "estimate", "unroll"]
+    )]
+    pub connect: Option<String>,
+
+    /// How many passwords the coordinator hands out to a worker at once
+    #[structopt(long, default_value = "10000000000")]
+    pub chunk_size: u128,
+
+    /// Only checks passwords against the encrypted files whose names match this glob pattern,
+    /// e.g. "*.pdf"
+    #[structopt(long, value_name = "pattern")]
+    pub file_glob: Option<glob::Pattern>,
+
+    /// Searches for the password of each encrypted file separately, instead of assuming that all
+    /// files share one password
+    #[structopt(long, conflicts_with_all = &["extract", "serve", "connect"])]
+    pub independent_files: bool,
+
+    /// Prints the AES-encrypted files as hashes for hashcat (mode 13600) or John the Ripper
+    /// instead of cracking. zipcrack itself can only crack ZipCrypto
+    #[structopt(long, visible_alias = "jtr", conflicts_with = "raw-header")]
+    pub hashcat: bool,
+
     /// Prints out the records inside the ZIP file
     #[structopt(long)]
     pub show_zipfile_records: bool,
 
+    /// Checks that the local file headers agree with the central directory before cracking
+    #[structopt(long)]
+    pub validate: bool,
+
+    /// Fails on any problem with the structure of the archive instead of working around it,
+    /// including the checks of --validate, and when --max-false-positive-rate is exceeded
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Warns before the search (fails with --strict) if more wrong passwords than this are
+    /// expected to pass the checks in the keyspace. Each encrypted file lets through one in 65536
+    #[structopt(long, default_value = "1")]
+    pub max_false_positive_rate: f64,
+
+    /// Shows the speed of each thread in the status display, to spot threads that fall behind
+    #[structopt(long)]
+    pub per_thread_stats: bool,
+
+    /// Shows a progress bar with the percentage done and the ETA instead of the status lines.
+    /// Only when the output is a terminal and the number of passwords is known
+    #[structopt(long)]
+    pub progress_bar: bool,
+
+    /// Shows a dashboard with the speed over time, the progress and the found passwords instead
+    /// of the status lines. Press q to stop the search. Requires building with --features tui
+    #[structopt(long, conflicts_with_all = &["progress-bar", "min-rate", "per-thread-stats"])]
+    pub tui: bool,
+
+    /// Whether to color the output: "auto" (only on terminals), "always" or "never". "auto" also
+    /// respects the NO_COLOR environment variable and TERM=dumb
+    #[structopt(long, default_value = "auto")]
+    pub color: ColorChoice,
+
+    /// Prints a JSON summary of the run to stderr at the end
+    #[structopt(long)]
+    pub json_summary: bool,
+
+    /// Also writes the found passwords to this file, in the --format
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// How to write the found passwords to --output and stdout at the end: "plain" (one per
+    /// line, the usual summary on stdout), "json" (an array of objects with the password and the
+    /// number of files it matches) or "csv" (password,length,matched_files)
+    #[structopt(long, default_value = "plain")]
+    pub format: OutputFormat,
+
+    /// Reports passwords that passed some files but were rejected by a later one (only without
+    /// --unroll)
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
     /// Uses the unrolled version of the algorithm
     #[structopt(long)]
     pub unroll: bool,
 
+    /// How often the progress is saved to the logfile, in seconds
+    #[structopt(long, default_value = "60")]
+    pub log_interval: u64,
+
+    /// Also saves the progress to the logfile each time the number of tried passwords reaches a
+    /// multiple of this, e.g. for reproducible checkpoints in fast runs
+    #[structopt(long, value_name = "count")]
+    pub checkpoint_every: Option<u64>,
+
+    /// How often the status display is updated, in milliseconds
+    #[structopt(long, default_value = "100")]
+    pub refresh_interval: u64,
+
     /// How many threads to spawn
     #[structopt(long, default_value = "1")]
     pub num_threads: u8,
+
+    /// Binds each thread to its own CPU core, for better cache locality on many-core machines
+    #[structopt(long)]
+    pub pin_threads: bool,
+
+    /// Extracts the files that are not encrypted into this directory
+    #[structopt(long, parse(from_os_str))]
+    pub extract_clear: Option<PathBuf>,
+
+    /// Only reads the headers of the files, not their data, which the search doesn't need. Saves
+    /// memory with large archives. This is the default unless --extract, --extract-clear, --magic
+    /// or --hashcat need the data, with which it conflicts
+    #[structopt(long, conflicts_with_all = &["extract", "extract-clear", "magic", "hashcat"])]
+    pub headers_only: bool,
+
+    /// Extracts all files into this directory with the first found password. Found passwords
+    /// must also decrypt the files to contents with the right CRC-32, so false positives are
+    /// skipped, and the search stops at the first one that does
+    #[structopt(long, parse(from_os_str))]
+    pub extract: Option<PathBuf>,
+
+    /// Aborts the run with exit code 3 if the speed stays below this many passwords/s
+    #[structopt(long)]
+    pub min_rate: Option<f64>,
+
+    /// The expected first bytes of the first encrypted file, to eliminate false positives. Can be
+    /// "hex:<bytes>" or a literal string. Only useful if that file is stored without compression
+    #[structopt(long)]
+    pub magic: Option<Magic>,
+
+    /// Internal keys from a known-plaintext attack (e.g. with bkcrack), as three hex numbers.
+    /// Searches for a password with these keys (up to --max-length) instead of cracking the
+    /// archive. --extract works with the keys even if no password is found
+    #[structopt(
+        long,
+        conflicts_with_all = &["stdin-passwords", "markov", "serve", "connect", "estimate"]
+    )]
+    pub keys: Option<Keys>,
+
+    /// Prints the internal keys of each found password like bkcrack does, e.g. to extract the
+    /// archive with bkcrack
+    #[structopt(long)]
+    pub dump_keys: bool,
+}
+
+impl Opt {
+    /// Parses the command line and applies the options that modify other options
+    pub fn parse() -> anyhow::Result<Self> {
+        let mut args: Vec<OsString> = std::env::args_os().collect();
+        if let Some(path) = crate::config::config_path(&args[1..]) {
+            let config_args = Config::from_file(&path)?.args(&args[1..]);
+            args.splice(1..1, config_args);
+        }
+        let mut opt = Self::from_iter_safe(args).unwrap_or_else(|e| {
+            // Clap exits with 1 on usage errors, but that means "no password found"
+            if e.use_stderr() {
+                eprintln!("{}", e.message);
+                std::process::exit(crate::info::EXIT_ERROR.into());
+            }
+            e.exit()
+        });
+        if let Some(input) = opt.input_arg.take() {
+            opt.input = input;
+        }
+        if let Some(length) = opt.length {
+            opt.min_length = length.min;
+            opt.max_length = length.max;
+        }
+        if let Some(pattern) = &opt.pattern {
+            opt.min_length = pattern.0.len() as u8;
+            opt.max_length = pattern.0.len() as u8;
+        }
+        if !opt.alphabet_arg.is_empty() {
+            opt.alphabet = Alphabet::union(&std::mem::take(&mut opt.alphabet_arg));
+            if let Some(exclude) = &opt.exclude {
+                opt.alphabet = opt.alphabet.without(exclude.as_bytes())?;
+            }
+            if let Some(path) = &opt.freq {
+                let chars = std::fs::read(path)
+                    .with_context(|| format!("Could not read the frequency file {:?}", path))?;
+                opt.alphabet = opt
+                    .alphabet
+                    .ordered_by(&chars)
+                    .with_context(|| format!("Invalid frequency file {:?}", path))?;
+            }
+        }
+        if opt.json && opt.scan.is_none() && !opt.show_zipfile_records {
+            bail!("--json requires --scan or --show-zipfile-records");
+        }
+        if opt.checkpoint_every == Some(0) {
+            bail!("--checkpoint-every must be at least 1");
+        }
+        if opt.tui && !cfg!(feature = "tui") {
+            bail!("--tui requires building with --features tui");
+        }
+        if opt.log_interval == 0 || opt.refresh_interval == 0 {
+            bail!("--log-interval and --refresh-interval must be greater than zero");
+        }
+        if opt.unroll && opt.suffix.is_some() {
+            bail!("--suffix can't be combined with --unroll, which varies the last character");
+        }
+        if let Some(corpus) = &opt.markov {
+            if opt.unroll || opt.prefix.is_some() || opt.suffix.is_some() {
+                bail!("--markov can't be combined with --unroll, --prefix or --suffix");
+            }
+            let model = MarkovModel::from_file(corpus, &opt.alphabet.0)
+                .with_context(|| format!("Could not read Markov corpus {:?}", corpus))?;
+            opt.markov_model = Some(Arc::new(model));
+        }
+        if let Some(index) = opt.resume_from_index {
+            opt.start_password = Some(opt.password_at_index(index)?);
+        }
+        opt.check_start_password()?;
+        Ok(opt)
+    }
+
+    /// The password at the --resume-from-index, which has to be within the keyspace
+    fn password_at_index(&self, index: u128) -> anyhow::Result<String> {
+        match index_to_password(self, index) {
+            Some(password) => Ok(password),
+            None => bail!(
+                "--resume-from-index {} is beyond the keyspace of {} passwords",
+                index,
+                keyspace_size(self)
+            ),
+        }
+    }
+
+    /// Whether an option needs the file data, not only the headers. Otherwise, the archive is
+    /// parsed like with --headers-only.
+    pub fn needs_file_data(&self) -> bool {
+        self.extract.is_some()
+            || self.extract_clear.is_some()
+            || self.magic.is_some()
+            || self.hashcat
+    }
+
+    /// Checks that the --start-password consists of letters from the alphabet. The letters of a
+    /// custom alphabet are sorted, so the enumeration order may differ from the typed order.
+    pub(crate) fn check_start_password(&self) -> anyhow::Result<()> {
+        let Some(start_password) = &self.start_password else {
+            return Ok(());
+        };
+        if self.incremental_charset {
+            bail!(
+                "--start-password can't be used with --incremental-charset, whose first alphabets \
+                 don't contain all the characters"
+            );
+        }
+        if let Some(c) = start_password
+            .chars()
+            .find(|&c| !c.is_ascii() || !self.alphabet.0.contains(&(c as u8)))
+        {
+            bail!(
+                "The start password contains {:?}, which is not in the alphabet. Passwords are \
+                 built from [{}], in this order",
+                c,
+                self.alphabet
+            );
+        }
+        Ok(())
+    }
 }
 
+/// The search options for library users, without the CLI-only ones. Defaults match the CLI.
 #[derive(Clone)]
+pub struct CrackOptions {
+    alphabet: Alphabet,
+    min_length: u8,
+    max_length: u8,
+    num_threads: u8,
+    start_password: Option<String>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+    unroll: bool,
+    magic: Option<Magic>,
+    logfile: PathBuf,
+    cancel: CancellationToken,
+}
+
+impl CrackOptions {
+    pub fn new(alphabet: Alphabet) -> Self {
+        Self {
+            alphabet,
+            min_length: 1,
+            max_length: 10,
+            num_threads: 1,
+            start_password: None,
+            prefix: None,
+            suffix: None,
+            unroll: false,
+            magic: None,
+            logfile: PathBuf::from("zipcrack_log.json"),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    pub fn min_length(mut self, min_length: u8) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    pub fn max_length(mut self, max_length: u8) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    pub fn threads(mut self, num_threads: u8) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    pub fn start_password(mut self, start_password: impl Into<String>) -> Self {
+        self.start_password = Some(start_password.into());
+        self
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.suffix = Some(suffix.into());
+        self
+    }
+
+    pub fn unroll(mut self, unroll: bool) -> Self {
+        self.unroll = unroll;
+        self
+    }
+
+    pub fn magic(mut self, magic: Magic) -> Self {
+        self.magic = Some(magic);
+        self
+    }
+
+    pub fn logfile(mut self, logfile: impl Into<PathBuf>) -> Self {
+        self.logfile = logfile.into();
+        self
+    }
+
+    /// Lets the caller stop the search from another thread
+    pub fn cancellation_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+}
+
+impl From<CrackOptions> for Opt {
+    fn from(options: CrackOptions) -> Self {
+        Opt {
+            alphabet_arg: Vec::new(),
+            alphabet: options.alphabet,
+            stdin_passwords: false,
+            try_common: false,
+            count_only: false,
+            sample: None,
+            seed: None,
+            estimate: false,
+            benchmark_alphabets: false,
+            exclude: None,
+            incremental_charset: false,
+            markov: None,
+            markov_threshold: 30,
+            markov_model: None,
+            freq: None,
+            previous_alphabet: None,
+            cancel: options.cancel,
+            input_arg: None,
+            input: PathBuf::new(),
+            raw_header: None,
+            from_json: None,
+            generate: None,
+            raw_time: None,
+            self_test: false,
+            scan: None,
+            json: false,
+            config: None,
+            logfile: options.logfile,
+            max_length: options.max_length,
+            min_length: options.min_length,
+            auto_extend: false,
+            max_length_cap: 16,
+            length: None,
+            start_password: options.start_password,
+            resume_from_index: None,
+            prefix: options.prefix,
+            suffix: options.suffix,
+            pattern: None,
+            serve: None,
+            connect: None,
+            chunk_size: 10_000_000_000,
+            file_glob: None,
+            hashcat: false,
+            independent_files: false,
+            show_zipfile_records: false,
+            validate: false,
+            strict: false,
+            max_false_positive_rate: 1.0,
+            per_thread_stats: false,
+            progress_bar: false,
+            tui: false,
+            color: ColorChoice::Auto,
+            json_summary: false,
+            output: None,
+            format: OutputFormat::Plain,
+            verbose: 0,
+            unroll: options.unroll,
+            log_interval: 60,
+            checkpoint_every: None,
+            refresh_interval: 100,
+            num_threads: options.num_threads,
+            pin_threads: false,
+            extract_clear: None,
+            headers_only: false,
+            extract: None,
+            min_rate: None,
+            magic: options.magic,
+            keys: None,
+            dump_keys: false,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Alphabet(pub Vec<u8>);
 
+impl Alphabet {
+    /// The alphabets that --alphabet accepts by name, with their names
+    pub fn presets() -> impl Iterator<Item = (&'static str, Alphabet)> {
+        ALPHABET_PRESETS
+            .iter()
+            .map(|&(name, chars)| (name, Alphabet(chars.to_vec())))
+    }
+
+    /// The characters that are in any of the alphabets
+    pub fn union(alphabets: &[Alphabet]) -> Self {
+        let mut chars: Vec<u8> = alphabets.iter().flat_map(|a| a.0.iter().copied()).collect();
+        chars.sort_unstable();
+        chars.dedup();
+        Self(chars)
+    }
+
+    /// Removes the given characters from the alphabet
+    pub fn without(&self, excluded: &[u8]) -> anyhow::Result<Self> {
+        let mut excluded = excluded.to_vec();
+        excluded.sort_unstable();
+        excluded.dedup();
+        let mut chars = self.0.clone();
+        chars.retain(|c| excluded.binary_search(c).is_err());
+        if chars.is_empty() {
+            bail!(
+                "Excluding {:?} leaves an empty alphabet",
+                String::from_utf8_lossy(&excluded)
+            );
+        }
+        Ok(Self(chars))
+    }
+
+    /// The alphabet in the order of the characters, e.g. from the most to the least frequent.
+    /// Line breaks are ignored, and the other characters must be exactly the alphabet's.
+    pub fn ordered_by(&self, chars: &[u8]) -> anyhow::Result<Self> {
+        let chars: Vec<u8> = chars
+            .iter()
+            .copied()
+            .filter(|&c| c != b'\n' && c != b'\r')
+            .collect();
+        let mut seen = [false; 256];
+        for &c in &chars {
+            if !self.0.contains(&c) {
+                bail!("{:?} is not in the alphabet", char::from(c));
+            }
+            if std::mem::replace(&mut seen[usize::from(c)], true) {
+                bail!("{:?} is listed more than once", char::from(c));
+            }
+        }
+        let missing: Vec<u8> = self
+            .0
+            .iter()
+            .copied()
+            .filter(|&c| !seen[usize::from(c)])
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "The characters {:?} of the alphabet are missing",
+                String::from_utf8_lossy(&missing)
+            );
+        }
+        Ok(Self(chars))
+    }
+}
+
+/// The character sets for --incremental-charset
+const INCREMENTAL_CHARSETS: &[&[u8]] = &[
+    b"abcdefghijklmnopqrstuvwxyz",
+    b"0123456789abcdefghijklmnopqrstuvwxyz",
+];
+
+impl Alphabet {
+    /// The sequence of growing alphabets for --incremental-charset, ending with this alphabet
+    pub fn incremental(&self) -> Vec<Alphabet> {
+        let mut alphabets: Vec<Alphabet> = Vec::new();
+        for charset in INCREMENTAL_CHARSETS {
+            let mut chars = self.0.clone();
+            chars.retain(|c| charset.contains(c));
+            let is_new = alphabets
+                .last()
+                .map_or(!chars.is_empty(), |last| last.0 != chars);
+            if is_new && chars != self.0 {
+                alphabets.push(Alphabet(chars));
+            }
+        }
+        alphabets.push(self.clone());
+        alphabets
+    }
+}
+
+impl std::fmt::Display for Alphabet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // The alphabet is always ASCII, but may contain control characters
+        write!(f, "{}", escape_control(&String::from_utf8_lossy(&self.0)))
+    }
+}
+
+/// The alphabets that --alphabet accepts by name
+const ALPHABET_PRESETS: &[(&str, &[u8])] = &[
+    (
+        "base64",
+        b"+/0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+    ),
+    ("lower", b"abcdefghijklmnopqrstuvwxyz"),
+    ("upper", b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"),
+    ("digits", b"0123456789"),
+];
+
 impl std::str::FromStr for Alphabet {
     type Err = &'static str;
 
@@ -54,24 +810,423 @@ impl std::str::FromStr for Alphabet {
         if !s.is_ascii() {
             return Err("Alphabet contains non-ASCII characters");
         }
-        let alphabet = match s {
-            "base64" => {
-                b"+/0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz".to_vec()
+        let mut parts = Vec::new();
+        let mut rest = s;
+        loop {
+            if let Some(custom) = rest.strip_prefix("custom:") {
+                if custom.is_empty() {
+                    return Err("Custom alphabet cannot be empty");
+                }
+                parts.push(Self(custom.as_bytes().to_vec()));
+                break;
             }
-            _ => {
-                if let Some(custom) = s.strip_prefix("custom:") {
-                    let mut chars = custom.as_bytes().to_vec();
-                    if chars.is_empty() {
-                        return Err("Custom alphabet cannot be empty");
-                    }
-                    chars.sort_unstable();
-                    chars.dedup();
-                    chars
-                } else {
-                    return Err("Invalid alphabet");
+            let (name, next) = match rest.split_once('+') {
+                Some((name, next)) => (name, Some(next)),
+                None => (rest, None),
+            };
+            if let Some(hex) = name.strip_prefix("hex:") {
+                let bytes = parse_hex(hex)?;
+                if bytes.is_empty() {
+                    return Err("Hex alphabet cannot be empty");
                 }
+                if !bytes.is_ascii() {
+                    return Err("Hex alphabet contains non-ASCII bytes");
+                }
+                parts.push(Self(bytes));
+            } else {
+                match ALPHABET_PRESETS.iter().find(|(preset, _)| *preset == name) {
+                    Some((_, chars)) => parts.push(Self(chars.to_vec())),
+                    None => return Err("Invalid alphabet"),
+                }
+            }
+            match next {
+                Some(next) => rest = next,
+                None => break,
+            }
+        }
+        Ok(Self::union(&parts))
+    }
+}
+
+/// Parses pairs of hex digits
+pub(crate) fn parse_hex(hex: &str) -> Result<Vec<u8>, &'static str> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err("Hex bytes must consist of pairs of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| "Invalid hex digit")
+}
+
+#[derive(Clone, Debug)]
+pub struct Magic(pub Vec<u8>);
+
+impl std::str::FromStr for Magic {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let magic = if let Some(hex) = s.strip_prefix("hex:") {
+            parse_hex(hex)?
+        } else {
+            s.as_bytes().to_vec()
+        };
+        if magic.is_empty() {
+            return Err("Magic cannot be empty");
+        }
+        Ok(Self(magic))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Whether TERM says that the terminal doesn't support colors or cursor movement
+pub fn is_dumb_terminal() -> bool {
+    std::env::var_os("TERM").is_some_and(|term| term == "dumb")
+}
+
+impl ColorChoice {
+    /// Whether to color the output on a stream, e.g. stdout()
+    pub fn use_color(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && stream.is_terminal()
+                    && !is_dumb_terminal()
             }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err("Color must be \"auto\", \"always\" or \"never\""),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Plain,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err("Format must be \"plain\", \"json\" or \"csv\""),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LengthRange {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl std::str::FromStr for LengthRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_length = |s: &str| s.trim().parse::<u8>().map_err(|_| "Invalid length");
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse_length(min)?, parse_length(max)?),
+            None => (parse_length(s)?, parse_length(s)?),
         };
-        Ok(Self(alphabet))
+        if min > max {
+            return Err("The minimum length must not be greater than the maximum length");
+        }
+        Ok(Self { min, max })
+    }
+}
+
+/// A password where some characters are unknown (None)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PasswordPattern(pub Vec<Option<u8>>);
+
+impl std::str::FromStr for PasswordPattern {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pattern = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'?' => pattern.push(None),
+                b'\\' => match bytes.next() {
+                    Some(escaped @ (b'?' | b'\\')) => pattern.push(Some(escaped)),
+                    _ => return Err("Only \"?\" and \"\\\" can be escaped with \"\\\""),
+                },
+                _ => pattern.push(Some(byte)),
+            }
+        }
+        if pattern.len() > usize::from(u8::MAX) {
+            return Err("The pattern must not be longer than 255 characters");
+        }
+        Ok(Self(pattern))
+    }
+}
+
+/// A 12-byte encryption header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawHeader(pub [u8; 12]);
+
+impl std::str::FromStr for RawHeader {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_hex(s)?;
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| "The encryption header must be 12 bytes long")?;
+        Ok(Self(bytes))
+    }
+}
+
+/// The three internal keys of ZipCrypto
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keys(pub [u32; 3]);
+
+impl std::str::FromStr for Keys {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|key| !key.is_empty())
+            .map(|key| u32::from_str_radix(key.trim_start_matches("0x"), 16))
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| "Invalid hex digit in keys")?;
+        let keys = keys.try_into().map_err(|_| "Expected three keys")?;
+        Ok(Self(keys))
+    }
+}
+
+/// The format of bkcrack, which FromStr also accepts
+impl std::fmt::Display for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [x, y, z] = self.0;
+        write!(f, "{:08x} {:08x} {:08x}", x, y, z)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_combined_alphabets() {
+        let alphabet: Alphabet = "lower+upper".parse().unwrap();
+        let expected: Vec<u8> = (b'A'..=b'Z').chain(b'a'..=b'z').collect();
+        assert_eq!(alphabet.0, expected);
+        let alphabet: Alphabet = "digits+custom:c+a+".parse().unwrap();
+        assert_eq!(alphabet.0, b"+0123456789ac");
+        assert!("lower+".parse::<Alphabet>().is_err());
+        assert!("lower+custom:".parse::<Alphabet>().is_err());
+        assert!("lower+hex".parse::<Alphabet>().is_err());
+        let alphabet: Alphabet = "hex:1f00+custom:\\a".parse().unwrap();
+        assert_eq!(alphabet.0, b"\0\x1f\\a");
+        assert_eq!(alphabet.to_string(), "\\x00\\x1f\\\\a");
+        assert!("hex:".parse::<Alphabet>().is_err());
+        assert!("hex:80".parse::<Alphabet>().is_err());
+
+        let opt =
+            Opt::from_iter_safe(["zipcrack", "x.zip", "-a", "digits", "-a", "custom:a1"]).unwrap();
+        assert_eq!(Alphabet::union(&opt.alphabet_arg).0, b"0123456789a");
+    }
+
+    #[test]
+    fn test_exclude() {
+        let alphabet: Alphabet = "custom:abcdef".parse().unwrap();
+        assert_eq!(alphabet.without(b"eaae").unwrap().0, b"bcdf");
+        assert!(alphabet.without(b"fedcba").is_err());
+    }
+
+    #[test]
+    fn test_ordered_by() {
+        let alphabet: Alphabet = "custom:abc".parse().unwrap();
+        assert_eq!(alphabet.ordered_by(b"ca\nb\n").unwrap().0, b"cab");
+        let e = alphabet.ordered_by(b"cab!").err().unwrap();
+        assert_eq!(e.to_string(), "'!' is not in the alphabet");
+        let e = alphabet.ordered_by(b"caa").err().unwrap();
+        assert_eq!(e.to_string(), "'a' is listed more than once");
+        let e = alphabet.ordered_by(b"c").err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "The characters \"ab\" of the alphabet are missing"
+        );
+    }
+
+    #[test]
+    fn test_incremental() {
+        let alphabet: Alphabet = "base64".parse().unwrap();
+        let alphabets: Vec<_> = alphabet.incremental().into_iter().map(|a| a.0).collect();
+        assert_eq!(
+            alphabets,
+            vec![
+                b"abcdefghijklmnopqrstuvwxyz".to_vec(),
+                b"0123456789abcdefghijklmnopqrstuvwxyz".to_vec(),
+                alphabet.0.clone()
+            ]
+        );
+        let alphabet: Alphabet = "custom:ab!".parse().unwrap();
+        let alphabets: Vec<_> = alphabet.incremental().into_iter().map(|a| a.0).collect();
+        assert_eq!(alphabets, vec![b"ab".to_vec(), b"!ab".to_vec()]);
+
+        // The first stages don't contain every start password, and the Markov search would be
+        // repeated for each stage
+        for other in [["--start-password", "A"], ["--markov", "corpus.txt"]] {
+            let args = ["zipcrack", "x.zip", "-a", "base64", "--incremental-charset"];
+            assert!(Opt::from_iter_safe(args.into_iter().chain(other)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_crack_options() {
+        let opt: Opt = CrackOptions::new("custom:xyz".parse().unwrap())
+            .max_length(4)
+            .threads(8)
+            .prefix("ab")
+            .into();
+        assert_eq!(opt.alphabet.0, b"xyz");
+        assert_eq!((opt.min_length, opt.max_length), (1, 4));
+        assert_eq!(opt.num_threads, 8);
+        assert_eq!(opt.prefix.as_deref(), Some("ab"));
+        assert!(!opt.unroll);
+    }
+
+    #[test]
+    fn test_password_at_index() {
+        let mut opt: Opt = CrackOptions::new("custom:abc".parse().unwrap())
+            .max_length(3)
+            .into();
+        assert_eq!(opt.password_at_index(4).unwrap(), "ab");
+        opt.start_password = Some(opt.password_at_index(38).unwrap());
+        assert_eq!(keyspace_size(&opt), 1);
+        opt.start_password = None;
+        let e = opt.password_at_index(39).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "--resume-from-index 39 is beyond the keyspace of 39 passwords"
+        );
+
+        let conflicting = Opt::from_iter_safe([
+            "zipcrack",
+            "x.zip",
+            "-a",
+            "lower",
+            "--resume-from-index",
+            "5",
+            "--start-password",
+            "a",
+        ]);
+        assert!(conflicting.is_err());
+    }
+
+    #[test]
+    fn test_needs_file_data() {
+        let mut opt: Opt = CrackOptions::new("lower".parse().unwrap()).into();
+        assert!(!opt.needs_file_data());
+        opt.magic = Some("%PDF".parse().unwrap());
+        assert!(opt.needs_file_data());
+    }
+
+    #[test]
+    fn test_markov_conflicts() {
+        // The Markov model has its own order, without positions or alphabet stages
+        for other in [
+            &["--start-password", "abc"][..],
+            &["--resume-from-index", "5"],
+            &["--incremental-charset"],
+        ] {
+            let args = ["zipcrack", "x.zip", "--markov", "corpus.txt"];
+            assert!(Opt::from_iter_safe(args.iter().chain(other)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_start_password() {
+        let mut opt: Opt = CrackOptions::new("custom:cba".parse().unwrap())
+            .start_password("cab")
+            .into();
+        assert_eq!(opt.alphabet.0, b"abc");
+        assert!(opt.check_start_password().is_ok());
+        opt.start_password = Some(String::from("cad"));
+        assert!(opt.check_start_password().is_err());
+        opt.start_password = Some(String::from("cä"));
+        assert!(opt.check_start_password().is_err());
+        opt.start_password = Some(String::from("cab"));
+        opt.incremental_charset = true;
+        assert!(opt.check_start_password().is_err());
+    }
+
+    #[test]
+    fn test_color_choice() {
+        assert_eq!("never".parse(), Ok(ColorChoice::Never));
+        assert!("sometimes".parse::<ColorChoice>().is_err());
+        assert!(ColorChoice::Always.use_color(&std::io::stdout()));
+        assert!(!ColorChoice::Never.use_color(&std::io::stdout()));
+    }
+
+    #[test]
+    fn test_length_range() {
+        assert_eq!(
+            "4-8".parse::<LengthRange>(),
+            Ok(LengthRange { min: 4, max: 8 })
+        );
+        assert_eq!(
+            "6".parse::<LengthRange>(),
+            Ok(LengthRange { min: 6, max: 6 })
+        );
+        assert!("8-4".parse::<LengthRange>().is_err());
+        assert!("4-".parse::<LengthRange>().is_err());
+        assert!("four".parse::<LengthRange>().is_err());
+    }
+
+    #[test]
+    fn test_raw_header() {
+        assert_eq!(
+            "93962919a5b71f814c7946c4".parse(),
+            Ok(RawHeader([
+                147, 150, 41, 25, 165, 183, 31, 129, 76, 121, 70, 196
+            ]))
+        );
+        assert!("939629".parse::<RawHeader>().is_err());
+        assert!("93962919a5b71f814c7946cx".parse::<RawHeader>().is_err());
+    }
+
+    #[test]
+    fn test_keys() {
+        let keys = Keys([0x8879dfed, 0x14335b6b, 0x8dc58b53]);
+        assert_eq!("8879dfed 14335b6b 8dc58b53".parse(), Ok(keys));
+        assert_eq!("0x8879dfed,0x14335b6b,0x8dc58b53".parse(), Ok(keys));
+        assert_eq!(
+            Keys([0x1, 0x14335b6b, 0x8dc58b53]).to_string(),
+            "00000001 14335b6b 8dc58b53"
+        );
+        assert_eq!(keys.to_string().parse(), Ok(keys));
+        assert!("8879dfed 14335b6b".parse::<Keys>().is_err());
+        assert!("8879dfed 14335b6b xyz".parse::<Keys>().is_err());
     }
 }
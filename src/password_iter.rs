@@ -1,21 +1,180 @@
+use crate::crack::{join_workers, spawn_worker, worker_cores};
 use crate::decrypt::*;
 use crate::info::*;
 use crate::opt::*;
 
+use std::io::{BufRead, BufWriter, Write};
+use std::ops::Range;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 
 pub type Password = [u8];
 pub type InitializedKeys = [u32; 3];
 
+/// How many passwords are enumerated between checks for cancellation
+const CANCELLATION_CHECK_INTERVAL: u32 = 1 << 16;
+
+/// The number of passwords with lengths in the given range. Saturates at u128::MAX.
+fn num_passwords(alphabet_len: usize, min_length: usize, max_length: usize) -> u128 {
+    let alphabet_len = alphabet_len as u128;
+    let mut num_passwords = alphabet_len.saturating_pow(min_length as u32);
+    let mut total: u128 = 0;
+    for _ in min_length..=max_length {
+        total = total.saturating_add(num_passwords);
+        num_passwords = num_passwords.saturating_mul(alphabet_len);
+    }
+    total
+}
+
+/// The lengths refer to the whole password, but only the part between prefix and suffix varies.
+/// Returns the minimum and maximum length of that part, or None if the prefix and suffix are
+/// already too long.
+fn varying_lengths(opt: &Opt) -> Option<(usize, usize)> {
+    let fixed_length =
+        opt.prefix.as_ref().map_or(0, String::len) + opt.suffix.as_ref().map_or(0, String::len);
+    let max_length = usize::from(opt.max_length).checked_sub(fixed_length)?;
+    let min_length = usize::from(opt.min_length).saturating_sub(fixed_length);
+    Some((min_length, max_length))
+}
+
+/// The varying part of the password at this position in the order of for_each_password(), which
+/// goes by length and then by the position of the characters in the alphabet. None if there are
+/// fewer passwords.
+pub fn index_to_password(opt: &Opt, mut index: u128) -> Option<String> {
+    let alphabet = &opt.alphabet.0;
+    let (min_length, max_length) = varying_lengths(opt)?;
+    let alphabet_len = alphabet.len() as u128;
+    let mut length = min_length;
+    loop {
+        if length > max_length {
+            return None;
+        }
+        match alphabet_len.checked_pow(length as u32) {
+            Some(count) if index >= count => index -= count,
+            _ => break,
+        }
+        length += 1;
+    }
+    let mut password = vec![alphabet[0]; length];
+    for c in password.iter_mut().rev() {
+        *c = alphabet[(index % alphabet_len) as usize];
+        index /= alphabet_len;
+    }
+    // The alphabet is always ASCII
+    Some(String::from_utf8(password).unwrap())
+}
+
+/// The position of the varying part of a password in the order of for_each_password(), the
+/// inverse of index_to_password(). None if it has a character that isn't in the alphabet or a
+/// length outside of the range. Saturates at u128::MAX.
+pub fn password_to_index(opt: &Opt, password: &str) -> Option<u128> {
+    let alphabet = &opt.alphabet.0;
+    let (min_length, max_length) = varying_lengths(opt)?;
+    let length = password.len();
+    if length < min_length || length > max_length {
+        return None;
+    }
+    let shorter = if length > min_length {
+        num_passwords(alphabet.len(), min_length, length - 1)
+    } else {
+        0
+    };
+    let mut index: u128 = 0;
+    for c in password.bytes() {
+        let position = alphabet.iter().position(|&letter| letter == c)?;
+        index = index
+            .saturating_mul(alphabet.len() as u128)
+            .saturating_add(position as u128);
+    }
+    Some(shorter.saturating_add(index))
+}
+
+/// The number of passwords for_each_password() enumerates, from the --start-password on if
+/// given. Saturates at u128::MAX.
+pub fn keyspace_size(opt: &Opt) -> u128 {
+    if let Some(pattern) = &opt.pattern {
+        let num_unknown = pattern.0.iter().filter(|c| c.is_none()).count();
+        return (opt.alphabet.0.len() as u128).saturating_pow(num_unknown as u32);
+    }
+    let Some((min_length, max_length)) = varying_lengths(opt) else {
+        return 0;
+    };
+    let total = num_passwords(opt.alphabet.0.len(), min_length, max_length);
+    match &opt.previous_alphabet {
+        // The passwords from the previous alphabet are skipped
+        Some(previous) if total != u128::MAX => {
+            total - num_passwords(previous.0.len(), min_length, max_length)
+        }
+        // So are the ones before the start password
+        None if total != u128::MAX => total.saturating_sub(start_index(opt)),
+        _ => total,
+    }
+}
+
+/// The index of the --start-password, or 0 without one
+pub fn start_index(opt: &Opt) -> u128 {
+    opt.start_password
+        .as_deref()
+        .and_then(|password| password_to_index(opt, password))
+        .unwrap_or(0)
+}
+
+/// A lookup table for whether a character is in the alphabet
+fn char_set(alphabet: &Alphabet) -> [bool; 256] {
+    let mut set = [false; 256];
+    for &c in &alphabet.0 {
+        set[usize::from(c)] = true;
+    }
+    set
+}
+
 /// Iterative password enumeration
-pub fn for_each_password(opt: Opt, idx: u8, mut callback: impl FnMut(&Password, InitializedKeys)) {
+pub fn for_each_password(opt: Opt, idx: u8, callback: impl FnMut(&Password, InitializedKeys)) {
+    match opt.pattern.clone() {
+        Some(pattern) => enumerate_pattern(&opt, &pattern, idx, callback),
+        None => enumerate_passwords(opt, idx, None, callback),
+    }
+}
+
+/// Enumerates the passwords with indices in the range, see index_to_password(). Like with
+/// for_each_password(), each thread only gets every opt.num_threads-th password.
+pub fn for_each_password_range(
+    mut opt: Opt,
+    idx: u8,
+    range: Range<u128>,
+    callback: impl FnMut(&Password, InitializedKeys),
+) {
+    if range.is_empty() {
+        return;
+    }
+    let Some(start_password) = index_to_password(&opt, range.start) else {
+        return;
+    };
+    opt.start_password = Some(start_password);
+    opt.previous_alphabet = None;
+    enumerate_passwords(opt, idx, Some(range.end - range.start), callback)
+}
+
+/// Enumerates from the start password on, stopping after count passwords (in total, not only
+/// the ones of this thread) if given
+fn enumerate_passwords(
+    opt: Opt,
+    idx: u8,
+    count: Option<u128>,
+    mut callback: impl FnMut(&Password, InitializedKeys),
+) {
     let alphabet = &opt.alphabet.0;
+    let prefix = opt.prefix.as_deref().unwrap_or_default().as_bytes();
+    let suffix = opt.suffix.as_deref().unwrap_or_default().as_bytes();
+    let fixed_length = prefix.len() + suffix.len();
+    let Some((min_length, max_length)) = varying_lengths(&opt) else {
+        return;
+    };
 
     let mut password = if let Some(pw) = opt.start_password.clone() {
         pw.into_bytes()
     } else {
-        vec![alphabet[0]; opt.min_length.into()]
+        vec![alphabet[0]; min_length]
     };
 
     // Password represented as indices into the alphabet. It's easier to find the next password
@@ -35,7 +194,8 @@ pub fn for_each_password(opt: Opt, idx: u8, mut callback: impl FnMut(&Password,
     // the keys after character i. This makes the key testing effort independent of key length.
     // initialized_keys[i] contains the keys after i characters.
     // Therefore, initialized_keys is 1 longer than password.
-    let mut initialized_keys = vec![[305419896u32, 591751049u32, 878082192u32]];
+    // The prefix never changes, so it's part of the initial keys.
+    let mut initialized_keys = vec![keys_for_password(prefix)];
 
     // TODO: Maybe this could be done more neatly with a struct that groups
     // password_idx, password, and initialized_keys
@@ -54,7 +214,7 @@ pub fn for_each_password(opt: Opt, idx: u8, mut callback: impl FnMut(&Password,
         let mut carry = usize::from(offset);
         while carry != 0 {
             if cursor == 0 {
-                if password.len() == usize::from(opt.max_length) {
+                if password.len() == max_length {
                     // We're done.
                     return true;
                 }
@@ -79,13 +239,39 @@ pub fn for_each_password(opt: Opt, idx: u8, mut callback: impl FnMut(&Password,
     // opt.num_threads and with offset idx from the origin,
     add_offset(&mut password_idx, &mut password, &mut initialized_keys, idx);
 
+    let already_tried = opt.previous_alphabet.as_ref().map(char_set);
+    let mut full_password = prefix.to_vec();
+    let mut since_cancellation_check = 0;
+    let mut position = u128::from(idx);
     loop {
+        if since_cancellation_check == 0 && opt.cancel.is_cancelled() {
+            break;
+        }
+        if count.is_some_and(|count| position >= count) {
+            break;
+        }
+        position += u128::from(opt.num_threads);
+        since_cancellation_check = (since_cancellation_check + 1) % CANCELLATION_CHECK_INTERVAL;
         while initialized_keys.len() <= password.len() {
             let i = initialized_keys.len();
             initialized_keys.push(initialized_keys[i - 1]);
             update_keys(&mut initialized_keys[i], password[i - 1]);
         }
-        callback(&password, initialized_keys[password.len()]);
+        if already_tried.is_some_and(|set| password.iter().all(|&c| set[usize::from(c)])) {
+            // Skip passwords that were already tried with a smaller alphabet
+        } else if fixed_length == 0 {
+            callback(&password, initialized_keys[password.len()]);
+        } else {
+            // The suffix follows the varying part, so it has to be applied every time.
+            let mut keys = initialized_keys[password.len()];
+            for &c in suffix {
+                update_keys(&mut keys, c);
+            }
+            full_password.truncate(prefix.len());
+            full_password.extend_from_slice(&password);
+            full_password.extend_from_slice(suffix);
+            callback(&full_password, keys);
+        }
         let finished = add_offset(
             &mut password_idx,
             &mut password,
@@ -98,6 +284,70 @@ pub fn for_each_password(opt: Opt, idx: u8, mut callback: impl FnMut(&Password,
     }
 }
 
+/// Enumerates the passwords that match the pattern, with the last unknown character changing
+/// fastest. The literals can be any byte, they don't need to be in the alphabet.
+fn enumerate_pattern(
+    opt: &Opt,
+    pattern: &PasswordPattern,
+    idx: u8,
+    mut callback: impl FnMut(&Password, InitializedKeys),
+) {
+    let alphabet = &opt.alphabet.0;
+    let unknown_positions: Vec<usize> = (0..pattern.0.len())
+        .filter(|&i| pattern.0[i].is_none())
+        .collect();
+    let mut password: Vec<u8> = pattern.0.iter().map(|c| c.unwrap_or(alphabet[0])).collect();
+    // The unknown characters as indices into the alphabet
+    let mut password_idx = vec![0; unknown_positions.len()];
+
+    // Like in enumerate_passwords(), initialized_keys[i] contains the keys after i characters.
+    // Only the keys from the first changed position on have to be recomputed, so literals after
+    // the last unknown character are the only ones that are applied every time.
+    let mut initialized_keys = vec![INITIAL_KEYS];
+
+    // Returns the first position in the password that changed, or None if we're done
+    let add_offset = |password_idx: &mut Vec<usize>, password: &mut Vec<u8>, offset: u8| {
+        let mut cursor = unknown_positions.len();
+        let mut changed = password.len();
+        let mut carry = usize::from(offset);
+        while carry != 0 {
+            if cursor == 0 {
+                return None;
+            }
+            cursor -= 1;
+            let idx = password_idx[cursor] + carry;
+            carry = idx / alphabet.len();
+            password_idx[cursor] = idx % alphabet.len();
+            changed = unknown_positions[cursor];
+            password[changed] = alphabet[password_idx[cursor]];
+        }
+        Some(changed)
+    };
+
+    // Each thread starts at offset idx and takes steps of opt.num_threads
+    let Some(mut changed) = add_offset(&mut password_idx, &mut password, idx) else {
+        return;
+    };
+    let mut since_cancellation_check = 0;
+    loop {
+        if since_cancellation_check == 0 && opt.cancel.is_cancelled() {
+            break;
+        }
+        since_cancellation_check = (since_cancellation_check + 1) % CANCELLATION_CHECK_INTERVAL;
+        initialized_keys.truncate(changed + 1);
+        while initialized_keys.len() <= password.len() {
+            let i = initialized_keys.len();
+            initialized_keys.push(initialized_keys[i - 1]);
+            update_keys(&mut initialized_keys[i], password[i - 1]);
+        }
+        callback(&password, initialized_keys[password.len()]);
+        match add_offset(&mut password_idx, &mut password, opt.num_threads) {
+            Some(first_changed) => changed = first_changed,
+            None => break,
+        }
+    }
+}
+
 /// Iterative password enumeration with the last character unrolled
 ///
 /// Note: This will not check the empty password
@@ -107,22 +357,41 @@ pub fn for_each_password_unrolled(mut opt: Opt, idx: u8, mut callback: impl FnMu
     }
     opt.min_length = opt.min_length.saturating_sub(1);
     opt.max_length = opt.max_length.saturating_sub(1);
-    let chunked_alphabet: Vec<_> = opt
-        .alphabet
-        .0
-        .chunks(8)
-        .map(|slice| {
-            // This will pad the last chunk to 8. Unwrap is safe since chunks() doesn't yield empty slices.
-            let mut arr = [*slice.last().unwrap(); 8];
-            arr[..slice.len()].clone_from_slice(slice);
-            arr
-        })
-        .collect();
+    let chunk = |alphabet: &[u8]| -> Vec<[u8; 8]> {
+        alphabet
+            .chunks(8)
+            .map(|slice| {
+                // This will pad the last chunk to 8. Unwrap is safe since chunks() doesn't yield empty slices.
+                let mut arr = [*slice.last().unwrap(); 8];
+                arr[..slice.len()].clone_from_slice(slice);
+                arr
+            })
+            .collect()
+    };
+    let chunked_alphabet = chunk(&opt.alphabet.0);
+
+    // If the prefix consists of characters that were already tried, only the new characters are
+    // left for the last letter. The for_each_password() call must not skip such prefixes itself.
+    let already_tried = opt.previous_alphabet.take().map(|previous| {
+        let set = char_set(&previous);
+        let mut new_chars = opt.alphabet.0.clone();
+        new_chars.retain(|&c| !set[usize::from(c)]);
+        (set, chunk(&new_chars))
+    });
+    let fixed_length = opt.prefix.as_ref().map_or(0, String::len);
 
     let callback_for_single_password = move |pw: &[u8], initialized_keys: InitializedKeys| {
+        let alphabet = match &already_tried {
+            Some((set, chunked_new_chars))
+                if pw[fixed_length..].iter().all(|&c| set[usize::from(c)]) =>
+            {
+                chunked_new_chars
+            }
+            _ => &chunked_alphabet,
+        };
         let password_block = PasswordBlock {
             password_prefix: pw,
-            alphabet: chunked_alphabet.as_slice(),
+            alphabet: alphabet.as_slice(),
             initialized_keys,
         };
         callback(password_block);
@@ -130,28 +399,173 @@ pub fn for_each_password_unrolled(mut opt: Opt, idx: u8, mut callback: impl FnMu
     for_each_password(opt, idx, callback_for_single_password);
 }
 
-/// Given a password validation function, tests each password
-pub fn test_each_password(
-    opt: Opt,
+/// From how many passwords on --generate warns about the size of the output
+pub const LARGE_KEYSPACE: u128 = 1_000_000_000;
+
+/// Up to how many passwords a search is over too quickly for the status display to show anything
+pub const TINY_KEYSPACE: u128 = 100_000;
+
+/// Writes the passwords that the search would try, one per line, and returns how many
+pub fn write_passwords(opt: &Opt, writer: impl Write) -> std::io::Result<u64> {
+    let mut opt = opt.clone();
+    opt.num_threads = 1;
+    opt.cancel = CancellationToken::new();
+    let cancel = opt.cancel.clone();
+    let mut writer = BufWriter::new(writer);
+    let mut result = Ok(());
+    let mut count = 0;
+    let callback = |pw: &Password, _: InitializedKeys| {
+        if result.is_ok() {
+            result = writer.write_all(pw).and_then(|_| writer.write_all(b"\n"));
+            if result.is_err() {
+                cancel.cancel();
+            }
+            count += 1;
+        }
+    };
+    match opt.markov_model.clone() {
+        Some(model) => model.for_each_password(&opt, 0, callback),
+        None => for_each_password(opt, 0, callback),
+    }
+    result?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Wraps the predicate in a callback that counts passwords and records the ones that match,
+/// for the status display. With a thread index, also counts the passwords of that thread.
+fn with_info(
     info_data: Arc<InfoData>,
-    idx: u8,
-    mut predicate: impl FnMut(InitializedKeys) -> bool,
-) {
-    let callback_with_info = move |pw: &[u8], initialized_keys: InitializedKeys| {
+    thread: Option<u8>,
+    mut checkpoints: Option<Checkpoints>,
+    mut predicate: impl FnMut(&Password, InitializedKeys) -> bool,
+) -> impl FnMut(&Password, InitializedKeys) {
+    move |pw: &[u8], initialized_keys: InitializedKeys| {
         let count = info_data.counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(checkpoints) = &mut checkpoints {
+            checkpoints.update(&info_data, count, 1);
+        }
+        if let Some(thread) = thread {
+            info_data.thread_counters[usize::from(thread)]
+                .0
+                .fetch_add(1, Ordering::Relaxed);
+        }
 
         // Once in a while, tell the info thread a recent password
-        if count % 100_000 == 0 {
+        if count.is_multiple_of(100_000) {
             let mut recent_password = info_data.recent_password.lock().unwrap();
             *recent_password = String::from_utf8_lossy(pw).into_owned();
         }
-        if predicate(initialized_keys) {
+        if predicate(pw, initialized_keys) {
             let s = String::from_utf8_lossy(pw);
             let mut found_passwords = info_data.found_passwords.lock().unwrap();
-            found_passwords.push(s.into_owned());
+            found_passwords.push(FoundPassword {
+                password: s.into_owned(),
+                keys: initialized_keys,
+            });
         }
-    };
-    for_each_password(opt, idx, callback_with_info);
+    }
+}
+
+/// Given a password validation function, tests each password
+pub fn test_each_password(
+    opt: Opt,
+    info_data: Arc<InfoData>,
+    idx: u8,
+    predicate: impl FnMut(&Password, InitializedKeys) -> bool,
+) {
+    let thread = opt.per_thread_stats.then_some(idx);
+    let callback_with_info = with_info(info_data, thread, Checkpoints::new(&opt), predicate);
+    match opt.markov_model.clone() {
+        Some(model) => model.for_each_password(&opt, idx, callback_with_info),
+        None => for_each_password(opt, idx, callback_with_info),
+    }
+}
+
+/// Given a password validation function, tests each password with an index in the range
+pub fn test_password_range(
+    opt: Opt,
+    info_data: Arc<InfoData>,
+    idx: u8,
+    range: Range<u128>,
+    predicate: impl FnMut(&Password, InitializedKeys) -> bool,
+) {
+    let thread = opt.per_thread_stats.then_some(idx);
+    let callback_with_info = with_info(info_data, thread, Checkpoints::new(&opt), predicate);
+    for_each_password_range(opt, idx, range, callback_with_info);
+}
+
+/// Given a password validation function, tests each password of the list on this thread
+pub fn test_password_list<'a>(
+    passwords: impl IntoIterator<Item = &'a str>,
+    info_data: Arc<InfoData>,
+    predicate: impl FnMut(&Password, InitializedKeys) -> bool,
+) {
+    let mut callback = with_info(info_data, None, None, predicate);
+    for password in passwords {
+        callback(password.as_bytes(), keys_for_password(password.as_bytes()));
+    }
+}
+
+/// How many lines from stdin are handed to a thread at once
+const STDIN_BATCH_SIZE: usize = 1024;
+
+/// Tests the passwords from the input, usually stdin, one per line. The lines are distributed
+/// round-robin to opt.num_threads threads in batches.
+pub fn test_stdin_passwords<P>(
+    opt: &Opt,
+    input: impl BufRead,
+    info_data: Arc<InfoData>,
+    predicate: P,
+) where
+    P: FnMut(&Password, InitializedKeys) -> bool + Clone + Send + 'static,
+{
+    let mut senders = Vec::new();
+    let mut threads = Vec::new();
+    for (idx, core_id) in (0..opt.num_threads).zip(worker_cores(opt)) {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<Vec<u8>>>(4);
+        let thread = opt.per_thread_stats.then_some(idx);
+        let checkpoints = Checkpoints::new(opt);
+        let mut callback = with_info(info_data.clone(), thread, checkpoints, predicate.clone());
+        threads.push(spawn_worker(opt.cancel.clone(), core_id, move || {
+            for batch in receiver {
+                for pw in batch {
+                    let keys = keys_for_password(&pw);
+                    callback(&pw, keys);
+                }
+            }
+        }));
+        senders.push(sender);
+    }
+
+    let mut batch = Vec::with_capacity(STDIN_BATCH_SIZE);
+    let mut thread_idx = 0;
+    for line in input.split(b'\n') {
+        if opt.cancel.is_cancelled() {
+            break;
+        }
+        let mut line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Error reading passwords from stdin: {}", e);
+                break;
+            }
+        };
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        batch.push(line);
+        if batch.len() == STDIN_BATCH_SIZE {
+            let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(STDIN_BATCH_SIZE));
+            // The thread only hangs up if it panicked, which the join below reports
+            let _ = senders[thread_idx].send(full_batch);
+            thread_idx = (thread_idx + 1) % senders.len();
+        }
+    }
+    let _ = senders[thread_idx].send(batch);
+    // Closing the channels ends the threads' loops
+    drop(senders);
+    join_workers(threads);
 }
 
 /// Given a password block validation function, tests each password
@@ -163,47 +577,94 @@ pub fn test_each_password_unrolled(
 ) {
     let block_counter = AtomicU64::new(0);
     let password_block_size = u64::try_from(opt.alphabet.0.len()).unwrap();
+    let thread = opt.per_thread_stats.then_some(usize::from(idx));
+    let mut checkpoints = Checkpoints::new(&opt);
     let callback_with_info = move |password_block: PasswordBlock| {
-        info_data
+        let count = info_data
             .counter
             .fetch_add(password_block_size, Ordering::Relaxed);
+        if let Some(checkpoints) = &mut checkpoints {
+            checkpoints.update(&info_data, count, password_block_size);
+        }
+        if let Some(thread) = thread {
+            info_data.thread_counters[thread]
+                .0
+                .fetch_add(password_block_size, Ordering::Relaxed);
+        }
         let block_counter_cur = block_counter.fetch_add(1, Ordering::Relaxed);
 
         // Once in a while, tell the info thread a recent password
         if block_counter_cur == 100_000 {
-            let mut recent_password = info_data.recent_password.lock().unwrap();
-            let last_password_prefix = password_block.password_prefix.to_vec();
-            *recent_password =
-                String::from_utf8(last_password_prefix).expect("Password is not valid UTF-8");
-            recent_password.push('-');
+            let mut last_password =
+                String::from_utf8_lossy(password_block.password_prefix).into_owned();
+            last_password.push('-');
+            *info_data.recent_password.lock().unwrap() = last_password;
             block_counter.store(0, Ordering::Relaxed);
         }
 
-        for pw in block_predicate(password_block) {
-            let s = String::from_utf8(pw).expect("Password is not valid UTF-8");
-            let mut found_passwords = info_data.found_passwords.lock().unwrap();
-            found_passwords.push(s);
+        let matches = block_predicate(password_block);
+        if matches.is_empty() {
+            return;
         }
+        let matches: Vec<_> = matches
+            .into_iter()
+            .map(|pw| {
+                let mut keys = password_block.initialized_keys;
+                update_keys(&mut keys, *pw.last().unwrap());
+                let s = String::from_utf8_lossy(&pw).into_owned();
+                FoundPassword { password: s, keys }
+            })
+            .collect();
+        // One lock for the whole block, since a single file lets many passwords through
+        info_data.found_passwords.lock().unwrap().extend(matches);
     };
     for_each_password_unrolled(opt, idx, callback_with_info);
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
 
-    fn test_opt() -> Opt {
-        Opt {
-            alphabet: Alphabet(b"abc".to_vec()),
-            input: std::path::PathBuf::new(),
-            logfile: std::path::PathBuf::new(),
-            max_length: 3,
-            min_length: 1,
-            num_threads: 1,
-            show_zipfile_records: false,
-            start_password: None,
-            unroll: false,
-        }
+    pub(crate) fn test_opt() -> Opt {
+        CrackOptions::new(Alphabet(b"abc".to_vec()))
+            .max_length(3)
+            .logfile("")
+            .into()
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let opt = test_opt();
+        opt.cancel.cancel();
+        let mut count = 0;
+        for_each_password(opt, 0, |_: &[u8], _: InitializedKeys| count += 1);
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_stdin_passwords_threads() {
+        let mut opt = test_opt();
+        opt.num_threads = 3;
+        let lines: Vec<String> = (0..3000).map(|i| format!("pw{}", i)).collect();
+        let input = lines.join("\n");
+        let info_data = InfoData::new();
+        let tested = Arc::new(std::sync::Mutex::new(vec![]));
+        let tested_clone = tested.clone();
+        let predicate = move |pw: &Password, _: InitializedKeys| {
+            tested_clone.lock().unwrap().push(pw.to_vec());
+            pw == b"pw2500"
+        };
+        test_stdin_passwords(&opt, input.as_bytes(), info_data.clone(), predicate);
+
+        let mut tested = tested.lock().unwrap().clone();
+        tested.sort();
+        let mut expected: Vec<Vec<u8>> = lines.into_iter().map(String::into_bytes).collect();
+        expected.sort();
+        assert_eq!(tested, expected);
+        assert_eq!(info_data.counter.load(Ordering::Relaxed), 3000);
+        let found_passwords = info_data.found_passwords.lock().unwrap();
+        assert_eq!(found_passwords.len(), 1);
+        assert_eq!(found_passwords[0].password, "pw2500");
     }
 
     #[test]
@@ -305,16 +766,216 @@ mod test {
         assert_eq!(passwords, expected_passwords);
     }
 
+    #[test]
+    fn test_keyspace_size() {
+        let mut opt = test_opt();
+        assert_eq!(keyspace_size(&opt), 3 + 9 + 27);
+        opt.min_length = 0;
+        assert_eq!(keyspace_size(&opt), 1 + 3 + 9 + 27);
+        opt.prefix = Some(String::from("ab"));
+        assert_eq!(keyspace_size(&opt), 1 + 3);
+        opt.previous_alphabet = Some(Alphabet(b"ab".to_vec()));
+        assert_eq!(keyspace_size(&opt), (1 + 3) - (1 + 2));
+        opt.max_length = 255;
+        opt.alphabet = Alphabet(b"0123456789".to_vec());
+        assert_eq!(keyspace_size(&opt), u128::MAX);
+    }
+
+    #[test]
+    fn test_password_to_index_round_trip() {
+        let mut rng = crate::crack::SplitMix64(0);
+        for _ in 0..20 {
+            let mut opt = test_opt();
+            let letters = b"0123456789abcdef";
+            opt.alphabet.0 = (0..=rng.next() % 4)
+                .map(|_| letters[(rng.next() % 16) as usize])
+                .collect();
+            opt.alphabet.0.sort_unstable();
+            opt.alphabet.0.dedup();
+            opt.min_length = (rng.next() % 3) as u8;
+            opt.max_length = opt.min_length + (rng.next() % 3) as u8;
+            let mut passwords = vec![];
+            for_each_password(opt.clone(), 0, |pw, _| {
+                passwords.push(String::from_utf8(pw.to_vec()).unwrap())
+            });
+            assert_eq!(passwords.len() as u128, keyspace_size(&opt));
+            for (index, password) in passwords.iter().enumerate() {
+                assert_eq!(
+                    index_to_password(&opt, index as u128).as_ref(),
+                    Some(password)
+                );
+                assert_eq!(password_to_index(&opt, password), Some(index as u128));
+            }
+        }
+    }
+
+    #[test]
+    fn test_frequency_order() {
+        let mut opt = test_opt();
+        opt.max_length = 2;
+        opt.alphabet = opt.alphabet.ordered_by(b"cab").unwrap();
+        let mut passwords = vec![];
+        for_each_password(opt.clone(), 0, |pw, _| {
+            passwords.push(String::from_utf8(pw.to_vec()).unwrap())
+        });
+        assert_eq!(
+            passwords,
+            ["c", "a", "b", "cc", "ca", "cb", "ac", "aa", "ab", "bc", "ba", "bb"]
+        );
+        assert_eq!(index_to_password(&opt, 4).unwrap(), "ca");
+        assert_eq!(password_to_index(&opt, "ca"), Some(4));
+    }
+
+    #[test]
+    fn test_write_passwords() {
+        let mut opt = test_opt();
+        opt.num_threads = 4;
+        opt.start_password = Some(String::from("cc"));
+        let mut written = Vec::new();
+        assert_eq!(write_passwords(&opt, &mut written).unwrap(), 28);
+        assert_eq!(keyspace_size(&opt), 28);
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.starts_with("cc\naaa\naab\n"));
+        assert!(written.ends_with("\nccc\n"));
+    }
+
+    #[test]
+    fn test_exact_length() {
+        let mut opt = test_opt();
+        opt.min_length = 2;
+        opt.max_length = 2;
+        let mut passwords = vec![];
+        for_each_password(opt, 0, |pw, _| passwords.push(pw.to_vec()));
+        assert_eq!(passwords.len(), 9);
+        assert_eq!(passwords.first().unwrap(), b"aa");
+        assert_eq!(passwords.last().unwrap(), b"cc");
+    }
+
+    #[test]
+    fn test_password_ranges() {
+        let mut opt = test_opt();
+        let mut all_passwords = vec![];
+        for_each_password(opt.clone(), 0, |pw, _| all_passwords.push(pw.to_vec()));
+        assert_eq!(index_to_password(&opt, 0).unwrap(), "a");
+        assert_eq!(index_to_password(&opt, 4).unwrap(), "ab");
+        assert_eq!(index_to_password(&opt, 38).unwrap(), "ccc");
+        assert_eq!(index_to_password(&opt, 39), None);
+        assert_eq!(password_to_index(&opt, "ab"), Some(4));
+        assert_eq!(password_to_index(&opt, "abcd"), None);
+        assert_eq!(password_to_index(&opt, "ad"), None);
+
+        let ranges = [0..5, 5..20, 20..39, 39..50];
+        for num_threads in [1, 2] {
+            opt.num_threads = num_threads;
+            for range in ranges.clone() {
+                let mut passwords = vec![];
+                for idx in 0..num_threads {
+                    for_each_password_range(opt.clone(), idx, range.clone(), |pw, _| {
+                        passwords.push(pw.to_vec())
+                    });
+                }
+                passwords.sort_by_key(|pw| (pw.len(), pw.clone()));
+                let expected =
+                    &all_passwords[range.start.min(39) as usize..range.end.min(39) as usize];
+                assert_eq!(passwords, expected);
+            }
+        }
+    }
+
     #[test]
     fn test_initialized_keys() {
         let mut opt = test_opt();
         opt.min_length = 0;
         for_each_password(opt, 0, |pw: &[u8], initialized_keys: [u32; 3]| {
-            let mut keys = [305419896u32, 591751049u32, 878082192u32];
-            for &c in pw {
-                update_keys(&mut keys, c);
+            assert_eq!(initialized_keys, keys_for_password(pw));
+        });
+    }
+
+    #[test]
+    fn test_prefix_and_suffix() {
+        let mut opt = test_opt();
+        opt.max_length = 4;
+        opt.prefix = Some(String::from("x"));
+        opt.suffix = Some(String::from("yz"));
+        let mut passwords = vec![];
+        for_each_password(opt, 0, |pw: &[u8], initialized_keys: [u32; 3]| {
+            assert_eq!(initialized_keys, keys_for_password(pw));
+            passwords.push(pw.to_vec());
+        });
+        let expected_passwords = vec![
+            b"xyz".to_vec(),
+            b"xayz".to_vec(),
+            b"xbyz".to_vec(),
+            b"xcyz".to_vec(),
+        ];
+        assert_eq!(passwords, expected_passwords);
+    }
+
+    #[test]
+    fn test_pattern() {
+        let mut opt = test_opt();
+        opt.pattern = Some("?x\\??".parse().unwrap());
+        assert_eq!(
+            opt.pattern.as_ref().unwrap().0,
+            vec![None, Some(b'x'), Some(b'?'), None]
+        );
+        assert_eq!(keyspace_size(&opt), 9);
+        let mut passwords = vec![];
+        for_each_password(opt.clone(), 0, |pw: &[u8], initialized_keys: [u32; 3]| {
+            assert_eq!(initialized_keys, keys_for_password(pw));
+            passwords.push(String::from_utf8(pw.to_vec()).unwrap());
+        });
+        assert_eq!(passwords[..4], ["ax?a", "ax?b", "ax?c", "bx?a"]);
+        assert_eq!(passwords.len(), 9);
+
+        let mut sharded = vec![];
+        opt.num_threads = 4;
+        for idx in 0..4 {
+            for_each_password(opt.clone(), idx, |pw: &[u8], _: InitializedKeys| {
+                sharded.push(String::from_utf8(pw.to_vec()).unwrap())
+            });
+        }
+        sharded.sort();
+        assert_eq!(sharded, passwords);
+    }
+
+    #[test]
+    fn test_previous_alphabet() {
+        let mut opt = test_opt();
+        opt.previous_alphabet = Some(Alphabet(b"a".to_vec()));
+        opt.max_length = 2;
+        let expected_passwords = vec![
+            b"b".to_vec(),
+            b"c".to_vec(),
+            b"ab".to_vec(),
+            b"ac".to_vec(),
+            b"ba".to_vec(),
+            b"bb".to_vec(),
+            b"bc".to_vec(),
+            b"ca".to_vec(),
+            b"cb".to_vec(),
+            b"cc".to_vec(),
+        ];
+
+        let mut passwords = vec![];
+        for_each_password(opt.clone(), 0, |pw: &[u8], _: InitializedKeys| {
+            passwords.push(pw.to_vec())
+        });
+        assert_eq!(passwords, expected_passwords);
+
+        passwords = vec![];
+        for_each_password_unrolled(opt, 0, |pb: PasswordBlock| {
+            for last_char_chunk in pb.alphabet {
+                for &last_char in last_char_chunk {
+                    let mut password = pb.password_prefix.to_vec();
+                    password.push(last_char);
+                    // Filter out duplicates from padding
+                    if passwords.last() != Some(&password) {
+                        passwords.push(password);
+                    }
+                }
             }
-            assert_eq!(initialized_keys, keys);
         });
+        assert_eq!(passwords, expected_passwords);
     }
 }
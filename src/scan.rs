@@ -0,0 +1,181 @@
+//! Finds the ZIP files in a directory tree and reports how they are encrypted, for --scan
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json as json;
+
+use crate::zipfile::{EncryptionMethod, ZipArchive};
+
+/// The signature of a local file header, which a ZIP file normally starts with. JARs, APKs and
+/// Office documents are ZIP files too, so the extension doesn't matter.
+const ZIP_SIGNATURE: &[u8; 4] = b"PK\x03\x04";
+
+#[derive(Debug)]
+pub struct ScanResult {
+    pub path: PathBuf,
+    pub num_files: usize,
+    pub num_encrypted: usize,
+    /// The encryption methods of the encrypted files, each only once
+    pub methods: BTreeSet<EncryptionMethod>,
+    /// Why the file could not be read or parsed
+    pub error: Option<String>,
+}
+
+impl ScanResult {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            num_files: 0,
+            num_encrypted: 0,
+            methods: BTreeSet::new(),
+            error: None,
+        }
+    }
+
+    fn method_text(&self) -> String {
+        if self.error.is_some() {
+            String::from("?")
+        } else if self.methods.is_empty() {
+            String::from("-")
+        } else {
+            let names: Vec<String> = self.methods.iter().map(|m| m.to_string()).collect();
+            names.join("+")
+        }
+    }
+}
+
+fn has_zip_signature(path: &Path) -> std::io::Result<bool> {
+    let mut signature = [0u8; 4];
+    match fs::File::open(path)?.read_exact(&mut signature) {
+        Ok(()) => Ok(&signature == ZIP_SIGNATURE),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn scan_file(path: &Path) -> ScanResult {
+    let mut result = ScanResult::new(path.to_path_buf());
+    let archive = fs::read(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| ZipArchive::parse(&bytes));
+    match archive {
+        Ok(archive) => {
+            for local_file in archive.local_files() {
+                result.num_files += 1;
+                if let Some(method) = local_file.local_file_header.encryption_method() {
+                    result.num_encrypted += 1;
+                    result.methods.insert(method);
+                }
+            }
+        }
+        Err(e) => result.error = Some(format!("{:#}", e)),
+    }
+    result
+}
+
+fn scan_into(dir: &Path, results: &mut Vec<ScanResult>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("Could not read directory {:?}", dir))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Could not read directory {:?}", dir))?;
+    entries.sort();
+    for path in entries {
+        // Not following symlinks, they could form a cycle
+        let file_type = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            if let Err(e) = scan_into(&path, results) {
+                let mut result = ScanResult::new(path);
+                result.error = Some(format!("{:#}", e));
+                results.push(result);
+            }
+        } else if file_type.is_file() {
+            match has_zip_signature(&path) {
+                Ok(true) => results.push(scan_file(&path)),
+                Ok(false) => (),
+                Err(e) => {
+                    let mut result = ScanResult::new(path);
+                    result.error = Some(e.to_string());
+                    results.push(result);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Walks the directory recursively and parses every file that starts with a ZIP signature
+pub fn scan(dir: &Path) -> Result<Vec<ScanResult>> {
+    let mut results = Vec::new();
+    scan_into(dir, &mut results)?;
+    Ok(results)
+}
+
+pub fn print_table(results: &[ScanResult]) {
+    println!("{:<10} {:>6} {:>9}  Path", "Method", "Files", "Encrypted");
+    for result in results {
+        print!(
+            "{:<10} {:>6} {:>9}  {}",
+            result.method_text(),
+            result.num_files,
+            result.num_encrypted,
+            result.path.display()
+        );
+        match &result.error {
+            Some(error) => println!(" ({})", error),
+            None => println!(),
+        }
+    }
+}
+
+pub fn to_json(results: &[ScanResult]) -> json::Value {
+    let values: Vec<json::Value> = results
+        .iter()
+        .map(|result| {
+            let methods: Vec<String> = result.methods.iter().map(|m| m.to_string()).collect();
+            json::json!({
+                "path": result.path.to_string_lossy(),
+                "files": result.num_files,
+                "encrypted_files": result.num_encrypted,
+                "methods": methods,
+                "error": result.error,
+            })
+        })
+        .collect();
+    json::Value::Array(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let dir = std::env::temp_dir().join(format!("zipcrack_scan_{}", std::process::id()));
+        let subdir = dir.join("sub");
+        fs::create_dir_all(&subdir).unwrap();
+        let encrypted = include_bytes!("../tests/data/encrypted.zip");
+        fs::write(subdir.join("app.jar"), encrypted).unwrap();
+        fs::write(dir.join("notes.txt"), b"not a zip file").unwrap();
+        fs::write(dir.join("tiny"), b"PK").unwrap();
+
+        let results = scan(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, subdir.join("app.jar"));
+        assert_eq!(results[0].num_files, 4);
+        assert_eq!(results[0].num_encrypted, 4);
+        assert_eq!(
+            results[0].methods.iter().copied().collect::<Vec<_>>(),
+            vec![EncryptionMethod::ZipCrypto]
+        );
+        assert_eq!(to_json(&results)[0]["methods"][0], "ZipCrypto");
+    }
+}
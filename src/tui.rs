@@ -0,0 +1,309 @@
+//! The --tui dashboard, an alternative to the status lines for long interactive runs
+
+use std::collections::VecDeque;
+use std::io::{stdout, Stdout};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::cursor::Show;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, List, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+
+use crate::info::{
+    escape_control, format_duration, log_to_file, password_rate, FoundPassword, InfoData,
+};
+use crate::opt::Opt;
+use crate::password_iter::keyspace_size;
+
+/// How many rate samples the graph keeps, more than fit on most terminals
+const RATE_HISTORY_LENGTH: usize = 512;
+
+/// What the dashboard shows, sampled from InfoData
+struct Dashboard {
+    elapsed: Duration,
+    /// The number of passwords tried in this run
+    tried: u64,
+    /// None if the number of passwords isn't known, e.g. with --stdin-passwords
+    keyspace: Option<u64>,
+    /// Passwords per second, oldest first
+    rate_history: VecDeque<u64>,
+    recent_password: String,
+    found_passwords: Vec<FoundPassword>,
+    log_error: Option<String>,
+}
+
+impl Dashboard {
+    fn rate(&self) -> f64 {
+        password_rate(self.tried, self.elapsed)
+    }
+
+    fn progress(&self) -> Option<f64> {
+        let keyspace = self.keyspace.filter(|&keyspace| keyspace > 0)?;
+        Some((self.tried as f64 / keyspace as f64).min(1.0))
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        let remaining = self.keyspace?.saturating_sub(self.tried);
+        let rate = self.rate();
+        (rate > 0.0)
+            .then(|| Duration::from_secs_f64((remaining as f64 / rate).min(u64::MAX as f64)))
+    }
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard) {
+    let [stats_area, progress_area, rate_area, found_area] = *Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(5),
+            Constraint::Length(3),
+            Constraint::Min(5),
+            Constraint::Length(8),
+        ])
+        .split(frame.size())
+    else {
+        unreachable!()
+    };
+
+    let eta = dashboard
+        .eta()
+        .map_or_else(|| String::from("-"), format_duration);
+    let mut stats = vec![
+        Line::from(format!(
+            "Speed: {:.2}M passwords/s, total: {}",
+            dashboard.rate() / 1_000_000.0,
+            dashboard.tried
+        )),
+        Line::from(format!(
+            "Elapsed: {}, ETA: {}",
+            format_duration(dashboard.elapsed),
+            eta
+        )),
+        Line::from(format!(
+            "Latest password: {}",
+            escape_control(&dashboard.recent_password)
+        )),
+    ];
+    if let Some(e) = &dashboard.log_error {
+        stats.push(Line::from(format!("Not logging: {}", e)));
+    }
+    let stats = Paragraph::new(stats).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("zipcrack (press q to stop)"),
+    );
+    frame.render_widget(stats, stats_area);
+
+    let progress_block = Block::default().borders(Borders::ALL).title("Keyspace");
+    match dashboard.progress() {
+        Some(progress) => {
+            let gauge = Gauge::default()
+                .block(progress_block)
+                .gauge_style(Style::default().fg(Color::Cyan))
+                .ratio(progress)
+                .label(format!("{:.2}%", progress * 100.0));
+            frame.render_widget(gauge, progress_area);
+        }
+        None => frame.render_widget(
+            Paragraph::new("unknown").block(progress_block),
+            progress_area,
+        ),
+    }
+
+    // Only the most recent samples fit
+    let width = usize::from(rate_area.width.saturating_sub(2));
+    let rates: Vec<u64> = dashboard.rate_history.iter().copied().collect();
+    let rates = &rates[rates.len().saturating_sub(width)..];
+    let sparkline = Sparkline::default()
+        .block(Block::default().borders(Borders::ALL).title("Passwords/s"))
+        .style(Style::default().fg(Color::Yellow))
+        .data(rates);
+    frame.render_widget(sparkline, rate_area);
+
+    // The most recent ones, like the status lines
+    let num_shown = usize::from(found_area.height.saturating_sub(2));
+    let found = &dashboard.found_passwords;
+    let items: Vec<String> = found[found.len().saturating_sub(num_shown)..]
+        .iter()
+        .map(|found| format!("{:?}", found.password))
+        .collect();
+    let list = List::new(items)
+        .style(Style::default().fg(Color::Green))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Found passwords ({})", found.len())),
+        );
+    frame.render_widget(list, found_area);
+}
+
+/// Whether q, Esc or Ctrl-C was pressed within the timeout. Raw mode turns Ctrl-C into a key
+/// press instead of a signal.
+fn quit_requested(timeout: Duration) -> std::io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !event::poll(remaining)? {
+            return Ok(false);
+        }
+        if let Event::Key(key) = event::read()? {
+            let is_ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.kind == KeyEventKind::Press
+                && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || is_ctrl_c)
+            {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+fn enter(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> std::io::Result<()> {
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+    terminal.hide_cursor()
+}
+
+fn leave(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> std::io::Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()
+}
+
+/// Leaves the dashboard from the signal handler, which exits the process before the dashboard
+/// thread can do it
+pub fn restore_terminal() {
+    let result = disable_raw_mode().and_then(|()| execute!(stdout(), LeaveAlternateScreen, Show));
+    if let Err(e) = result {
+        eprintln!("Could not restore the terminal: {}", e);
+    }
+}
+
+/// Shows the dashboard until the search stops. Pressing q cancels the search.
+fn run<B: Backend>(
+    opt: &Opt,
+    data: &InfoData,
+    terminal: &mut Terminal<B>,
+    mut stop_requested: impl FnMut(Duration) -> std::io::Result<bool>,
+) -> std::io::Result<Option<anyhow::Error>> {
+    let start_time = Instant::now();
+    let start_counter = data.counter.load(Ordering::Relaxed);
+    let mut last_counter = start_counter;
+    let mut last_sample_time = Instant::now();
+    let mut log_timer = Instant::now();
+    let mut log_error: Option<anyhow::Error> = None;
+    let keyspace = (!opt.stdin_passwords && opt.markov_model.is_none())
+        .then(|| u64::try_from(keyspace_size(opt)).unwrap_or(u64::MAX));
+    let mut rate_history = VecDeque::with_capacity(RATE_HISTORY_LENGTH);
+    loop {
+        if stop_requested(Duration::from_millis(opt.refresh_interval))? {
+            opt.cancel.cancel();
+        }
+        let cur_counter = data.counter.load(Ordering::Relaxed);
+        if cur_counter == last_counter {
+            break;
+        }
+        let rate = password_rate(cur_counter - last_counter, last_sample_time.elapsed());
+        last_counter = cur_counter;
+        last_sample_time = Instant::now();
+        if rate_history.len() == RATE_HISTORY_LENGTH {
+            rate_history.pop_front();
+        }
+        rate_history.push_back(rate as u64);
+
+        let found_passwords = data.found_passwords.lock().unwrap().clone();
+        let recent_password = data.recent_password.lock().unwrap().clone();
+        if log_error.is_none() && log_timer.elapsed() > Duration::from_secs(opt.log_interval) {
+            log_timer = Instant::now();
+            if let Err(e) = log_to_file(
+                &opt.logfile,
+                cur_counter,
+                &found_passwords,
+                &recent_password,
+            ) {
+                log_error = Some(e);
+            }
+        }
+        let dashboard = Dashboard {
+            elapsed: start_time.elapsed(),
+            tried: cur_counter - start_counter,
+            keyspace,
+            rate_history: rate_history.clone(),
+            recent_password,
+            found_passwords,
+            log_error: log_error.as_ref().map(|e| e.to_string()),
+        };
+        terminal.draw(|frame| draw(frame, &dashboard))?;
+    }
+    Ok(log_error)
+}
+
+pub fn spawn_tui_thread(opt: Opt, data: Arc<InfoData>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut terminal = match Terminal::new(CrosstermBackend::new(stdout())) {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                eprintln!("Could not start the dashboard: {}", e);
+                return;
+            }
+        };
+        let result =
+            enter(&mut terminal).and_then(|()| run(&opt, &data, &mut terminal, quit_requested));
+        if let Err(e) = leave(&mut terminal) {
+            eprintln!("Could not restore the terminal: {}", e);
+        }
+        match result {
+            Ok(Some(e)) => eprintln!("Error writing logfile, stopped logging: {}", e),
+            Ok(None) => (),
+            Err(e) => eprintln!("The dashboard failed: {}", e),
+        }
+        crate::display::final_stats(data);
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn test_draw() {
+        let dashboard = Dashboard {
+            elapsed: Duration::from_secs(10),
+            tried: 5_000_000,
+            keyspace: Some(20_000_000),
+            rate_history: [400_000, 500_000, 600_000].into_iter().collect(),
+            recent_password: String::from("abcd"),
+            found_passwords: vec![FoundPassword {
+                password: String::from("abc"),
+                keys: [0; 3],
+            }],
+            log_error: None,
+        };
+        assert_eq!(dashboard.eta(), Some(Duration::from_secs(30)));
+        let mut terminal = Terminal::new(TestBackend::new(60, 24)).unwrap();
+        terminal.draw(|frame| draw(frame, &dashboard)).unwrap();
+        let text: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(text.contains("Speed: 0.50M passwords/s"));
+        assert!(text.contains("ETA: 30s"));
+        assert!(text.contains("25.00%"));
+        assert!(text.contains("Found passwords (1)"));
+        assert!(text.contains("\"abc\""));
+    }
+}
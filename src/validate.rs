@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+
+use anyhow::bail;
+
+use crate::info::warn;
+use crate::opt::ColorChoice;
+use crate::zipfile::{CentralDirectoryFileHeader, EncryptionMethod, LocalFile, Record, ZipArchive};
+
+/// A disagreement between the local file headers, the central directory and the end of central
+/// directory record, or an encrypted file that can't be checked
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inconsistency {
+    FieldMismatch {
+        file_name: OsString,
+        field: &'static str,
+        local: u32,
+        central: u32,
+    },
+    MissingCentralDirectoryEntry {
+        file_name: OsString,
+    },
+    MissingLocalFile {
+        file_name: OsString,
+    },
+    MissingEncryptionHeader {
+        file_name: OsString,
+        compressed_size: u32,
+    },
+    EntryCountMismatch {
+        expected: u16,
+        found: usize,
+    },
+    UnparsedBytes {
+        count: usize,
+    },
+    Truncated {
+        record: &'static str,
+        offset: usize,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inconsistency::FieldMismatch {
+                file_name,
+                field,
+                local,
+                central,
+            } => write!(
+                f,
+                "{:?}: {} is {:#x} in the local header, but {:#x} in the central directory",
+                file_name, field, local, central
+            ),
+            Inconsistency::MissingCentralDirectoryEntry { file_name } => {
+                write!(f, "{:?}: no central directory entry", file_name)
+            }
+            Inconsistency::MissingLocalFile { file_name } => {
+                write!(f, "{:?}: no local file", file_name)
+            }
+            Inconsistency::MissingEncryptionHeader {
+                file_name,
+                compressed_size,
+            } => write!(
+                f,
+                "{:?}: encrypted, but its {} bytes can't hold the 12-byte encryption header",
+                file_name, compressed_size
+            ),
+            Inconsistency::EntryCountMismatch { expected, found } => write!(
+                f,
+                "The end of central directory record counts {} entries, but the central directory \
+                 has {}, the archive may be truncated",
+                expected, found
+            ),
+            Inconsistency::UnparsedBytes { count } => write!(
+                f,
+                "{} bytes don't belong to any record, the archive may be damaged",
+                count
+            ),
+            Inconsistency::Truncated { record, offset } => write!(
+                f,
+                "The archive ends in the middle of a {} at offset {}, it's probably truncated",
+                record, offset
+            ),
+        }
+    }
+}
+
+fn compare_fields(
+    local_file: &LocalFile,
+    cdfh: &CentralDirectoryFileHeader,
+    inconsistencies: &mut Vec<Inconsistency>,
+) {
+    let header = &local_file.local_file_header;
+    let mut compare = |field, local: u32, central: u32| {
+        if local != central {
+            inconsistencies.push(Inconsistency::FieldMismatch {
+                file_name: header.file_name.clone(),
+                field,
+                local,
+                central,
+            });
+        }
+    };
+    compare(
+        "compression method",
+        header.compression_method.into(),
+        cdfh.compression_method.into(),
+    );
+    compare(
+        "encryption flag",
+        header.is_encrypted().into(),
+        (cdfh.general_purpose_bit_flag & 1).into(),
+    );
+    // With a data descriptor, the local header doesn't contain the CRC and sizes
+    let (crc32, compressed_size, uncompressed_size) = match &local_file.data_descriptor {
+        Some(dd) => (dd.crc32, dd.compressed_size, dd.uncompressed_size),
+        None if header.has_data_descriptor() => return,
+        None => (
+            header.crc32,
+            header.compressed_size,
+            header.uncompressed_size,
+        ),
+    };
+    compare("CRC-32", crc32, cdfh.crc_32);
+    compare("compressed size", compressed_size, cdfh.compressed_size);
+    compare(
+        "uncompressed size",
+        uncompressed_size,
+        cdfh.uncompressed_size,
+    );
+}
+
+/// Matches local files to central directory entries by name and reports any disagreements
+pub fn validate(records: &[Record]) -> Vec<Inconsistency> {
+    let mut central_directory: HashMap<&OsStr, &CentralDirectoryFileHeader> = records
+        .iter()
+        .filter_map(|record| match record {
+            Record::CentralDirectory(cdfh) => Some((cdfh.file_name.as_os_str(), cdfh)),
+            _ => None,
+        })
+        .collect();
+    let mut inconsistencies = Vec::new();
+    for local_file in records.iter().filter_map(Record::get_local_file) {
+        let file_name = local_file.local_file_header.file_name.as_os_str();
+        match central_directory.remove(file_name) {
+            Some(cdfh) => compare_fields(local_file, cdfh, &mut inconsistencies),
+            None => inconsistencies.push(Inconsistency::MissingCentralDirectoryEntry {
+                file_name: file_name.to_owned(),
+            }),
+        }
+    }
+    let mut missing_local_files: Vec<_> = central_directory.into_keys().collect();
+    missing_local_files.sort();
+    inconsistencies.extend(missing_local_files.into_iter().map(|file_name| {
+        Inconsistency::MissingLocalFile {
+            file_name: file_name.to_owned(),
+        }
+    }));
+    inconsistencies
+}
+
+/// Reports the ZipCrypto-encrypted local files without an encryption header. The search skips
+/// them, since there is nothing to check passwords against.
+pub fn check_encryption_headers(records: &[Record]) -> Vec<Inconsistency> {
+    records
+        .iter()
+        .filter_map(Record::get_local_file)
+        .filter(|local_file| {
+            local_file.local_file_header.encryption_method() == Some(EncryptionMethod::ZipCrypto)
+                && local_file.encryption_header.is_none()
+        })
+        .map(|local_file| Inconsistency::MissingEncryptionHeader {
+            file_name: local_file.local_file_header.file_name.clone(),
+            compressed_size: local_file.local_file_header.compressed_size,
+        })
+        .collect()
+}
+
+/// Compares the number of entries in the end of central directory record to the number of parsed
+/// central directory entries
+pub fn check_entry_count(records: &[Record]) -> Option<Inconsistency> {
+    let expected = records.iter().find_map(|record| match record {
+        Record::EndOfCentralDirectory(eocd) => Some(eocd.cd_num_entries),
+        _ => None,
+    })?;
+    let found = records
+        .iter()
+        .filter(|record| matches!(record, Record::CentralDirectory(_)))
+        .count();
+    (usize::from(expected) != found)
+        .then_some(Inconsistency::EntryCountMismatch { expected, found })
+}
+
+/// The problems with the structure of the archive that are found without --validate
+pub fn check_structure(archive: &ZipArchive) -> Vec<Inconsistency> {
+    let mut inconsistencies = Vec::new();
+    if archive.unparsed_bytes() > 0 {
+        inconsistencies.push(Inconsistency::UnparsedBytes {
+            count: archive.unparsed_bytes(),
+        });
+    }
+    if let Some(truncated_record) = archive.truncated_record() {
+        inconsistencies.push(Inconsistency::Truncated {
+            record: truncated_record.name(),
+            offset: truncated_record.offset,
+        });
+    }
+    inconsistencies.extend(check_entry_count(archive.records()));
+    inconsistencies.extend(check_encryption_headers(archive.records()));
+    inconsistencies
+}
+
+/// Prints the inconsistencies as warnings, or fails with all of them if `fatal` is set, e.g. with
+/// --strict. The search works around them, but a forensic analysis may want to stop.
+pub fn report(
+    inconsistencies: &[Inconsistency],
+    fatal: bool,
+    color: ColorChoice,
+) -> anyhow::Result<()> {
+    if !fatal {
+        for inconsistency in inconsistencies {
+            warn(color, inconsistency);
+        }
+        return Ok(());
+    }
+    for inconsistency in inconsistencies {
+        eprintln!("{}", inconsistency);
+    }
+    if !inconsistencies.is_empty() {
+        bail!(
+            "Validation failed with {} inconsistencies",
+            inconsistencies.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+//! The entry point for running in the browser. Build with
+//! `cargo build --lib --target wasm32-unknown-unknown --no-default-features --features wasm`.
+
+use wasm_bindgen::prelude::*;
+
+use crate::crack::find_passwords;
+use crate::opt::{Alphabet, CrackOptions};
+use crate::zipfile::ZipArchive;
+
+/// Tries all passwords with the given alphabet and lengths on the archive and returns the ones that
+/// match. Runs on the calling thread, so keep the keyspace small.
+#[wasm_bindgen]
+pub fn crack_archive(
+    archive: &[u8],
+    alphabet: &str,
+    min_length: u8,
+    max_length: u8,
+) -> Result<Vec<String>, JsError> {
+    let archive = ZipArchive::parse(archive).map_err(|e| JsError::new(&e.to_string()))?;
+    if !archive.is_any_encrypted() {
+        return Err(JsError::new("The archive contains no encrypted files"));
+    }
+    let alphabet: Alphabet = alphabet.parse().map_err(JsError::new)?;
+    let opt = CrackOptions::new(alphabet)
+        .min_length(min_length)
+        .max_length(max_length)
+        .into();
+    Ok(find_passwords(opt, &archive)
+        .into_iter()
+        .map(|found| found.password)
+        .collect())
+}
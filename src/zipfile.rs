@@ -1,13 +1,11 @@
-use nom::branch::alt;
 use nom::bytes::complete::{tag, take};
-use nom::combinator::map;
+use nom::combinator::{map, opt};
 use nom::error::context;
-use nom::multi::many0;
-use nom::number::complete::{le_u16, le_u32};
+use nom::multi::length_data;
+use nom::number::complete::{le_u16, le_u32, le_u64, le_u8};
 use nom::sequence::tuple;
 use nom::IResult;
-use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStrExt;
+use std::ffi::OsString;
 
 // Good introduction:
 // https://games.greggman.com/game/zip-rant/
@@ -15,7 +13,7 @@ use std::os::unix::ffi::OsStrExt;
 // To see info about an archive:
 // unzip -Zv plaintext.zip
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LocalFileHeader {
     pub version_needed_to_extract: u16,
     pub general_purpose_bit_flag: u16,
@@ -27,12 +25,251 @@ pub struct LocalFileHeader {
     pub uncompressed_size: u32,
     pub file_name: OsString,
     pub extra_field: Vec<u8>,
+    pub extended_timestamp: Option<ExtendedTimestamp>,
+    pub ntfs_timestamp: Option<NtfsTimestamp>,
+    pub unix_owner: Option<UnixOwner>,
+    pub aes: Option<AesExtraField>,
+    /// The extra field entries with tags zipcrack doesn't know, as (tag, data)
+    pub unknown_extra_fields: Vec<(u16, Vec<u8>)>,
+}
+
+/// The compression method of WinZip's AES encryption, with the real one in the extra field
+pub const AES_COMPRESSION_METHOD: u16 = 99;
+pub const AES_TAG: u16 = 0x9901;
+
+/// The extra field of a file with WinZip's AES encryption
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AesExtraField {
+    /// 1 for AE-1, 2 for AE-2 (which doesn't store the CRC)
+    pub vendor_version: u16,
+    /// 1, 2 or 3 for 128-, 192- or 256-bit keys
+    pub strength: u8,
+    /// The compression method of the data before it was encrypted
+    pub compression_method: u16,
+}
+
+impl AesExtraField {
+    /// The length of the salt that the file data starts with
+    pub fn salt_length(&self) -> Option<usize> {
+        match self.strength {
+            1 => Some(8),
+            2 => Some(12),
+            3 => Some(16),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_aes_extra_field(input: &[u8]) -> IResult<&[u8], AesExtraField> {
+    let (input, vendor_version) = le_u16(input)?;
+    let (input, _) = tag("AE")(input)?;
+    let (input, (strength, compression_method)) = tuple((le_u8, le_u16))(input)?;
+    Ok((
+        input,
+        AesExtraField {
+            vendor_version,
+            strength,
+            compression_method,
+        },
+    ))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EncryptionMethod {
+    /// The traditional PKWARE encryption, which zipcrack can crack
+    ZipCrypto,
+    /// WinZip's AES encryption
+    Aes,
+    /// PKWARE's strong encryption
+    Strong,
+}
+
+impl std::fmt::Display for EncryptionMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Self::ZipCrypto => "ZipCrypto",
+            Self::Aes => "AES",
+            Self::Strong => "Strong",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 impl LocalFileHeader {
     pub fn is_encrypted(&self) -> bool {
         self.general_purpose_bit_flag & 1 == 1
     }
+
+    /// How the file is encrypted, or None if it isn't
+    pub fn encryption_method(&self) -> Option<EncryptionMethod> {
+        if !self.is_encrypted() {
+            None
+        } else if self.general_purpose_bit_flag & 0x40 != 0 {
+            Some(EncryptionMethod::Strong)
+        } else if self.compression_method == AES_COMPRESSION_METHOD || self.aes.is_some() {
+            Some(EncryptionMethod::Aes)
+        } else {
+            Some(EncryptionMethod::ZipCrypto)
+        }
+    }
+
+    /// Whether the CRC and sizes are stored in a data descriptor after the file data
+    pub fn has_data_descriptor(&self) -> bool {
+        self.general_purpose_bit_flag & 8 == 8
+    }
+}
+
+/// File names are raw bytes. Outside of Unix, they must be valid UTF-8 to be represented exactly.
+fn file_name_from_bytes(bytes: &[u8]) -> OsString {
+    #[cfg(unix)]
+    let file_name = <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes);
+    #[cfg(not(unix))]
+    let file_name = String::from_utf8_lossy(bytes).into_owned();
+    file_name.into()
+}
+
+pub fn parse_extra_field_entry(input: &[u8]) -> IResult<&[u8], (u16, &[u8])> {
+    tuple((le_u16, length_data(le_u16)))(input)
+}
+
+/// Walks the (tag, data) entries of an extra field. A malformed entry, e.g. with a size that
+/// exceeds the remaining bytes, ends the walk instead of failing the whole record.
+pub struct ExtraFieldEntries<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> ExtraFieldEntries<'a> {
+    pub fn new(extra_field: &'a [u8]) -> Self {
+        Self { rest: extra_field }
+    }
+
+    /// The bytes that are left, which are the malformed ones once the walk is over
+    pub fn rest(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+impl<'a> Iterator for ExtraFieldEntries<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (rest, entry) = parse_extra_field_entry(self.rest).ok()?;
+        self.rest = rest;
+        Some(entry)
+    }
+}
+
+/// Finds the data of the extra field entry with the given tag
+pub fn find_extra_field(extra_field: &[u8], tag: u16) -> Option<&[u8]> {
+    ExtraFieldEntries::new(extra_field)
+        .find(|&(entry_tag, _)| entry_tag == tag)
+        .map(|(_, data)| data)
+}
+
+pub const EXTENDED_TIMESTAMP_TAG: u16 = 0x5455;
+
+/// The Info-ZIP extended timestamp, with times in seconds since the Unix epoch
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedTimestamp {
+    pub mtime: Option<u32>,
+    pub atime: Option<u32>,
+    pub ctime: Option<u32>,
+}
+
+pub fn parse_extended_timestamp(input: &[u8]) -> IResult<&[u8], ExtendedTimestamp> {
+    let (mut input, flags) = le_u8(input)?;
+    let mut times = [None; 3];
+    for (bit, time) in times.iter_mut().enumerate() {
+        // The central directory sets the same flags as the local header, but only contains the
+        // mtime, so stop when the data runs out.
+        if flags & (1 << bit) != 0 && input.len() >= 4 {
+            let (rest, value) = le_u32(input)?;
+            *time = Some(value);
+            input = rest;
+        }
+    }
+    Ok((
+        input,
+        ExtendedTimestamp {
+            mtime: times[0],
+            atime: times[1],
+            ctime: times[2],
+        },
+    ))
+}
+
+pub const NTFS_TAG: u16 = 0x000a;
+
+/// The timestamps from the NTFS extra field, as FILETIMEs (100ns intervals since 1601-01-01)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NtfsTimestamp {
+    pub mtime: u64,
+    pub atime: u64,
+    pub ctime: u64,
+}
+
+pub fn parse_ntfs_timestamp(input: &[u8]) -> IResult<&[u8], NtfsTimestamp> {
+    // Skip the reserved bytes
+    let (mut input, _) = take(4usize)(input)?;
+    // The field consists of attributes, only attribute 1 (the timestamps) is defined
+    loop {
+        let (rest, (attribute_tag, data)) = parse_extra_field_entry(input)?;
+        input = rest;
+        if attribute_tag == 1 {
+            let (_, (mtime, atime, ctime)) = tuple((le_u64, le_u64, le_u64))(data)?;
+            return Ok((
+                input,
+                NtfsTimestamp {
+                    mtime,
+                    atime,
+                    ctime,
+                },
+            ));
+        }
+    }
+}
+
+pub const UNIX2_TAG: u16 = 0x7855;
+pub const UNIX3_TAG: u16 = 0x7875;
+
+/// The owner of the file from the Info-ZIP Unix extra fields
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnixOwner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// The "Unix2" field with 16-bit IDs. Only the local header contains them.
+pub fn parse_unix2_owner(input: &[u8]) -> IResult<&[u8], UnixOwner> {
+    map(tuple((le_u16, le_u16)), |(uid, gid)| UnixOwner {
+        uid: uid.into(),
+        gid: gid.into(),
+    })(input)
+}
+
+/// A little-endian ID of up to 4 bytes, preceded by its size
+fn parse_variable_size_id(input: &[u8]) -> IResult<&[u8], u32> {
+    let (input, id_bytes) = length_data(le_u8)(input)?;
+    if id_bytes.len() > 4 {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            id_bytes,
+            nom::error::ErrorKind::TooLarge,
+        )));
+    }
+    let id = id_bytes
+        .iter()
+        .rev()
+        .fold(0, |id, &byte| (id << 8) | u32::from(byte));
+    Ok((input, id))
+}
+
+/// The "Unix3" field with variable-size IDs
+pub fn parse_unix3_owner(input: &[u8]) -> IResult<&[u8], UnixOwner> {
+    let (input, _) = tag([1])(input)?;
+    map(
+        tuple((parse_variable_size_id, parse_variable_size_id)),
+        |(uid, gid)| UnixOwner { uid, gid },
+    )(input)
 }
 
 pub fn parse_local_file_header(input: &[u8]) -> IResult<&[u8], LocalFileHeader> {
@@ -44,21 +281,57 @@ pub fn parse_local_file_header(input: &[u8]) -> IResult<&[u8], LocalFileHeader>
     let (input, extra_field_length) = le_u16(input)?;
     let (input, file_name) = take(file_name_length)(input)?;
     let (input, extra_field) = take(extra_field_length)(input)?;
-    Ok((
-        input,
-        LocalFileHeader {
-            version_needed_to_extract: fields.0,
-            general_purpose_bit_flag: fields.1,
-            compression_method: fields.2,
-            last_mod_file_time: fields.3,
-            last_mod_file_date: fields.4,
-            crc32: fields.5,
-            compressed_size: fields.6,
-            uncompressed_size: fields.7,
-            file_name: OsStr::from_bytes(file_name).to_owned(),
-            extra_field: extra_field.to_vec(),
-        },
-    ))
+    let mut header = LocalFileHeader {
+        version_needed_to_extract: fields.0,
+        general_purpose_bit_flag: fields.1,
+        compression_method: fields.2,
+        last_mod_file_time: fields.3,
+        last_mod_file_date: fields.4,
+        crc32: fields.5,
+        compressed_size: fields.6,
+        uncompressed_size: fields.7,
+        file_name: file_name_from_bytes(file_name),
+        extra_field: extra_field.to_vec(),
+        extended_timestamp: None,
+        ntfs_timestamp: None,
+        unix_owner: None,
+        aes: None,
+        unknown_extra_fields: Vec::new(),
+    };
+    // Known entries that can't be parsed are ignored. If a tag occurs twice, the first one counts.
+    let mut unix2_owner = None;
+    for (tag, data) in ExtraFieldEntries::new(extra_field) {
+        match tag {
+            EXTENDED_TIMESTAMP_TAG => {
+                header.extended_timestamp = header
+                    .extended_timestamp
+                    .or_else(|| parse_extended_timestamp(data).ok().map(|(_, t)| t))
+            }
+            NTFS_TAG => {
+                header.ntfs_timestamp = header
+                    .ntfs_timestamp
+                    .or_else(|| parse_ntfs_timestamp(data).ok().map(|(_, t)| t))
+            }
+            UNIX3_TAG => {
+                header.unix_owner = header
+                    .unix_owner
+                    .or_else(|| parse_unix3_owner(data).ok().map(|(_, owner)| owner))
+            }
+            UNIX2_TAG => {
+                unix2_owner =
+                    unix2_owner.or_else(|| parse_unix2_owner(data).ok().map(|(_, owner)| owner))
+            }
+            AES_TAG => {
+                header.aes = header
+                    .aes
+                    .or_else(|| parse_aes_extra_field(data).ok().map(|(_, aes)| aes))
+            }
+            _ => header.unknown_extra_fields.push((tag, data.to_vec())),
+        }
+    }
+    // Unix3 supersedes Unix2, but some archivers write both
+    header.unix_owner = header.unix_owner.or(unix2_owner);
+    Ok((input, header))
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -76,7 +349,7 @@ pub fn parse_encryption_header(input: &[u8]) -> IResult<&[u8], EncryptionHeader>
     ))
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DataDescriptor {
     pub crc32: u32,
     pub compressed_size: u32,
@@ -96,7 +369,7 @@ pub fn parse_data_descriptor(input: &[u8]) -> IResult<&[u8], DataDescriptor> {
     ))
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LocalFile {
     pub local_file_header: LocalFileHeader,
     pub encryption_header: Option<EncryptionHeader>,
@@ -104,19 +377,132 @@ pub struct LocalFile {
     pub data_descriptor: Option<DataDescriptor>,
 }
 
+impl LocalFile {
+    /// The CRC-32 of the contents, from the data descriptor if there is one. None if it should be
+    /// in a data descriptor but there is none.
+    pub fn crc32(&self) -> Option<u32> {
+        match &self.data_descriptor {
+            Some(dd) => Some(dd.crc32),
+            None if self.local_file_header.has_data_descriptor() => None,
+            None => Some(self.local_file_header.crc32),
+        }
+    }
+
+    /// Like crc32(), the size of the contents
+    pub fn uncompressed_size(&self) -> Option<u32> {
+        match &self.data_descriptor {
+            Some(dd) => Some(dd.uncompressed_size),
+            None if self.local_file_header.has_data_descriptor() => None,
+            None => Some(self.local_file_header.uncompressed_size),
+        }
+    }
+}
+
+/// The signatures of the records that can follow a local file
+const NEXT_RECORD_SIGNATURES: [&[u8; 4]; 5] = [
+    b"PK\x03\x04",
+    b"PK\x06\x08",
+    b"PK\x01\x02",
+    b"PK\x05\x05",
+    b"PK\x05\x06",
+];
+
+/// A record that the archive ends in the middle of, e.g. because a download was cut off
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TruncatedRecord {
+    pub signature: [u8; 4],
+    pub offset: usize,
+}
+
+impl TruncatedRecord {
+    /// The kind of record, by its signature
+    pub fn name(&self) -> &'static str {
+        match &self.signature {
+            b"PK\x03\x04" => "local file",
+            b"PK\x06\x08" => "archive extra data record",
+            b"PK\x01\x02" => "central directory file header",
+            b"PK\x05\x05" => "digital signature",
+            _ => "end of central directory record",
+        }
+    }
+}
+
+/// Whether the input starts with the signature of a record that doesn't parse. The parser stops
+/// there, so this is a record that was cut off rather than one that follows a complete archive.
+fn starts_with_truncated_record(input: &[u8]) -> bool {
+    let parses = match input.get(..4) {
+        Some(b"PK\x03\x04") => parse_local_file_with(input, false).is_ok(),
+        Some(b"PK\x06\x08") => parse_archive_extra_data_record(input).is_ok(),
+        Some(b"PK\x01\x02") => parse_central_directory_file_header(input).is_ok(),
+        Some(b"PK\x05\x05") => parse_digital_signature(input).is_ok(),
+        Some(b"PK\x05\x06") => parse_end_of_central_directory_record(input).is_ok(),
+        _ => return false,
+    };
+    !parses
+}
+
+fn starts_with_record(input: &[u8]) -> bool {
+    NEXT_RECORD_SIGNATURES
+        .iter()
+        .any(|signature| input.starts_with(*signature))
+}
+
+/// Finds the size of a file that was written in streaming mode, which only has its sizes in the
+/// data descriptor after the data. The compressed data can contain a data descriptor signature
+/// too, so a signature only counts if the size in its descriptor is its offset and a record or
+/// the end of the input follows the descriptor.
+fn find_streamed_file_size(input: &[u8]) -> Option<usize> {
+    let signature = b"PK\x07\x08";
+    let mut offset = 0;
+    while let Some(position) = input[offset..]
+        .windows(signature.len())
+        .position(|window| window == signature)
+    {
+        offset += position;
+        if let Ok((rest, data_descriptor)) = parse_data_descriptor(&input[offset..]) {
+            let next_is_record = rest.is_empty() || starts_with_record(rest);
+            if usize::try_from(data_descriptor.compressed_size) == Ok(offset) && next_is_record {
+                return Some(offset);
+            }
+        }
+        offset += 1;
+    }
+    None
+}
+
 pub fn parse_local_file(input: &[u8]) -> IResult<&[u8], LocalFile> {
+    parse_local_file_with(input, true)
+}
+
+/// Like parse_local_file(), but without keep_file_data, the file data is skipped instead of
+/// copied and file_data is empty
+fn parse_local_file_with(input: &[u8], keep_file_data: bool) -> IResult<&[u8], LocalFile> {
     let (input, local_file_header) =
         context("Parsing local file header", parse_local_file_header)(input)?;
     let mut compressed_size = usize::try_from(local_file_header.compressed_size).unwrap();
-    let (input, encryption_header) = if local_file_header.is_encrypted() {
-        compressed_size -= 12;
-        map(parse_encryption_header, Some)(input)?
-    } else {
-        (input, None)
+    // In streaming mode, the sizes in the header are zero
+    let is_streamed = local_file_header.has_data_descriptor() && compressed_size == 0;
+    if is_streamed {
+        if let Some(size) = find_streamed_file_size(input) {
+            compressed_size = size;
+        }
+    }
+    let is_zip_crypto = local_file_header.encryption_method() == Some(EncryptionMethod::ZipCrypto);
+    // A broken archive can set the encryption flag on a file that is too small to contain the
+    // encryption header, see validate::check_encryption_headers()
+    let (input, encryption_header) = match compressed_size.checked_sub(12) {
+        Some(size) if is_zip_crypto => {
+            compressed_size = size;
+            map(parse_encryption_header, Some)(input)?
+        }
+        _ => (input, None),
     };
     let (input, file_data) = take(compressed_size)(input)?;
-    let (input, data_descriptor) = if local_file_header.is_encrypted() {
+    let (input, data_descriptor) = if local_file_header.has_data_descriptor() || is_streamed {
         context("Parsing data descriptor", map(parse_data_descriptor, Some))(input)?
+    } else if local_file_header.is_encrypted() {
+        // Some archivers write a data descriptor after encrypted files without setting bit 3
+        opt(parse_data_descriptor)(input)?
     } else {
         (input, None)
     };
@@ -125,7 +511,11 @@ pub fn parse_local_file(input: &[u8]) -> IResult<&[u8], LocalFile> {
         LocalFile {
             local_file_header,
             encryption_header,
-            file_data: file_data.to_vec(),
+            file_data: if keep_file_data {
+                file_data.to_vec()
+            } else {
+                Vec::new()
+            },
             data_descriptor,
         },
     ))
@@ -178,20 +568,40 @@ pub fn parse_central_directory_file_header(
             internal_file_attributes: fields.13,
             external_file_attributes: fields.14,
             relative_offset_of_local_header: fields.15,
-            file_name: OsStr::from_bytes(file_name).to_owned(),
+            file_name: file_name_from_bytes(file_name),
             extra_field: extra_field.to_vec(),
             file_comment: file_comment.to_vec(),
         },
     ))
 }
 
+#[derive(Debug)]
+pub struct ArchiveExtraData {
+    pub extra_field: Vec<u8>,
+}
+
+pub fn parse_archive_extra_data_record(input: &[u8]) -> IResult<&[u8], ArchiveExtraData> {
+    // APPNOTE.TXT 4.3.11, used for central directory encryption. Unlike in the other records,
+    // the length is 4 bytes.
+    let (input, _) = tag("PK\x06\x08")(input)?;
+    let (input, size) = le_u32(input)?;
+    let (input, extra_field) = take(size)(input)?;
+    Ok((
+        input,
+        ArchiveExtraData {
+            extra_field: extra_field.to_vec(),
+        },
+    ))
+}
+
 #[derive(Debug)]
 pub struct DigitalSignature {
-    signature_data: Vec<u8>,
+    pub signature_data: Vec<u8>,
 }
 
 pub fn parse_digital_signature(input: &[u8]) -> IResult<&[u8], DigitalSignature> {
-    let (input, _) = tag("PK\x07\x08")(input)?;
+    // APPNOTE.TXT 4.3.13. Not to be confused with the data descriptor signature PK\x07\x08.
+    let (input, _) = tag("PK\x05\x05")(input)?;
     let (input, size) = le_u16(input)?;
     let (input, signature_data) = take(size)(input)?;
     Ok((
@@ -219,6 +629,17 @@ pub struct EndOfCentralDirectoryRecord {
     pub zip_file_comment: Vec<u8>,
 }
 
+impl EndOfCentralDirectoryRecord {
+    /// The ZIP file comment as text, or None if there is none. The format doesn't specify an
+    /// encoding, so invalid UTF-8 is replaced.
+    pub fn comment_text(&self) -> Option<String> {
+        if self.zip_file_comment.is_empty() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.zip_file_comment).into_owned())
+    }
+}
+
 pub fn parse_end_of_central_directory_record(
     input: &[u8],
 ) -> IResult<&[u8], EndOfCentralDirectoryRecord> {
@@ -242,6 +663,7 @@ pub fn parse_end_of_central_directory_record(
 #[derive(Debug)]
 pub enum Record {
     LocalFile(LocalFile),
+    ArchiveExtraData(ArchiveExtraData),
     CentralDirectory(CentralDirectoryFileHeader),
     DigitalSignature(DigitalSignature),
     EndOfCentralDirectory(EndOfCentralDirectoryRecord),
@@ -258,42 +680,1002 @@ impl Record {
     }
 }
 
+/// The size of the end of central directory record without the comment
+const EOCD_MIN_SIZE: usize = 22;
+
+/// Finds the end of central directory record by scanning backwards from the end of the file. The
+/// comment can be up to 65535 bytes long and may itself contain the signature, so a candidate
+/// only counts if its comment extends exactly to the end of the file.
+pub fn find_end_of_central_directory(input: &[u8]) -> Option<usize> {
+    let last_candidate = input.len().checked_sub(EOCD_MIN_SIZE)?;
+    let first_candidate = last_candidate.saturating_sub(usize::from(u16::MAX));
+    (first_candidate..=last_candidate).rev().find(|&offset| {
+        let record = &input[offset..];
+        let comment_length = usize::from(u16::from_le_bytes([record[20], record[21]]));
+        record.starts_with(b"PK\x05\x06") && EOCD_MIN_SIZE + comment_length == record.len()
+    })
+}
+
+/// The length of the data before the ZIP data, e.g. the executable of a self-extracting archive.
+/// The offsets in the central directory are relative to the start of the ZIP data, so the length
+/// is the difference between where the central directory is and where it says it is.
+pub fn find_stub_length(input: &[u8]) -> Option<usize> {
+    let eocd_offset = find_end_of_central_directory(input)?;
+    let (_, eocd) = parse_end_of_central_directory_record(&input[eocd_offset..]).ok()?;
+    let cd_position = eocd_offset.checked_sub(usize::try_from(eocd.cd_size).ok()?)?;
+    cd_position.checked_sub(usize::try_from(eocd.cd_offset).ok()?)
+}
+
+/// Like parse(), but also returns the byte offset at which each record starts
+///
+/// The records are parsed in the order of APPNOTE.TXT 4.3.6: local files, an optional archive
+/// extra data record, the central directory, an optional digital signature and the end of central
+/// directory record. Parsing stops at the
+/// first record that doesn't fit.
+pub fn parse_with_offsets(input: &[u8]) -> IResult<&[u8], Vec<(usize, Record)>> {
+    let (remaining, (records, _, _)) = parse_records(input, true)?;
+    Ok((remaining, records))
+}
+
+/// The records with their offsets, the length of the stub before the first record and the number
+/// of bytes that were skipped to find the end of central directory record
+type ParsedRecords = (Vec<(usize, Record)>, usize, usize);
+
+/// Like parse_with_offsets(), but also returns the stub length and the number of skipped bytes.
+/// See parse_local_file_with() for keep_file_data.
+fn parse_records(input: &[u8], keep_file_data: bool) -> IResult<&[u8], ParsedRecords> {
+    // A self-extracting archive starts with the executable that extracts it
+    let stub_length = find_stub_length(input)
+        .filter(|&stub_length| {
+            !starts_with_record(input) && starts_with_record(&input[stub_length..])
+        })
+        .unwrap_or(0);
+    let mut remaining = &input[stub_length..];
+    let mut skipped = 0;
+    let mut records = Vec::new();
+    let offset = |remaining: &[u8]| input.len() - remaining.len();
+    while let Ok((rest, local_file)) = parse_local_file_with(remaining, keep_file_data) {
+        records.push((offset(remaining), Record::LocalFile(local_file)));
+        remaining = rest;
+    }
+    if let Ok((rest, extra_data)) = parse_archive_extra_data_record(remaining) {
+        records.push((offset(remaining), Record::ArchiveExtraData(extra_data)));
+        remaining = rest;
+    }
+    while let Ok((rest, cdfh)) = parse_central_directory_file_header(remaining) {
+        records.push((offset(remaining), Record::CentralDirectory(cdfh)));
+        remaining = rest;
+    }
+    if let Ok((rest, signature)) = parse_digital_signature(remaining) {
+        records.push((offset(remaining), Record::DigitalSignature(signature)));
+        remaining = rest;
+    }
+    if let Ok((rest, eocd)) = parse_end_of_central_directory_record(remaining) {
+        records.push((offset(remaining), Record::EndOfCentralDirectory(eocd)));
+        remaining = rest;
+    } else if let Some(eocd_offset) = find_end_of_central_directory(remaining) {
+        // Something we couldn't parse is in the way, but the end of the file is still readable
+        let eocd_input = &remaining[eocd_offset..];
+        let (rest, eocd) = parse_end_of_central_directory_record(eocd_input)?;
+        records.push((offset(eocd_input), Record::EndOfCentralDirectory(eocd)));
+        remaining = rest;
+        skipped = eocd_offset;
+    }
+    Ok((remaining, (records, stub_length, skipped)))
+}
+
 pub fn parse(input: &[u8]) -> IResult<&[u8], Vec<Record>> {
-    let parse_record = alt((
-        map(parse_local_file, Record::LocalFile),
-        map(
-            parse_central_directory_file_header,
-            Record::CentralDirectory,
-        ),
-        map(parse_digital_signature, Record::DigitalSignature),
-        map(
-            parse_end_of_central_directory_record,
-            Record::EndOfCentralDirectory,
-        ),
-    ));
-    let (input, records) = many0(parse_record)(input)?;
-    Ok((input, records))
+    let (input, records) = parse_with_offsets(input)?;
+    Ok((
+        input,
+        records.into_iter().map(|(_, record)| record).collect(),
+    ))
+}
+
+/// A parsed ZIP file
+#[derive(Debug)]
+pub struct ZipArchive {
+    records: Vec<Record>,
+    /// The byte offset at which each record starts
+    offsets: Vec<usize>,
+    /// The length of the data before the first record, e.g. the executable of a
+    /// self-extracting archive
+    stub_length: usize,
+    /// The number of bytes that don't belong to any record or the stub
+    unparsed_bytes: usize,
+    /// The record that the unparsed bytes at the end start with, if it's cut off
+    truncated_record: Option<TruncatedRecord>,
+}
+
+impl ZipArchive {
+    pub fn parse(input: &[u8]) -> anyhow::Result<Self> {
+        Self::parse_with(input, true)
+    }
+
+    /// Like parse(), but without copying the file data, which a search with only the encryption
+    /// headers doesn't need. The file_data of the local files is empty.
+    pub fn parse_headers_only(input: &[u8]) -> anyhow::Result<Self> {
+        Self::parse_with(input, false)
+    }
+
+    fn parse_with(input: &[u8], keep_file_data: bool) -> anyhow::Result<Self> {
+        let (rest, (records_with_offsets, stub_length, skipped)) =
+            parse_records(input, keep_file_data).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let (offsets, records) = records_with_offsets.into_iter().unzip();
+        let truncated_record = starts_with_truncated_record(rest).then(|| TruncatedRecord {
+            signature: rest[..4].try_into().unwrap(),
+            offset: input.len() - rest.len(),
+        });
+        Ok(Self {
+            records,
+            offsets,
+            stub_length,
+            unparsed_bytes: skipped + rest.len(),
+            truncated_record,
+        })
+    }
+
+    /// An archive with a single encrypted file that consists of only this encryption header, to
+    /// search for the password of a header that was extracted with other tools. The header ends
+    /// with the time, and without a data descriptor it is checked against the high word of the
+    /// CRC-32, so that is set to the time.
+    pub fn from_encryption_header(encryption_header: [u8; 12], last_mod_file_time: u16) -> Self {
+        let local_file = LocalFile {
+            local_file_header: LocalFileHeader {
+                version_needed_to_extract: 20,
+                general_purpose_bit_flag: 1,
+                compression_method: 0,
+                last_mod_file_time,
+                last_mod_file_date: 0,
+                crc32: u32::from(last_mod_file_time) << 16,
+                compressed_size: 12,
+                uncompressed_size: 0,
+                file_name: OsString::from("<raw header>"),
+                extra_field: Vec::new(),
+                extended_timestamp: None,
+                ntfs_timestamp: None,
+                unix_owner: None,
+                aes: None,
+                unknown_extra_fields: Vec::new(),
+            },
+            encryption_header: Some(EncryptionHeader {
+                bytes: encryption_header,
+            }),
+            file_data: Vec::new(),
+            data_descriptor: None,
+        };
+        Self::from_local_file(local_file)
+    }
+
+    /// An archive with only this file, e.g. to search for its password on its own
+    pub fn from_local_file(local_file: LocalFile) -> Self {
+        Self {
+            records: vec![Record::LocalFile(local_file)],
+            offsets: vec![0],
+            stub_length: 0,
+            unparsed_bytes: 0,
+            truncated_record: None,
+        }
+    }
+
+    pub fn stub_length(&self) -> usize {
+        self.stub_length
+    }
+
+    /// Where the local header of a central directory entry starts in the input. The offset in the
+    /// entry doesn't include the stub.
+    pub fn local_header_offset(&self, cdfh: &CentralDirectoryFileHeader) -> usize {
+        self.stub_length + cdfh.relative_offset_of_local_header as usize
+    }
+
+    /// The number of bytes that the parser skipped or left over at the end, which means that the
+    /// archive is damaged or that the parser doesn't understand it
+    pub fn unparsed_bytes(&self) -> usize {
+        self.unparsed_bytes
+    }
+
+    /// The record that the archive ends in the middle of, if any
+    pub fn truncated_record(&self) -> Option<TruncatedRecord> {
+        self.truncated_record
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn records_with_offsets(&self) -> impl Iterator<Item = (usize, &Record)> {
+        self.offsets.iter().copied().zip(&self.records)
+    }
+
+    pub fn local_files(&self) -> impl Iterator<Item = &LocalFile> {
+        self.records.iter().filter_map(Record::get_local_file)
+    }
+
+    pub fn encrypted_files(&self) -> impl Iterator<Item = &LocalFile> {
+        self.local_files()
+            .filter(|local_file| local_file.local_file_header.is_encrypted())
+    }
+
+    pub fn is_any_encrypted(&self) -> bool {
+        self.encrypted_files().next().is_some()
+    }
+
+    pub fn file_by_name(&self, name: &str) -> Option<&LocalFile> {
+        self.local_files()
+            .find(|local_file| local_file.local_file_header.file_name == name)
+    }
+
+    /// The ZIP file comment from the end of central directory record
+    pub fn comment(&self) -> Option<&[u8]> {
+        self.records.iter().find_map(|record| match record {
+            Record::EndOfCentralDirectory(eocd) => Some(eocd.zip_file_comment.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// The ZIP file comment as text, see EndOfCentralDirectoryRecord::comment_text()
+    pub fn comment_text(&self) -> Option<String> {
+        self.records.iter().find_map(|record| match record {
+            Record::EndOfCentralDirectory(eocd) => eocd.comment_text(),
+            _ => None,
+        })
+    }
 }
 
 // TODO: This is ugly, make a Display impl instead
-pub fn show_file(records: &[Record]) {
-    for record in records {
+pub fn show_file(archive: &ZipArchive) {
+    for (offset, record) in archive.records_with_offsets() {
+        println!("Offset: {:#x}", offset);
         match record {
             Record::LocalFile(local_file) => {
                 println!("Header: {:#?}", local_file.local_file_header);
+                let mut entries = ExtraFieldEntries::new(&local_file.local_file_header.extra_field);
+                entries.by_ref().for_each(drop);
+                if !entries.rest().is_empty() {
+                    println!(
+                        "Warning: Ignoring {} malformed bytes at the end of the extra field",
+                        entries.rest().len()
+                    );
+                }
                 println!("Encryption header: {:#?}", local_file.encryption_header);
                 println!("Data descriptor: {:#?}", local_file.data_descriptor);
             }
             Record::CentralDirectory(central_directory_file_header) => {
                 println!("{:#?}", central_directory_file_header);
             }
+            Record::ArchiveExtraData(extra_data) => {
+                println!("{:#?}", extra_data);
+            }
             Record::DigitalSignature(digital_signature) => {
                 println!("{:#?}", digital_signature);
             }
             Record::EndOfCentralDirectory(eocd) => {
                 println!("{:#?}", eocd);
+                if let Some(comment) = eocd.comment_text() {
+                    println!("Comment: {:?}", comment);
+                }
             }
         }
     }
     println!("==============================================");
 }
+
+/// The size of a local file header without the file name and extra field
+const LOCAL_FILE_HEADER_MIN_SIZE: usize = 30;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The records for --show-zipfile-records --json. For each encrypted file, this includes what a
+/// password search or a known-plaintext attack needs: the encryption header, where it starts and
+/// the time whose bytes the header ends with.
+pub fn records_to_json(archive: &ZipArchive) -> serde_json::Value {
+    use serde_json::json;
+    let records: Vec<serde_json::Value> = archive
+        .records_with_offsets()
+        .map(|(offset, record)| match record {
+            Record::LocalFile(local_file) => {
+                let header = &local_file.local_file_header;
+                let encryption_header = local_file.encryption_header.map(|encryption_header| {
+                    let header_size = LOCAL_FILE_HEADER_MIN_SIZE
+                        + header.file_name.len()
+                        + header.extra_field.len();
+                    json!({
+                        "offset": offset + header_size,
+                        "bytes": hex(&encryption_header.bytes),
+                        "last_mod_file_time": header.last_mod_file_time,
+                    })
+                });
+                json!({
+                    "type": "local_file",
+                    "offset": offset,
+                    "file_name": header.file_name.to_string_lossy(),
+                    "general_purpose_bit_flag": header.general_purpose_bit_flag,
+                    "compression_method": header.compression_method,
+                    "crc32": header.crc32,
+                    "compressed_size": header.compressed_size,
+                    "uncompressed_size": header.uncompressed_size,
+                    "encryption_method": header.encryption_method().map(|m| m.to_string()),
+                    "encryption_header": encryption_header,
+                })
+            }
+            Record::CentralDirectory(cdfh) => json!({
+                "type": "central_directory",
+                "offset": offset,
+                "file_name": cdfh.file_name.to_string_lossy(),
+                "local_header_offset": archive.local_header_offset(cdfh),
+            }),
+            Record::ArchiveExtraData(extra_data) => json!({
+                "type": "archive_extra_data",
+                "offset": offset,
+                "size": extra_data.extra_field.len(),
+            }),
+            Record::DigitalSignature(signature) => json!({
+                "type": "digital_signature",
+                "offset": offset,
+                "size": signature.signature_data.len(),
+            }),
+            Record::EndOfCentralDirectory(eocd) => json!({
+                "type": "end_of_central_directory",
+                "offset": offset,
+                "entries": eocd.cd_num_entries,
+                "cd_size": eocd.cd_size,
+                "cd_offset": eocd.cd_offset,
+                "comment": eocd.comment_text(),
+            }),
+        })
+        .collect();
+    json!({
+        "stub_length": archive.stub_length(),
+        "unparsed_bytes": archive.unparsed_bytes(),
+        "records": records,
+    })
+}
+
+/// Rebuilds the local files from the JSON of records_to_json(), for --from-json. The files have
+/// no data, only the headers and encryption headers, which is all that the search checks.
+pub fn archive_from_json(value: &serde_json::Value) -> anyhow::Result<ZipArchive> {
+    use anyhow::Context;
+    let records = value["records"]
+        .as_array()
+        .context("Missing the \"records\" array")?;
+    let mut local_files = Vec::new();
+    let mut offsets = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        if record["type"] != "local_file" {
+            continue;
+        }
+        let local_file =
+            local_file_from_json(record).with_context(|| format!("Invalid record {}", i))?;
+        local_files.push(Record::LocalFile(local_file));
+        offsets
+            .push(json_number(record, "offset").with_context(|| format!("Invalid record {}", i))?);
+    }
+    if local_files.is_empty() {
+        anyhow::bail!("There are no local file records");
+    }
+    Ok(ZipArchive {
+        records: local_files,
+        offsets,
+        stub_length: 0,
+        unparsed_bytes: 0,
+        truncated_record: None,
+    })
+}
+
+/// A number field of a JSON object, which must fit into T
+fn json_number<T: TryFrom<u64>>(object: &serde_json::Value, name: &str) -> anyhow::Result<T> {
+    let value = object
+        .get(name)
+        .ok_or_else(|| anyhow::anyhow!("Missing the field {:?}", name))?;
+    value
+        .as_u64()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| anyhow::anyhow!("The field {:?} has an invalid value {}", name, value))
+}
+
+fn local_file_from_json(record: &serde_json::Value) -> anyhow::Result<LocalFile> {
+    let file_name = record["file_name"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Missing the field \"file_name\""))?;
+    let (encryption_header, last_mod_file_time) = match record.get("encryption_header") {
+        None => anyhow::bail!("Missing the field \"encryption_header\""),
+        Some(serde_json::Value::Null) => (None, 0),
+        Some(encryption_header) => {
+            let bytes = encryption_header["bytes"].as_str().ok_or_else(|| {
+                anyhow::anyhow!("Missing the field \"bytes\" of the encryption header")
+            })?;
+            let bytes: [u8; 12] = crate::opt::parse_hex(bytes)
+                .map_err(anyhow::Error::msg)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("The encryption header must be 12 bytes long"))?;
+            let last_mod_file_time = json_number(encryption_header, "last_mod_file_time")?;
+            (Some(EncryptionHeader { bytes }), last_mod_file_time)
+        }
+    };
+    Ok(LocalFile {
+        local_file_header: LocalFileHeader {
+            version_needed_to_extract: 20,
+            general_purpose_bit_flag: json_number(record, "general_purpose_bit_flag")?,
+            compression_method: json_number(record, "compression_method")?,
+            last_mod_file_time,
+            last_mod_file_date: 0,
+            crc32: json_number(record, "crc32")?,
+            compressed_size: json_number(record, "compressed_size")?,
+            uncompressed_size: json_number(record, "uncompressed_size")?,
+            file_name: OsString::from(file_name),
+            extra_field: Vec::new(),
+            extended_timestamp: None,
+            ntfs_timestamp: None,
+            unix_owner: None,
+            aes: None,
+            unknown_extra_fields: Vec::new(),
+        },
+        encryption_header,
+        file_data: Vec::new(),
+        data_descriptor: None,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// The bytes of a local file, for building archives in tests. The default is an unencrypted,
+    /// stored file without extra fields. With bit 3 in the flags, the CRC-32 is in a data
+    /// descriptor after the data instead of in the header.
+    pub(crate) struct LocalFileBytes<'a> {
+        pub version: u16,
+        pub flags: u16,
+        pub compression_method: u16,
+        pub last_mod_file_time: u16,
+        pub crc32: u32,
+        /// The length of the data if None
+        pub uncompressed_size: Option<u32>,
+        pub file_name: &'a str,
+        pub extra_field: &'a [u8],
+        pub data: &'a [u8],
+    }
+
+    impl Default for LocalFileBytes<'_> {
+        fn default() -> Self {
+            Self {
+                version: 20,
+                flags: 0,
+                compression_method: 0,
+                last_mod_file_time: 0,
+                crc32: 0,
+                uncompressed_size: None,
+                file_name: "a.txt",
+                extra_field: &[],
+                data: &[],
+            }
+        }
+    }
+
+    impl LocalFileBytes<'_> {
+        pub(crate) fn to_bytes(&self) -> Vec<u8> {
+            let has_data_descriptor = self.flags & 8 == 8;
+            let compressed_size = self.data.len() as u32;
+            let uncompressed_size = self.uncompressed_size.unwrap_or(compressed_size);
+            let mut bytes = b"PK\x03\x04".to_vec();
+            bytes.extend(self.version.to_le_bytes());
+            bytes.extend(self.flags.to_le_bytes());
+            bytes.extend(self.compression_method.to_le_bytes());
+            bytes.extend(self.last_mod_file_time.to_le_bytes());
+            // Date
+            bytes.extend([0, 0]);
+            let header_crc32 = if has_data_descriptor { 0 } else { self.crc32 };
+            bytes.extend(header_crc32.to_le_bytes());
+            bytes.extend(compressed_size.to_le_bytes());
+            bytes.extend(uncompressed_size.to_le_bytes());
+            bytes.extend((self.file_name.len() as u16).to_le_bytes());
+            bytes.extend((self.extra_field.len() as u16).to_le_bytes());
+            bytes.extend(self.file_name.as_bytes());
+            bytes.extend(self.extra_field);
+            bytes.extend(self.data);
+            if has_data_descriptor {
+                bytes.extend(b"PK\x07\x08");
+                bytes.extend(self.crc32.to_le_bytes());
+                bytes.extend(compressed_size.to_le_bytes());
+                bytes.extend(uncompressed_size.to_le_bytes());
+            }
+            bytes
+        }
+    }
+
+    /// An unencrypted, stored local file without extra fields
+    fn local_file_bytes(file_name: &str, data: &[u8]) -> Vec<u8> {
+        LocalFileBytes {
+            file_name,
+            data,
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    fn end_of_central_directory_bytes(comment: &[u8]) -> Vec<u8> {
+        let mut bytes = b"PK\x05\x06".to_vec();
+        bytes.extend([0; 16]);
+        bytes.extend((comment.len() as u16).to_le_bytes());
+        bytes.extend(comment);
+        bytes
+    }
+
+    fn central_directory_bytes(file_name: &str) -> Vec<u8> {
+        let mut bytes = b"PK\x01\x02".to_vec();
+        // Versions, flags, compression method, time, date, CRC-32 and sizes
+        bytes.extend([0; 24]);
+        bytes.extend((file_name.len() as u16).to_le_bytes());
+        // Extra field and comment lengths, disk number, attributes, offset
+        bytes.extend([0; 16]);
+        bytes.extend(file_name.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_extra_field_entries() {
+        // An unknown entry, then Unix2 with uid 1000 and gid 100
+        let mut extra_field = vec![
+            0x34, 0x12, 0x02, 0x00, 0xaa, 0xbb, 0x55, 0x78, 0x04, 0x00, 0xe8, 0x03, 0x64, 0x00,
+        ];
+        // An entry whose size exceeds the remaining bytes
+        extra_field.extend([0x01, 0x00, 0x10, 0x00, 0x00]);
+        let mut entries = ExtraFieldEntries::new(&extra_field);
+        assert_eq!(entries.next(), Some((0x1234, &[0xaa, 0xbb][..])));
+        assert_eq!(entries.next().unwrap().0, UNIX2_TAG);
+        assert_eq!(entries.next(), None);
+        assert_eq!(entries.rest().len(), 5);
+
+        let input = LocalFileBytes {
+            file_name: "a",
+            extra_field: &extra_field,
+            ..Default::default()
+        }
+        .to_bytes();
+        let (_, header) = parse_local_file_header(&input).unwrap();
+        assert_eq!(
+            header.unix_owner,
+            Some(UnixOwner {
+                uid: 1000,
+                gid: 100
+            })
+        );
+        assert_eq!(
+            header.unknown_extra_fields,
+            vec![(0x1234, vec![0xaa, 0xbb])]
+        );
+    }
+
+    #[test]
+    fn test_entry_count() {
+        let mut input = local_file_bytes("a.txt", b"hello");
+        input.extend(central_directory_bytes("a.txt"));
+        let mut eocd = end_of_central_directory_bytes(b"");
+        eocd[8..12].copy_from_slice(&[1, 0, 1, 0]);
+        let archive = ZipArchive::parse(&[input.as_slice(), &eocd].concat()).unwrap();
+        assert_eq!(crate::validate::check_entry_count(archive.records()), None);
+
+        // A deliberately wrong count, like in an archive that lost its last entries
+        eocd[8..12].copy_from_slice(&[3, 0, 3, 0]);
+        let archive = ZipArchive::parse(&[input.as_slice(), &eocd].concat()).unwrap();
+        assert_eq!(
+            crate::validate::check_entry_count(archive.records()),
+            Some(crate::validate::Inconsistency::EntryCountMismatch {
+                expected: 3,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_digital_signature() {
+        let mut input = local_file_bytes("a.txt", b"hello");
+        input.extend(central_directory_bytes("a.txt"));
+        input.extend(b"PK\x05\x05\x03\x00sig");
+        input.extend(end_of_central_directory_bytes(b""));
+        let (rest, records) = parse(&input).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(
+            records.as_slice(),
+            [
+                Record::LocalFile(_),
+                Record::CentralDirectory(_),
+                Record::DigitalSignature(DigitalSignature { signature_data }),
+                Record::EndOfCentralDirectory(_),
+            ] if signature_data == b"sig"
+        ));
+    }
+
+    #[test]
+    fn test_parse_headers_only() {
+        let input = include_bytes!("../tests/data/abc.zip");
+        let full = ZipArchive::parse(input).unwrap();
+        let headers_only = ZipArchive::parse_headers_only(input).unwrap();
+        assert_eq!(
+            full.records_with_offsets()
+                .map(|(offset, _)| offset)
+                .collect::<Vec<_>>(),
+            headers_only
+                .records_with_offsets()
+                .map(|(offset, _)| offset)
+                .collect::<Vec<_>>()
+        );
+        for (full, headers_only) in full.local_files().zip(headers_only.local_files()) {
+            assert!(!full.file_data.is_empty());
+            assert!(headers_only.file_data.is_empty());
+            assert_eq!(
+                full.encryption_header.map(|header| header.bytes),
+                headers_only.encryption_header.map(|header| header.bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn test_archive_extra_data_record() {
+        let mut input = local_file_bytes("a.txt", b"hello");
+        input.extend(b"PK\x06\x08\x05\x00\x00\x00extra");
+        input.extend(central_directory_bytes("a.txt"));
+        input.extend(end_of_central_directory_bytes(b""));
+        let (rest, records) = parse(&input).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(
+            records.as_slice(),
+            [
+                Record::LocalFile(_),
+                Record::ArchiveExtraData(ArchiveExtraData { extra_field }),
+                Record::CentralDirectory(_),
+                Record::EndOfCentralDirectory(_),
+            ] if extra_field == b"extra"
+        ));
+    }
+
+    #[test]
+    fn test_find_end_of_central_directory() {
+        // A maximal comment that contains a signature with the wrong comment length
+        let mut comment = vec![b'x'; usize::from(u16::MAX)];
+        comment[100..104].copy_from_slice(b"PK\x05\x06");
+        let mut input = local_file_bytes("a.txt", &[b'y'; 70_000]);
+        let eocd_offset = input.len();
+        input.extend(end_of_central_directory_bytes(&comment));
+        assert_eq!(find_end_of_central_directory(&input), Some(eocd_offset));
+        assert_eq!(find_end_of_central_directory(&input[..eocd_offset]), None);
+        assert_eq!(find_end_of_central_directory(b"PK\x05\x06"), None);
+
+        // Garbage between the local files and the end of central directory record
+        let mut input = local_file_bytes("a.txt", b"hello");
+        input.extend(b"garbage");
+        let eocd_offset = input.len();
+        input.extend(end_of_central_directory_bytes(&comment));
+        assert_eq!(ZipArchive::parse(&input).unwrap().unparsed_bytes(), 7);
+        let (rest, records) = parse_with_offsets(&input).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(records.last().unwrap().0, eocd_offset);
+        assert!(matches!(
+            &records.last().unwrap().1,
+            Record::EndOfCentralDirectory(eocd) if eocd.zip_file_comment == comment
+        ));
+    }
+
+    #[test]
+    fn test_self_extracting_archive() {
+        let stub = b"MZ an executable that extracts the archive";
+        let mut zip_data = local_file_bytes("a.txt", b"hello");
+        let b_offset = zip_data.len();
+        zip_data.extend(local_file_bytes("b.txt", b"world"));
+        let cd_offset = zip_data.len();
+        for (file_name, offset) in [("a.txt", 0), ("b.txt", b_offset)] {
+            let mut cdfh = central_directory_bytes(file_name);
+            cdfh[42..46].copy_from_slice(&(offset as u32).to_le_bytes());
+            zip_data.extend(cdfh);
+        }
+        let cd_size = zip_data.len() - cd_offset;
+        let mut eocd = end_of_central_directory_bytes(b"");
+        eocd[12..16].copy_from_slice(&(cd_size as u32).to_le_bytes());
+        eocd[16..20].copy_from_slice(&(cd_offset as u32).to_le_bytes());
+        zip_data.extend(eocd);
+        let input = [stub.as_slice(), &zip_data].concat();
+
+        assert_eq!(find_stub_length(&input), Some(stub.len()));
+        assert_eq!(find_stub_length(&zip_data), Some(0));
+        let archive = ZipArchive::parse(&input).unwrap();
+        assert_eq!(archive.stub_length(), stub.len());
+        assert_eq!(archive.unparsed_bytes(), 0);
+        assert_eq!(archive.file_by_name("b.txt").unwrap().file_data, b"world");
+        let local_file_offsets: Vec<usize> = archive
+            .records_with_offsets()
+            .filter(|(_, record)| matches!(record, Record::LocalFile(_)))
+            .map(|(offset, _)| offset)
+            .collect();
+        let cd_offsets: Vec<usize> = archive
+            .records()
+            .iter()
+            .filter_map(|record| match record {
+                Record::CentralDirectory(cdfh) => Some(archive.local_header_offset(cdfh)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(local_file_offsets, vec![stub.len(), stub.len() + b_offset]);
+        assert_eq!(cd_offsets, local_file_offsets);
+    }
+
+    #[test]
+    fn test_zip_archive() {
+        let mut input = local_file_bytes("a.txt", b"hello");
+        input.extend(end_of_central_directory_bytes(b"a comment"));
+        let archive = ZipArchive::parse(&input).unwrap();
+        assert_eq!(archive.local_files().count(), 1);
+        assert!(!archive.is_any_encrypted());
+        assert_eq!(archive.unparsed_bytes(), 0);
+        assert_eq!(archive.file_by_name("a.txt").unwrap().file_data, b"hello");
+        assert!(archive.file_by_name("b.txt").is_none());
+        assert_eq!(archive.comment(), Some(b"a comment".as_slice()));
+        assert_eq!(archive.comment_text().as_deref(), Some("a comment"));
+    }
+
+    #[test]
+    fn test_encryption_method() {
+        let archive = ZipArchive::from_encryption_header([0; 12], 0);
+        let mut header = match archive.records.into_iter().next() {
+            Some(Record::LocalFile(local_file)) => local_file.local_file_header,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            header.encryption_method(),
+            Some(EncryptionMethod::ZipCrypto)
+        );
+        header.compression_method = AES_COMPRESSION_METHOD;
+        assert_eq!(header.encryption_method(), Some(EncryptionMethod::Aes));
+        header.general_purpose_bit_flag |= 0x40;
+        assert_eq!(header.encryption_method(), Some(EncryptionMethod::Strong));
+        header.general_purpose_bit_flag = 0;
+        assert_eq!(header.encryption_method(), None);
+    }
+
+    #[test]
+    fn test_missing_encryption_header() {
+        let mut input = local_file_bytes("a.txt", b"abcd");
+        // Set the encryption flag, which also makes the parser expect a data descriptor
+        input[6] = 1;
+        input.extend(b"PK\x07\x08");
+        input.extend([0; 12]);
+        let archive = ZipArchive::parse(&input).unwrap();
+        let local_file = archive.file_by_name("a.txt").unwrap();
+        assert!(local_file.encryption_header.is_none());
+        assert_eq!(local_file.file_data, b"abcd");
+        assert_eq!(
+            crate::validate::check_encryption_headers(archive.records()),
+            vec![crate::validate::Inconsistency::MissingEncryptionHeader {
+                file_name: OsString::from("a.txt"),
+                compressed_size: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_streamed_file_with_fake_signature() {
+        let mut input = local_file_bytes("a.txt", b"");
+        // Set the encryption flag and the data descriptor flag, and leave the sizes at zero
+        input[6] = 9;
+        let mut data = vec![0xaa; 12];
+        // A data descriptor signature in the data, with a size that doesn't match its offset
+        data.extend(b"PK\x07\x08");
+        data.extend([0, 0, 0, 0, 12, 0, 0, 0, 12, 0, 0, 0]);
+        // One whose size matches, but which isn't followed by a record
+        data.extend(b"PK\x07\x08");
+        data.extend([0, 0, 0, 0, 28, 0, 0, 0, 28, 0, 0, 0]);
+        data.extend(b"more data");
+        input.extend(&data);
+        input.extend(b"PK\x07\x08");
+        input.extend([0x78, 0x56, 0x34, 0x12]);
+        input.extend((data.len() as u32).to_le_bytes());
+        input.extend((data.len() as u32).to_le_bytes());
+        input.extend(end_of_central_directory_bytes(b""));
+
+        let archive = ZipArchive::parse(&input).unwrap();
+        let local_file = archive.file_by_name("a.txt").unwrap();
+        assert_eq!(local_file.encryption_header.unwrap().bytes, [0xaa; 12]);
+        assert_eq!(local_file.file_data, data[12..]);
+        assert_eq!(
+            local_file.data_descriptor.as_ref().unwrap().crc32,
+            0x12345678
+        );
+        assert_eq!(archive.unparsed_bytes(), 0);
+    }
+
+    #[test]
+    fn test_records_to_json() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let value = records_to_json(&archive);
+        let record = &value["records"][0];
+        assert_eq!(record["type"], "local_file");
+        assert_eq!(record["offset"], 0);
+        assert_eq!(record["encryption_method"], "ZipCrypto");
+        let local_file = archive.local_files().next().unwrap();
+        let header_offset = record["encryption_header"]["offset"].as_u64().unwrap() as usize;
+        let input = include_bytes!("../tests/data/abc.zip");
+        let encryption_header = local_file.encryption_header.unwrap().bytes;
+        assert_eq!(
+            &input[header_offset..header_offset + 12],
+            &encryption_header
+        );
+        assert_eq!(
+            record["encryption_header"]["bytes"],
+            hex(&encryption_header)
+        );
+        assert_eq!(
+            record["encryption_header"]["last_mod_file_time"],
+            local_file.local_file_header.last_mod_file_time
+        );
+        assert_eq!(value["records"][3]["type"], "central_directory");
+        assert_eq!(value["records"][3]["local_header_offset"], 0);
+    }
+
+    #[test]
+    fn test_archive_from_json() {
+        let archive = ZipArchive::parse(include_bytes!("../tests/data/abc.zip")).unwrap();
+        let mut value = records_to_json(&archive);
+        let imported = archive_from_json(&value).unwrap();
+        assert_eq!(imported.local_files().count(), 3);
+        for (original, imported) in archive.local_files().zip(imported.local_files()) {
+            assert_eq!(
+                original.encryption_header.unwrap().bytes,
+                imported.encryption_header.unwrap().bytes
+            );
+            let (original, imported) = (&original.local_file_header, &imported.local_file_header);
+            assert_eq!(original.file_name, imported.file_name);
+            assert_eq!(original.last_mod_file_time, imported.last_mod_file_time);
+            assert_eq!(original.crc32, imported.crc32);
+            assert_eq!(
+                original.general_purpose_bit_flag,
+                imported.general_purpose_bit_flag
+            );
+        }
+
+        value["records"][1]["encryption_header"]
+            .as_object_mut()
+            .unwrap()
+            .remove("last_mod_file_time");
+        let e = archive_from_json(&value).unwrap_err();
+        assert_eq!(
+            format!("{:#}", e),
+            "Invalid record 1: Missing the field \"last_mod_file_time\""
+        );
+        assert!(archive_from_json(&serde_json::json!({"records": []})).is_err());
+    }
+
+    #[test]
+    fn test_truncated_record() {
+        let input = include_bytes!("../tests/data/abc.zip");
+        let archive = ZipArchive::parse(input).unwrap();
+        assert_eq!(archive.truncated_record(), None);
+        let offsets: Vec<usize> = archive
+            .records_with_offsets()
+            .map(|(offset, _)| offset)
+            .collect();
+
+        // Cut off in the second local file
+        let archive = ZipArchive::parse(&input[..offsets[1] + 40]).unwrap();
+        assert_eq!(archive.local_files().count(), 1);
+        let truncated_record = archive.truncated_record().unwrap();
+        assert_eq!(truncated_record.name(), "local file");
+        assert_eq!(truncated_record.offset, offsets[1]);
+
+        // Cut off in the central directory
+        let archive = ZipArchive::parse(&input[..offsets[4] + 10]).unwrap();
+        let truncated_record = archive.truncated_record().unwrap();
+        assert_eq!(truncated_record.signature, *b"PK\x01\x02");
+        assert_eq!(truncated_record.offset, offsets[4]);
+        assert_eq!(
+            crate::validate::check_structure(&archive)[1].to_string(),
+            format!(
+                "The archive ends in the middle of a central directory file header at offset {}, \
+                 it's probably truncated",
+                offsets[4]
+            )
+        );
+
+        // A complete archive after the end isn't cut off
+        let mut concatenated = input.to_vec();
+        concatenated.extend(input);
+        let archive = ZipArchive::parse(&concatenated).unwrap();
+        assert_eq!(archive.unparsed_bytes(), input.len());
+        assert_eq!(archive.truncated_record(), None);
+    }
+
+    #[test]
+    fn test_comment_text() {
+        let archive = ZipArchive::parse(&end_of_central_directory_bytes(b"")).unwrap();
+        assert_eq!(archive.comment_text(), None);
+        let archive = ZipArchive::parse(&end_of_central_directory_bytes(b"pw\xff?")).unwrap();
+        assert_eq!(archive.comment_text().as_deref(), Some("pw\u{fffd}?"));
+    }
+
+    #[test]
+    fn test_offsets() {
+        let mut input = local_file_bytes("a.txt", b"hello");
+        input.extend(local_file_bytes("b.txt", b"world!"));
+        input.extend(end_of_central_directory_bytes(b""));
+        let (rest, records) = parse_with_offsets(&input).unwrap();
+        assert!(rest.is_empty());
+        let offsets: Vec<_> = records.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, vec![0, 40, 81]);
+    }
+
+    #[test]
+    fn test_extended_timestamp() {
+        let extra_field = [
+            // Some other extra field entry that should be skipped
+            0x75, 0x78, 0x03, 0x00, 0x01, 0x02, 0x03,
+            // Extended timestamp with mtime, atime and ctime
+            0x55, 0x54, 0x0d, 0x00, 0x07, 0x00, 0xe1, 0xf5, 0x05, 0x01, 0xe1, 0xf5, 0x05, 0x02,
+            0xe1, 0xf5, 0x05,
+        ];
+        let data = find_extra_field(&extra_field, EXTENDED_TIMESTAMP_TAG).unwrap();
+        let (_, timestamp) = parse_extended_timestamp(data).unwrap();
+        let expected_timestamp = ExtendedTimestamp {
+            mtime: Some(100000000),
+            atime: Some(100000001),
+            ctime: Some(100000002),
+        };
+        assert_eq!(timestamp, expected_timestamp);
+
+        // Central directory version: The flags are the same, but only the mtime is present
+        let (_, timestamp) = parse_extended_timestamp(&[0x07, 0x00, 0xe1, 0xf5, 0x05]).unwrap();
+        let expected_timestamp = ExtendedTimestamp {
+            mtime: Some(100000000),
+            ..Default::default()
+        };
+        assert_eq!(timestamp, expected_timestamp);
+    }
+
+    #[test]
+    fn test_ntfs_timestamp() {
+        let mut extra_field = vec![0x0a, 0x00, 0x20, 0x00];
+        // Reserved
+        extra_field.extend([0x00; 4]);
+        // Attribute 1 with mtime, atime and ctime
+        extra_field.extend([0x01, 0x00, 0x18, 0x00]);
+        extra_field.extend(132_000_000_000_000_000u64.to_le_bytes());
+        extra_field.extend(132_000_000_000_000_001u64.to_le_bytes());
+        extra_field.extend(132_000_000_000_000_002u64.to_le_bytes());
+        let data = find_extra_field(&extra_field, NTFS_TAG).unwrap();
+        let (_, timestamp) = parse_ntfs_timestamp(data).unwrap();
+        let expected_timestamp = NtfsTimestamp {
+            mtime: 132_000_000_000_000_000,
+            atime: 132_000_000_000_000_001,
+            ctime: 132_000_000_000_000_002,
+        };
+        assert_eq!(timestamp, expected_timestamp);
+    }
+
+    #[test]
+    fn test_unix_owner() {
+        let extra_field = [
+            // Unix3 with a 4-byte uid 1000 and a 2-byte gid 100
+            0x75, 0x78, 0x09, 0x00, 0x01, 0x04, 0xe8, 0x03, 0x00, 0x00, 0x02, 0x64, 0x00,
+        ];
+        let data = find_extra_field(&extra_field, UNIX3_TAG).unwrap();
+        let (_, owner) = parse_unix3_owner(data).unwrap();
+        assert_eq!(
+            owner,
+            UnixOwner {
+                uid: 1000,
+                gid: 100
+            }
+        );
+
+        let (_, owner) = parse_unix2_owner(&[0xe8, 0x03, 0x64, 0x00]).unwrap();
+        assert_eq!(
+            owner,
+            UnixOwner {
+                uid: 1000,
+                gid: 100
+            }
+        );
+
+        // IDs wider than 32 bits are rejected
+        assert!(parse_unix3_owner(&[0x01, 0x05, 0, 0, 0, 0, 1, 0x01, 0]).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Cracks a real archive through the whole pipeline, from parsing to the found password
+
+use zipcrack::crack::{crack_unrolled_without_display, find_passwords, get_encryption_data};
+use zipcrack::info::InfoData;
+use zipcrack::opt::{CrackOptions, Opt};
+use zipcrack::zipfile::ZipArchive;
+
+/// Three files, one stored and two deflated, encrypted by Info-ZIP with the password "abc"
+const ARCHIVE: &[u8] = include_bytes!("data/abc.zip");
+
+fn lowercase_options() -> CrackOptions {
+    CrackOptions::new("custom:abcdefghijklmnopqrstuvwxyz".parse().unwrap())
+        .min_length(1)
+        .max_length(3)
+        .logfile("")
+}
+
+#[test]
+fn test_find_passwords() {
+    let archive = ZipArchive::parse(ARCHIVE).unwrap();
+    let opt: Opt = lowercase_options().into();
+    assert_eq!(get_encryption_data(&opt, &archive).len(), 3);
+    let found: Vec<String> = find_passwords(opt, &archive)
+        .into_iter()
+        .map(|found| found.password)
+        .collect();
+    assert_eq!(found, vec!["abc"]);
+}
+
+#[test]
+fn test_crack_unrolled() {
+    let archive = ZipArchive::parse(ARCHIVE).unwrap();
+    let opt: Opt = lowercase_options().unroll(true).threads(2).into();
+    let info_data = InfoData::new();
+    crack_unrolled_without_display(opt, &archive, info_data.clone());
+    let found_passwords = info_data.found_passwords.lock().unwrap();
+    let found: Vec<&str> = found_passwords
+        .iter()
+        .map(|found| found.password.as_str())
+        .collect();
+    assert_eq!(found, vec!["abc"]);
+}